@@ -0,0 +1,48 @@
+//! Downloads a file into the working directory, stopping cleanly on Ctrl-C.
+//!
+//! Usage: get_interruptible SERVER:PORT PATH
+
+extern crate ctrlc;
+extern crate tftp;
+
+use std::env;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::exit;
+use std::sync::atomic::Ordering;
+
+use tftp::client::{get_to_file_with_config, ClientConfig, Error};
+use tftp::packet::Mode;
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    if args.len() != 3 {
+        println!("Usage: {} SERVER:PORT PATH", args[0]);
+        exit(2)
+    }
+    let remote_addr: SocketAddr = match args[1].parse() {
+        Ok(addr) => addr,
+        Err(_) => {
+            println!("Invalid server address {}", args[1]);
+            exit(2)
+        }
+    };
+    let path = Path::new(&args[2]);
+    let dest = Path::new(path.file_name().expect("path names no file"));
+
+    let mut config = ClientConfig::default();
+    let cancel = config.cancel_flag();
+    ctrlc::set_handler(move || cancel.store(true, Ordering::SeqCst)).expect("could not install a Ctrl-C handler");
+
+    match get_to_file_with_config(remote_addr, path, Mode::Octet, dest, &config) {
+        Ok(()) => println!("Downloaded {}", dest.display()),
+        Err(Error::Cancelled) => {
+            println!("Download cancelled, the partial file was removed");
+            exit(130)
+        }
+        Err(err) => {
+            println!("Download failed: {}", err);
+            exit(1)
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Downloads a file to standard output, for use in a pipeline.
+//!
+//! Usage: get_stdout SERVER:PORT PATH | gzip > file.gz
+//!
+//! Messages go to standard error so they don't end up in the downloaded data. If the
+//! process reading the output exits early, the server is told and the download stops.
+
+extern crate tftp;
+
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::exit;
+
+use tftp::client::Client;
+use tftp::packet::Mode;
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} SERVER:PORT PATH", args[0]);
+        exit(2)
+    }
+    let remote_addr: SocketAddr = match args[1].parse() {
+        Ok(addr) => addr,
+        Err(_) => {
+            eprintln!("Invalid server address {}", args[1]);
+            exit(2)
+        }
+    };
+
+    let client = match Client::new(remote_addr) {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("Could not create the client: {}", err);
+            exit(1)
+        }
+    };
+    // The transfer flushes its writer once the last block is written.
+    let stdout = io::stdout();
+    if let Err(err) = client.get(Path::new(&args[2]), Mode::Octet, stdout.lock()) {
+        eprintln!("Download failed: {}", err);
+        exit(1)
+    }
+}
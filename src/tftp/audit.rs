@@ -0,0 +1,147 @@
+//! Structured audit trail of transfers.
+//!
+//! Both the client and the server report every finished transfer, successful or
+//! not, to an `AuditLog`. `JsonLineLogger` writes the entries to a file, one JSON
+//! object per line.
+
+#[cfg(feature = "serde")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "serde")]
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+#[cfg(feature = "serde")]
+use std::path::Path;
+#[cfg(feature = "serde")]
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+#[cfg(feature = "serde")]
+use serde_json;
+
+/// A finished transfer.
+///
+/// With the `serde` feature the timestamp is serialized as an RFC 3339 date in UTC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct AuditEntry<'a> {
+    /// When the transfer finished.
+    pub timestamp: DateTime<Utc>,
+    /// Address of the client of the transfer.
+    pub client: SocketAddr,
+    /// `"read"` or `"write"`.
+    pub operation: &'a str,
+    /// Requested file name.
+    pub path: &'a str,
+    /// Number of data blocks transferred.
+    pub blocks: u32,
+    /// Number of bytes of file contents transferred.
+    pub bytes: u64,
+    /// Time from the request to the end of the transfer, in milliseconds.
+    pub duration_ms: u64,
+    /// Why the transfer failed, `None` if it succeeded.
+    pub error: Option<String>,
+}
+
+/// Receiver of audit entries.
+pub trait AuditLog: Send + Sync {
+    /// Records a finished transfer.
+    fn record(&self, entry: AuditEntry);
+}
+
+/// Writes audit entries to a file as JSON lines, with the `serde` feature.
+#[cfg(feature = "serde")]
+pub struct JsonLineLogger {
+    writer: Mutex<BufWriter<File>>,
+}
+
+#[cfg(feature = "serde")]
+impl JsonLineLogger {
+    /// Creates a logger appending to the file at `path`, creating it if needed.
+    pub fn create(path: &Path) -> io::Result<JsonLineLogger> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(path));
+        Ok(JsonLineLogger::new(file))
+    }
+
+    /// Creates a logger writing to `file`.
+    pub fn new(file: File) -> JsonLineLogger {
+        JsonLineLogger {
+            writer: Mutex::new(BufWriter::new(file)),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl AuditLog for JsonLineLogger {
+    fn record(&self, entry: AuditEntry) {
+        let mut writer = self.writer.lock().unwrap();
+        // Every entry is flushed, the log is complete even if the process is killed.
+        let result = serde_json::to_writer(&mut *writer, &entry).map_err(io::Error::from)
+            .and_then(|_| writer.write_all(b"\n"))
+            .and_then(|_| writer.flush());
+        if let Err(err) = result {
+            warn!("Could not write audit entry: {}", err);
+        }
+    }
+}
+
+/// Returns the milliseconds in `duration`.
+pub fn duration_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod test {
+    use std::fs::{self, File};
+
+    use chrono::{TimeZone, Utc};
+    use serde_json::{self, Value};
+
+    use test_server::temp_dir;
+
+    use super::{AuditEntry, AuditLog, JsonLineLogger};
+
+    fn entry<'a>() -> AuditEntry<'a> {
+        AuditEntry {
+            timestamp: Utc.timestamp_millis_opt(1_500_000_000_123).unwrap(),
+            client: "127.0.0.1:4000".parse().unwrap(),
+            operation: "read",
+            path: "file.bin",
+            blocks: 3,
+            bytes: 1300,
+            duration_ms: 12,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn entry_is_serialized_with_an_rfc_3339_timestamp() {
+        let json: Value = serde_json::to_value(&entry()).unwrap();
+        assert_eq!("2017-07-14T02:40:00.123Z", json["timestamp"]);
+        assert_eq!("127.0.0.1:4000", json["client"]);
+        assert_eq!("read", json["operation"]);
+        assert_eq!("file.bin", json["path"]);
+        assert_eq!(3, json["blocks"]);
+        assert_eq!(1300, json["bytes"]);
+        assert_eq!(12, json["duration_ms"]);
+        assert_eq!(Value::Null, json["error"]);
+    }
+
+    #[test]
+    fn logger_writes_one_line_per_entry() {
+        let dir = temp_dir("audit");
+        let path = dir.join("audit.log");
+        let logger = JsonLineLogger::new(File::create(&path).unwrap());
+        logger.record(entry());
+        logger.record(AuditEntry { path: "a \"quoted\"\npath", error: Some("failed".to_string()), ..entry() });
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(2, lines.len());
+        assert_eq!("file.bin", lines[0]["path"]);
+        assert_eq!(Value::Null, lines[0]["error"]);
+        assert_eq!("a \"quoted\"\npath", lines[1]["path"]);
+        assert_eq!("failed", lines[1]["error"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
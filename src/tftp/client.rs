@@ -1,26 +1,68 @@
 //! A Trivial File Transfer (TFTP) protocol client implementation.
 //!
 //! This module contains the ability to read data from or write data to a remote TFTP server.
+//! It is the only client implementation of the crate; transfers are driven by a mio event
+//! loop, while the protocol logic lives in a state machine that does not depend on it.
 
 use std::convert::From;
-use std::io;
-use std::path::Path;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+#[cfg(feature = "serde")]
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::net::SocketAddr;
 use std::result;
 use std::str;
+use std::cmp;
 use std::mem;
+use std::collections::{BTreeSet, VecDeque};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use packet::{Mode, RequestPacket, DataPacketOctet, AckPacket, ErrorPacket,
-    EncodePacket, RawPacket, Opcode};
-use decodedpacket::DecodedPacket;
+    OackPacket, EncodePacket, RawPacket, Opcode, DecodeError, Error as ErrorCode, TFTP_HEADER_LEN, recv_buffer_size};
+use decodedpacket::{AnyPacket, DecodedPacket};
+use options::OptionNegotiator;
+use audit::{AuditEntry, AuditLog, duration_ms};
+use uri::TftpUri;
 
+use log::Level;
+use chrono::Utc;
 use mio::udp::UdpSocket;
-use mio::{Events, Poll, PollOpt, Event, Token, Ready};
+use mio::{Event, Events, Poll, PollOpt, Token, Ready};
+use socket2::SockRef;
 
 static MAX_DATA_SIZE: usize = 512;
 
+/// Smallest block size that can be requested with the `blksize` option (RFC 2348).
+const MIN_BLOCK_SIZE: usize = 8;
+
+/// Largest block size that can be requested with the `blksize` option (RFC 2348).
+const MAX_BLOCK_SIZE: usize = 65464;
+
+/// How often a cancelable transfer checks its cancel flag while waiting for the socket.
+const CANCEL_POLL_INTERVAL_MS: u64 = 50;
+
+/// Packets from ports other than the one of the transfer that are answered with an
+/// error before the server is considered broken.
+const MAX_TID_MISMATCHES: u32 = 5;
+
+/// Blocks in a row with an unexpected id that are tolerated before the transfer fails.
+const MAX_MISMATCHED_BLOCKS: u32 = 8;
+
 quick_error! {
+    /// Why a transfer failed.
+    ///
+    /// More variants may be added; a match outside this crate needs a wildcard
+    /// arm, such as `_ => return Err(err.into())` to pass the error on as an
+    /// `io::Error`.
     #[derive(Debug)]
+    #[non_exhaustive]
     pub enum Error {
         Io(err: io::Error) {
             from()
@@ -31,82 +73,798 @@ quick_error! {
         Server(err: ErrorPacket<'static>) {
             from()
             description("server error")
-            display("Server error: {}", err)
+            display("Server sent {}", err)
+            cause(err)
+        }
+        ServerBusy {
+            description("server busy")
+            display("Server is busy, try again later")
+        }
+        Cancelled {
+            description("transfer cancelled")
+            display("Transfer cancelled")
+        }
+        FileTooLarge(size: u64) {
+            description("file too large")
+            display("File of at least {} bytes exceeds the maximum size", size)
+        }
+        Timeout {
+            description("transfer timed out")
+            display("Transfer timed out, the server stopped responding")
+        }
+        InvalidFilename(reason: &'static str) {
+            description("invalid filename")
+            display("Invalid filename: {}", reason)
+        }
+        Protocol(err: DecodeError) {
+            from()
+            description("malformed packet")
+            display("Malformed packet: {}", err)
             cause(err)
         }
+        ProtocolViolation(description: String) {
+            description("protocol violation")
+            display("Protocol violation: {}", description)
+        }
+        ResumeRejected {
+            description("resume rejected")
+            display("The server does not support resuming the transfer")
+        }
+        DeadlineExceeded {
+            description("transfer deadline exceeded")
+            display("Transfer did not finish before its deadline")
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    /// Returns the error for an error packet sent by the server.
+    ///
+    /// There is no error code for a server serving as many clients as it can, such
+    /// servers send code 0 with a message like "Server busy". That is reported as
+    /// `Error::ServerBusy`, so callers can retry later, other errors as `Error::Server`.
+    pub(crate) fn from_server(err: ErrorPacket<'static>) -> Error {
+        let busy = err.error() == ErrorCode::Undefined &&
+            err.message().map_or(false, |msg| msg.to_lowercase().contains("busy"));
+        if busy { Error::ServerBusy } else { Error::Server(err) }
+    }
+}
+
+/// Schedule of the waits between retransmissions of an unanswered packet.
+///
+/// The first retransmission happens after `base`, every following one waits
+/// `multiplier` times longer than the previous, up to `max`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backoff {
+    /// Wait before the first retransmission.
+    ///
+    /// A client raises a shorter wait to `MIN_RETRANSMIT_WAIT`, so that it never
+    /// polls without a timeout.
+    pub base: Duration,
+
+    /// Factor the wait grows by after every retransmission.
+    ///
+    /// A client raises a multiplier of 0 to 1, so that the wait never shrinks.
+    pub multiplier: u32,
+
+    /// Longest wait between two retransmissions.
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            base: Duration::from_secs(1),
+            multiplier: 2,
+            max: Duration::from_secs(8),
+        }
+    }
+}
+
+/// Shortest wait before a retransmission a client uses.
+pub const MIN_RETRANSMIT_WAIT: Duration = Duration::from_millis(10);
+
+impl Backoff {
+    /// Returns how long to wait for an answer after `retransmit_count` retransmissions.
+    pub fn timeout(&self, retransmit_count: u32) -> Duration {
+        let mut timeout = self.base;
+        for _ in 0..retransmit_count {
+            if timeout >= self.max {
+                break
+            }
+            timeout = timeout.checked_mul(self.multiplier).unwrap_or(self.max);
+        }
+        cmp::min(timeout, self.max)
+    }
+
+    /// Raises a zero `multiplier` and a `base` below `MIN_RETRANSMIT_WAIT`, which
+    /// would make the wait drop to zero.
+    pub(crate) fn normalized(mut self) -> Backoff {
+        self.base = cmp::max(self.base, MIN_RETRANSMIT_WAIT);
+        self.multiplier = cmp::max(self.multiplier, 1);
+        self
+    }
+}
+
+/// Client tunables.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Capacity of the event buffer passed to `Poll::poll`.
+    ///
+    /// A transfer polls a single socket, so a handful of events is plenty.
+    /// Defaults to 4.
+    pub events_capacity: usize,
+
+    /// Block size to request with the `blksize` option (RFC 2348).
+    ///
+    /// Sizes outside of the range allowed by the RFC, 8 to 65464 bytes, are clamped
+    /// to it before the request is sent. Defaults to `None`, transferring 512 byte
+    /// blocks without negotiation.
+    pub block_size: Option<usize>,
+
+    /// Retransmission timeout to request with the `timeout` option (RFC 2349), in
+    /// whole seconds.
+    pub timeout: Option<Duration>,
+
+    /// Whether to ask the server for the size of the file with the `tsize` option
+    /// (RFC 2349).
+    pub tsize: bool,
+
+    /// Flag that cancels the transfer when set.
+    ///
+    /// The transfer notices the flag within `CANCEL_POLL_INTERVAL_MS`, sends an error
+    /// packet to the server and fails with `Error::Cancelled`.
+    pub cancel: Option<Arc<AtomicBool>>,
+
+    /// Largest file accepted from the server, in bytes.
+    ///
+    /// If the server reports a larger size with the `tsize` option, the transfer is
+    /// aborted before the first block; a transfer that grows larger is aborted before
+    /// the block over the limit is acknowledged. Either way the server is told and
+    /// the transfer fails with `Error::FileTooLarge`, with the reported size or the
+    /// bytes received up to the block. Defaults to no limit.
+    pub max_file_size: Option<u64>,
+
+    /// Waits between retransmissions of the last packet while the server is silent.
+    pub retransmit: Backoff,
+
+    /// Number of retransmissions after which the transfer fails with `Error::Timeout`.
+    /// Defaults to 5.
+    pub max_retries: u32,
+
+    /// Block id to continue with after block 65535, 0 or 1, requested with the
+    /// `rollover` option.
+    ///
+    /// Defaults to `None`, wrapping around to block 0 without negotiation.
+    pub rollover: Option<u16>,
+
+    /// Number of blocks the server may send before waiting for an ACK, requested
+    /// with the `windowsize` option (RFC 7440).
+    ///
+    /// Defaults to `None`, one block at a time. Only downloads request it.
+    pub window_size: Option<u16>,
+
+    /// Whether downloads to a file wait for the file to reach the disk, with
+    /// `File::sync_all`, before they are reported as complete.
+    ///
+    /// Defaults to false: a crash shortly after the transfer may lose the end of
+    /// the file.
+    pub sync_file: bool,
+
+    /// Time the whole transfer may take, however steadily the server sends.
+    ///
+    /// A transfer still running at the deadline sends an error packet to the server
+    /// and fails with `Error::DeadlineExceeded`. Defaults to no limit.
+    pub deadline: Option<Duration>,
+
+    /// Size of the kernel send buffer of the socket, in bytes.
+    ///
+    /// Defaults to `None`, keeping the size of the operating system. Sizes above
+    /// its limit are reduced to the limit. Only downloads set it.
+    pub socket_send_buf: Option<usize>,
+
+    /// Size of the kernel receive buffer of the socket, in bytes.
+    ///
+    /// Large blocks and windows can overrun the default buffer, and the kernel drops
+    /// what does not fit without the transfer noticing. Defaults to `None`, keeping
+    /// the size of the operating system. Sizes above its limit are reduced to the
+    /// limit. Only downloads set it.
+    pub socket_recv_buf: Option<usize>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            events_capacity: 4,
+            block_size: None,
+            timeout: None,
+            tsize: false,
+            cancel: None,
+            max_file_size: None,
+            retransmit: Backoff::default(),
+            max_retries: 5,
+            rollover: None,
+            window_size: None,
+            sync_file: false,
+            deadline: None,
+            socket_send_buf: None,
+            socket_recv_buf: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Returns the flag that cancels the transfers of this config, setting `cancel`
+    /// to a new flag if there is none.
+    ///
+    /// Hand the flag to a Ctrl-C handler to stop an interactive download cleanly:
+    /// the server gets an error packet, and `get_to_file` removes the partial file.
+    pub fn cancel_flag(&mut self) -> Arc<AtomicBool> {
+        self.cancel.get_or_insert_with(|| Arc::new(AtomicBool::new(false))).clone()
+    }
+
+    /// Returns the read request a download of `path` with this configuration starts
+    /// with, options included, without sending it.
+    ///
+    /// Encode it to see the bytes on the wire, for example to check the options of a
+    /// configuration in a test.
+    pub fn read_request<'a>(&self, path: &'a Path, mode: Mode) -> Result<RequestPacket<'a>> {
+        let filename = try!(request_filename(path));
+        Ok(RequestPacket::read_request_with_options(filename, mode, &self.request_options()))
+    }
+
+    /// Returns the write request an upload to `path` with this configuration starts
+    /// with, like `read_request`.
+    pub fn write_request<'a>(&self, path: &'a Path, mode: Mode) -> Result<RequestPacket<'a>> {
+        let filename = try!(request_filename(path));
+        Ok(RequestPacket::write_request_with_options(filename, mode, &self.write_request_options()))
+    }
+
+    /// Returns the block size to request, clamped to the range of RFC 2348.
+    fn requested_block_size(&self) -> Option<usize> {
+        self.block_size.map(|size| size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE))
+    }
+
+    /// Returns the options to append to the read request.
+    fn request_options(&self) -> Vec<(String, String)> {
+        let mut options = Vec::new();
+        if let Some(block_size) = self.requested_block_size() {
+            options.push(("blksize".to_string(), block_size.to_string()));
+        }
+        if let Some(timeout) = self.timeout {
+            options.push(("timeout".to_string(), timeout.as_secs().to_string()));
+        }
+        if self.tsize {
+            options.push(("tsize".to_string(), "0".to_string()));
+        }
+        if let Some(rollover) = self.rollover {
+            options.push(("rollover".to_string(), rollover.to_string()));
+        }
+        if let Some(window_size) = self.window_size {
+            options.push(("windowsize".to_string(), window_size.to_string()));
+        }
+        options
+    }
+
+    /// Returns the options to append to the write request.
+    ///
+    /// The size of an upload is not known up front and it is sent one block at a
+    /// time, so only `blksize` and `timeout` are requested.
+    fn write_request_options(&self) -> Vec<(String, String)> {
+        self.request_options().into_iter()
+            .filter(|&(ref name, _)| name == "blksize" || name == "timeout")
+            .collect()
+    }
+}
+
+/// The RFC 2347 options of a transfer, together with its mode.
+///
+/// The default requests no options and transfers in octet mode, like a plain
+/// RFC 1350 client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferOptions {
+    /// Block size to request with the `blksize` option (RFC 2348).
+    pub blksize: Option<u16>,
+
+    /// Window size to request with the `windowsize` option (RFC 7440), downloads only.
+    pub windowsize: Option<u16>,
+
+    /// Retransmission timeout to request with the `timeout` option (RFC 2349).
+    pub timeout_secs: Option<u8>,
+
+    /// Whether to ask the server for the size of the file (RFC 2349), downloads only.
+    pub request_tsize: bool,
+
+    /// Mode of the transfer.
+    pub mode: Mode,
+}
+
+impl Default for TransferOptions {
+    fn default() -> TransferOptions {
+        TransferOptions {
+            blksize: None,
+            windowsize: None,
+            timeout_secs: None,
+            request_tsize: false,
+            mode: Mode::default(),
+        }
+    }
+}
+
+/// Settings a transfer was conducted with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferInfo {
+    /// Size of the data blocks, 512 bytes unless another size was negotiated.
+    pub block_size: usize,
+
+    /// Size of the file as reported by the server, if `tsize` was negotiated.
+    pub tsize: Option<u64>,
+
+    /// Retransmission timeout acknowledged by the server, if `timeout` was negotiated.
+    pub timeout: Option<Duration>,
+
+    /// Block id following block 65535 chosen by the server, if `rollover` was negotiated.
+    pub rollover: Option<u16>,
+
+    /// Number of blocks sent per ACK, 1 unless another window size was negotiated.
+    pub window_size: u16,
+
+    /// Address the server answered the request from, its transfer identifier (TID).
+    ///
+    /// A server normally answers from a new port, this tells whether it did, which
+    /// helps to diagnose firewalls and NAT. The unspecified address `0.0.0.0:0` until
+    /// the server answered.
+    pub server_tid: SocketAddr,
+}
+
+impl Default for TransferInfo {
+    fn default() -> TransferInfo {
+        TransferInfo {
+            block_size: MAX_DATA_SIZE,
+            tsize: None,
+            timeout: None,
+            rollover: None,
+            window_size: 1,
+            server_tid: SocketAddr::from(([0, 0, 0, 0], 0)),
+        }
+    }
+}
+
+impl TransferInfo {
+    /// Builds the transfer settings from the options acknowledged by the server.
+    ///
+    /// Fails with `Error::ProtocolViolation` if the server acknowledged an option
+    /// that was not requested, or a block or window size larger than the requested one.
+    fn negotiated(requested: &[(String, String)], oack: &OackPacket) -> Result<TransferInfo> {
+        let acknowledged = try!(OptionNegotiator::new(requested).validate_oack(oack)
+            .map_err(|err| Error::ProtocolViolation(err.to_string())));
+        Ok(TransferInfo {
+            block_size: acknowledged.blksize.map_or(MAX_DATA_SIZE, |size| size as usize),
+            tsize: acknowledged.tsize,
+            timeout: acknowledged.timeout.map(|secs| Duration::from_secs(secs as u64)),
+            rollover: acknowledged.rollover,
+            window_size: acknowledged.windowsize.unwrap_or(1),
+            ..TransferInfo::default()
+        })
+    }
+}
+
+/// Position of a download after a block was written, see
+/// `Client::with_progress_callback`.
+///
+/// More fields may be added, so the struct can't be built outside of the crate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TransferProgress {
+    /// Number of bytes written so far.
+    pub bytes_transferred: u64,
+    /// Size of the file as reported by the server, if `tsize` was negotiated.
+    pub total_bytes: Option<u64>,
+    /// Id of the block just written.
+    pub block_id: u16,
+    /// Size of the data blocks of the transfer.
+    pub block_size: usize,
+}
+
+/// Returns the file name to request for `path`.
+///
+/// File names are sent as NUL terminated strings, so they have to be valid UTF-8
+/// and can't contain NUL characters themselves.
+pub(crate) fn request_filename(path: &Path) -> Result<&str> {
+    match path.to_str() {
+        Some(filename) if filename.contains('\0') => Err(Error::InvalidFilename("filename contains a NUL byte")),
+        Some(filename) => Ok(filename),
+        None => Err(Error::InvalidFilename("filename is not valid UTF-8")),
+    }
+}
+
+/// Returns true if `data_packet` is the last block of a transfer.
+///
+/// A transfer is terminated by a data packet with fewer than `block_size` bytes,
+/// where `block_size` is the size negotiated for the transfer, not the default of 512.
+fn is_final_block(data_packet: &DataPacketOctet, block_size: usize) -> bool {
+    data_packet.data().len() < block_size
+}
+
+/// Returns the size of a buffer receiving DATA packets of up to `block_size` bytes.
+///
+/// The buffer holds one byte more than the largest packet, so that an oversized
+/// block is not silently truncated to a valid one.
+fn receive_buffer_size(block_size: usize) -> usize {
+    recv_buffer_size(block_size) + 1
+}
+
+/// Returns the id of the block following `block_id`.
+///
+/// After block 65535 the ids start over at `rollover`, or at 0 if none was negotiated.
+fn next_block_id(block_id: u16, rollover: Option<u16>) -> u16 {
+    if block_id == u16::MAX {
+        rollover.unwrap_or(0)
+    } else {
+        block_id + 1
+    }
+}
+
+/// Returns true if `block_id` comes before `current_id`, allowing for block ids
+/// wrapping around in long transfers.
+fn is_older_block(block_id: u16, current_id: u16) -> bool {
+    block_id != current_id && current_id.wrapping_sub(block_id) < 0x8000
+}
+
+/// Blocks received in the current window of a download (RFC 7440), which is
+/// acknowledged with a single ACK of its last block.
+struct WindowedAck {
+    window_size: u16,
+    /// First block of the window.
+    expected: u16,
+    /// Blocks of the window received so far, as offsets from `expected` so that
+    /// block ids wrapping around keep their order.
+    received: BTreeSet<u16>,
+}
+
+impl WindowedAck {
+    fn new(window_size: u16, expected: u16) -> WindowedAck {
+        WindowedAck {
+            window_size: window_size,
+            expected: expected,
+            received: BTreeSet::new(),
+        }
+    }
+
+    /// Records `block_id` as received, blocks outside of the window are ignored.
+    fn push(&mut self, block_id: u16) {
+        let offset = block_id.wrapping_sub(self.expected);
+        if offset < self.window_size {
+            self.received.insert(offset);
+        }
+    }
+
+    /// Returns the last block received if every block from `expected` up to it was
+    /// received, `None` while there is a gap or nothing was received.
+    fn cumulative_ack(&self) -> Option<u16> {
+        match self.received.iter().next_back() {
+            Some(&last) if last as usize + 1 == self.received.len() => Some(self.expected.wrapping_add(last)),
+            _ => None,
+        }
+    }
+
+    /// Whether every block of the window was received.
+    fn is_window_complete(&self) -> bool {
+        self.received.len() == self.window_size as usize
+    }
+
+    /// Starts a new window at `expected`, once the previous one was acknowledged.
+    fn restart(&mut self, expected: u16) {
+        self.expected = expected;
+        self.received.clear();
     }
 }
 
-type Result<T> = result::Result<T, Error>;
+/// A packet from the server that drives the transfer forward.
+enum Received {
+    Data(DecodedPacket<DataPacketOctet<'static>>),
+    Oack(OackPacket<'static>),
+}
 
 trait PacketSender {
-    fn send_read_request(&self, path: &str, mode: Mode) -> Result<()>;
+    fn send_read_request(&mut self, path: &str, mode: Mode, options: &[(String, String)]) -> Result<()>;
     fn send_ack(&mut self, block_id: u16) -> Result<Option<()>>;
+    fn send_error(&mut self, error: ErrorCode, msg: &str) -> Result<()>;
+    /// Sends the last read request or acknowledgment again.
+    fn retransmit_last_packet(&mut self) -> Result<()>;
 }
 
 trait PacketReceiver {
-    fn receive_data(&mut self) -> Result<Option<DecodedPacket<DataPacketOctet<'static>>>>;
+    fn receive(&mut self) -> Result<Option<Received>>;
+    fn put_buffer_data(&mut self, buf: Vec<u8>);
+}
+
+/// A reusable buffer that is lent out while a packet occupies it.
+///
+/// Taking the buffer twice or restoring it while it is present is a bug in the
+/// ownership protocol, and panics instead of silently allocating a new buffer.
+struct PacketBuffer {
+    buf: Option<Vec<u8>>,
+}
+
+impl PacketBuffer {
+    fn new(size: usize) -> PacketBuffer {
+        PacketBuffer {
+            buf: Some(vec![0; size]),
+        }
+    }
+
+    /// Takes the buffer out, it has to be restored once the packet is done with.
+    fn take(&mut self) -> Vec<u8> {
+        self.buf.take().expect("packet buffer is already taken")
+    }
+
+    /// Puts a taken buffer back.
+    fn restore(&mut self, buf: Vec<u8>) {
+        assert!(self.buf.is_none(), "packet buffer is already present");
+        self.buf = Some(buf);
+    }
+
+    /// Gives up the buffer, or a new one of `size` bytes if it is still lent out.
+    fn into_inner(self, size: usize) -> Vec<u8> {
+        self.buf.unwrap_or_else(|| vec![0; size])
+    }
 }
 
 struct InternalClient {
     socket: UdpSocket,
     remote_addr: SocketAddr,
-    buffer_data: Option<Vec<u8>>,
-    buffer_ack: Vec<u8>,
+    block_size: usize,
+    buf_recv: PacketBuffer,
+    buf_send: PacketBuffer,
+    /// Bytes of the last read request or acknowledgment, sent again on retransmission.
+    last_sent: Vec<u8>,
+    /// Address `last_sent` was sent to, the server port may have changed since.
+    last_sent_to: SocketAddr,
+    /// Whether `remote_addr` is the port the server answered from (its TID).
+    tid_known: bool,
+    /// Packets received from other ports so far.
+    tid_mismatches: u32,
 }
 
 impl InternalClient {
-    fn new(socket: UdpSocket, remote_addr: SocketAddr) -> InternalClient {
+    /// Creates a client able to receive data blocks of up to `block_size` bytes.
+    fn new(socket: UdpSocket, remote_addr: SocketAddr, block_size: usize) -> InternalClient {
         InternalClient {
             socket: socket,
             remote_addr: remote_addr,
-            buffer_data: Some(vec![0; MAX_DATA_SIZE + 4]),
-            buffer_ack: vec![0; MAX_DATA_SIZE + 4],
+            block_size: block_size,
+            buf_recv: PacketBuffer::new(receive_buffer_size(block_size)),
+            buf_send: PacketBuffer::new(MAX_DATA_SIZE),
+            last_sent: Vec::new(),
+            last_sent_to: remote_addr,
+            tid_known: false,
+            tid_mismatches: 0,
         }
     }
 
-    fn put_buffer_data(&mut self, buf: Vec<u8>) {
-        self.buffer_data = Some(buf);
+    /// Sets the sizes of the kernel send and receive buffers of the socket, `None`
+    /// keeps a size as it is.
+    ///
+    /// The operating system silently reduces sizes above its limit; Linux doubles
+    /// the size to make room for its bookkeeping.
+    fn set_socket_buffer_sizes(&self, send_bytes: Option<usize>, recv_bytes: Option<usize>) -> Result<()> {
+        let socket = SockRef::from(&self.socket);
+        if let Some(send_bytes) = send_bytes {
+            try!(socket.set_send_buffer_size(send_bytes));
+        }
+        if let Some(recv_bytes) = recv_bytes {
+            try!(socket.set_recv_buffer_size(recv_bytes));
+        }
+        Ok(())
+    }
+
+    /// Receives into `buf` instead of a newly allocated buffer, grown if it can't
+    /// hold a whole block.
+    fn with_receive_buffer(mut self, mut buf: Vec<u8>) -> InternalClient {
+        let size = receive_buffer_size(self.block_size);
+        if buf.len() < size {
+            buf.resize(size, 0);
+        }
+        self.buf_recv = PacketBuffer { buf: Some(buf) };
+        self
+    }
+
+    /// Gives up the receive buffer once the transfer is over.
+    ///
+    /// A block still being handled when the transfer failed holds on to the buffer,
+    /// a new one is returned in that case.
+    fn into_receive_buffer(self) -> Vec<u8> {
+        let size = receive_buffer_size(self.block_size);
+        self.buf_recv.into_inner(size)
+    }
+
+    /// Sends `packet` to the server, encoded in the send buffer.
+    fn send_packet<P: EncodePacket>(&mut self, packet: &P) -> Result<Option<()>> {
+        let remote_addr = self.remote_addr;
+        self.send_packet_to(packet, &remote_addr)
+    }
+
+    fn send_packet_to<P: EncodePacket>(&mut self, packet: &P, addr: &SocketAddr) -> Result<Option<()>> {
+        let encoded = packet.encode_using(self.buf_send.take());
+        let result = self.socket.send_to(encoded.packet_buf(), addr);
+        self.buf_send.restore(encoded.get_buffer());
+        result.map(|opt| opt.map(|_| ())).map_err(From::from)
+    }
+
+    /// Checks that `packet`, received from `from`, belongs to the transfer.
+    ///
+    /// The first answer fixes the port of the server. Packets from other ports are
+    /// answered with an error and dropped, unless there are too many of them.
+    ///
+    /// A retransmitted read request can make the server start a second transfer
+    /// from another port. Its first block or OACK is dropped the same way, but does
+    /// not count as a mismatch: the duplicate transfer may send it again before it
+    /// sees the error.
+    fn check_tid(&mut self, from: SocketAddr, packet: &[u8]) -> Result<bool> {
+        if !self.tid_known {
+            self.remote_addr = from;
+            self.tid_known = true;
+            // Errors about packets sent to other ports, such as those of duplicate
+            // transfers, must not fail the transfer.
+            try!(report_icmp_errors(&self.socket, false));
+            return Ok(true)
+        }
+        if from == self.remote_addr {
+            return Ok(true)
+        }
+        if opens_transfer(packet) {
+            debug!("Dropping packet of a duplicate transfer from {}", from);
+            try!(self.send_packet_to(&ErrorPacket::new(ErrorCode::UnknownTransferId, "Unknown transfer ID"), &from));
+            return Ok(false)
+        }
+        self.tid_mismatches += 1;
+        if self.tid_mismatches > MAX_TID_MISMATCHES {
+            return Err(Error::ProtocolViolation(format!("TID mismatch: expected port {}, got {}",
+                                                        self.remote_addr.port(), from.port())))
+        }
+        warn!("Packet from unknown transfer id {}", from);
+        try!(self.send_packet_to(&ErrorPacket::new(ErrorCode::UnknownTransferId, "Unknown transfer ID"), &from));
+        Ok(false)
+    }
+}
+
+/// Whether `packet` is the first packet a server sends in answer to a read request,
+/// block 1 or an OACK.
+fn opens_transfer(packet: &[u8]) -> bool {
+    packet.starts_with(&[0, Opcode::DATA as u8, 0, 1]) || packet.starts_with(&[0, Opcode::OACK as u8])
+}
+
+/// Makes ICMP errors about the packets sent from `socket`, such as a port
+/// unreachable, fail its next receive, or stops doing so.
+///
+/// An unconnected UDP socket does not report these errors by default, and this
+/// one can't be connected to the server: the server answers from a new port (its
+/// TID), which a connected socket would drop. Only Linux can report them anyway;
+/// elsewhere a server that is not listening is noticed by the timeout.
+#[cfg(target_os = "linux")]
+fn report_icmp_errors(socket: &UdpSocket, enable: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = if try!(socket.local_addr()).is_ipv6() {
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVERR)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_RECVERR)
+    };
+    let value = enable as libc::c_int;
+    // Turning the option off also drops the errors that were not reported yet.
+    let ret = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), level, name, &value as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error())
     }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn report_icmp_errors(_socket: &UdpSocket, _enable: bool) -> io::Result<()> {
+    Ok(())
 }
 
 impl PacketSender for InternalClient {
-    fn send_read_request(&self, path: &str, mode: Mode) -> Result<()> {
-        let read_request = RequestPacket::read_request(path, mode);
-        let encoded = read_request.encode();
-        let buf = encoded.packet_buf();
-        self.socket.send_to(&buf, &self.remote_addr).map(|_| ()).map_err(From::from)
+    fn send_read_request(&mut self, path: &str, mode: Mode, options: &[(String, String)]) -> Result<()> {
+        let read_request = RequestPacket::read_request_with_options(path, mode, options);
+        let last_sent = mem::replace(&mut self.last_sent, Vec::new());
+        self.last_sent = read_request.encode_using(last_sent).into_bytes();
+        self.last_sent_to = self.remote_addr;
+        if !self.tid_known {
+            // Until the server answers, nothing but the request is sent: an ICMP
+            // error means nobody is listening at its address.
+            try!(report_icmp_errors(&self.socket, true));
+        }
+        try!(self.socket.send_to(&self.last_sent, &self.last_sent_to));
+        Ok(())
     }
 
     fn send_ack(&mut self, block_id: u16) -> Result<Option<()>> {
-        let buf = mem::replace(&mut self.buffer_ack, Vec::new());
-        let ack = AckPacket::new(block_id);
-        let encoded = ack.encode_using(buf);
-        let result = {
-            let buf = encoded.packet_buf();
-            self.socket.send_to(&buf, &self.remote_addr).map(|opt| opt.map(|_| ())).map_err(From::from)
-        };
-        self.buffer_ack = encoded.get_buffer();
-        result
+        self.last_sent.clear();
+        self.last_sent.extend_from_slice(&AckPacket::new(block_id).encode_into_array());
+        self.last_sent_to = self.remote_addr;
+        self.socket.send_to(&self.last_sent, &self.last_sent_to).map(|opt| opt.map(|_| ())).map_err(From::from)
+    }
+
+    fn send_error(&mut self, error: ErrorCode, msg: &str) -> Result<()> {
+        self.send_packet(&ErrorPacket::new(error, msg)).map(|_| ())
+    }
+
+    fn retransmit_last_packet(&mut self) -> Result<()> {
+        if !self.last_sent.is_empty() {
+            try!(self.socket.send_to(&self.last_sent, &self.last_sent_to));
+        }
+        Ok(())
     }
 }
 
-impl PacketReceiver for InternalClient {
-    fn receive_data(&mut self) -> Result<Option<DecodedPacket<DataPacketOctet<'static>>>> {
-        let mut buf = mem::replace(&mut self.buffer_data, None).unwrap_or(vec![0; MAX_DATA_SIZE + 4]);
-        let result = try!(self.socket.recv_from(&mut buf));
-        let p = result.map(|(n, from)| {
-            self.remote_addr = from;
-            RawPacket::new(buf, n)
-        }).map(|packet| {
-            match packet.opcode() {
-                Some(Opcode::DATA) => {
-                    DecodedPacket::decode(packet).unwrap()
-                },
-                _ => unimplemented!(),
+impl InternalClient {
+    /// Receives a packet of any type from the server, `None` if there is none yet
+    /// or it was not from the server.
+    ///
+    /// Data packets keep the receive buffer until it is put back, others return it
+    /// right away.
+    fn receive_any(&mut self) -> Result<Option<AnyPacket>> {
+        let mut buf = self.buf_recv.take();
+        debug_assert!(buf.len() >= receive_buffer_size(self.block_size), "receive buffer truncates blocks");
+        let (n, from) = match self.socket.recv_from(&mut buf) {
+            Ok(Some(result)) => result,
+            Ok(None) => {
+                self.buf_recv.restore(buf);
+                return Ok(None)
             }
-        });
-        Ok(p)
+            Err(err) => {
+                self.buf_recv.restore(buf);
+                return Err(From::from(err))
+            }
+        };
+        match self.check_tid(from, &buf[..n]) {
+            Ok(true) => {}
+            result => {
+                self.buf_recv.restore(buf);
+                return result.map(|_| None)
+            }
+        }
+        let packet = RawPacket::new(buf, n);
+        let received = match packet.try_opcode() {
+            // Checked here so that the buffer is kept for the next packet.
+            Ok(Opcode::DATA) if packet.len() < TFTP_HEADER_LEN => Err(DecodeError::TooShort),
+            Ok(Opcode::DATA) => match AnyPacket::decode(packet) {
+                Ok(data_packet) => return Ok(Some(data_packet)),
+                Err(err) => {
+                    // The buffer was dropped together with the malformed packet.
+                    self.buf_recv.restore(vec![0; receive_buffer_size(self.block_size)]);
+                    return Err(Error::Protocol(err))
+                }
+            },
+            _ => AnyPacket::decode_copy(&packet),
+        };
+        self.buf_recv.restore(packet.get_buffer());
+        received.map(Some).map_err(Error::Protocol)
+    }
+}
+
+impl PacketReceiver for InternalClient {
+    fn receive(&mut self) -> Result<Option<Received>> {
+        match try!(self.receive_any()) {
+            None => Ok(None),
+            Some(AnyPacket::Data(data_packet)) => Ok(Some(Received::Data(data_packet))),
+            Some(AnyPacket::Oack(oack)) => Ok(Some(Received::Oack(oack))),
+            Some(AnyPacket::Error(error)) => Err(Error::from_server(error)),
+            Some(AnyPacket::Unknown(opcode, _)) => Err(Error::ProtocolViolation(format!("unknown opcode {}", opcode))),
+            Some(packet) => Err(Error::ProtocolViolation(format!("unexpected {:?} packet during a download",
+                                                                 packet.opcode().unwrap()))),
+        }
+    }
+
+    fn put_buffer_data(&mut self, buf: Vec<u8>) {
+        self.buf_recv.restore(buf);
     }
 }
 
@@ -114,6 +872,7 @@ enum ClientStates<'a> {
     SendReadRequest(&'a Path, Mode),
     ReceivingData(u16),
     SendAck(DecodedPacket<DataPacketOctet<'static>>),
+    SendOptionsAck,
     Done,
 }
 
@@ -124,102 +883,3307 @@ impl<'a> ClientStates<'a> {
             _ => false,
         }
     }
+
+    /// Returns the socket readiness required to make progress in this state.
+    fn interest(&self) -> Ready {
+        match self {
+            &ClientStates::SendReadRequest(..) |
+            &ClientStates::SendAck(..) |
+            &ClientStates::SendOptionsAck => Ready::writable(),
+            _ => Ready::readable(),
+        }
+    }
 }
 
-struct Client<'a> {
-    poll: Poll,
-    client: InternalClient,
-    writer: &'a mut io::Write,
+/// Formats the state for the trace log, with the id of the block it is about.
+impl<'a> fmt::Display for ClientStates<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientStates::SendReadRequest(..) => write!(f, "SendReadRequest"),
+            ClientStates::ReceivingData(block_id) => write!(f, "ReceivingData({})", block_id),
+            ClientStates::SendAck(ref data_packet) => write!(f, "SendAck({})", data_packet.block_id()),
+            ClientStates::SendOptionsAck => write!(f, "SendOptionsAck"),
+            ClientStates::Done => write!(f, "Done"),
+        }
+    }
 }
 
-const CLIENT: Token = Token(0);
+/// Called with the id and the data of every block of a download, see
+/// `Client::with_block_callback`.
+type BlockCallback = Box<FnMut(u16, &[u8]) + Send>;
 
-impl<'a> Client<'a> {
-    fn new(poll: Poll, client: InternalClient, writer: &'a mut io::Write) -> Client<'a> {
-        Client {
-            poll: poll,
+/// Called after every block written, see `Client::with_progress_callback`.
+type ProgressCallback = Box<FnMut(&TransferProgress) + Send>;
+
+/// Called with the file size the server acknowledged with `tsize`, before the first
+/// block is written.
+type SizeCallback = Box<FnMut(u64) + Send>;
+
+/// State transitions of a single transfer, independent of the event loop.
+struct Transfer<C, W: io::Write> {
+    client: C,
+    /// Buffered so a file is written in a few large writes, not one per block.
+    writer: BufWriter<W>,
+    /// Options sent with the read request.
+    options: Vec<(String, String)>,
+    /// Whether the server acknowledged the options with an OACK.
+    oack_received: bool,
+    info: TransferInfo,
+    max_file_size: Option<u64>,
+    /// Number of bytes written so far.
+    written: u64,
+    /// Number of blocks written so far.
+    blocks: u32,
+    retransmit: Backoff,
+    max_retries: u32,
+    /// Retransmissions of the last packet sent so far.
+    retransmit_count: u32,
+    /// Retransmissions of all packets of the transfer.
+    retransmits: u64,
+    /// Number of new packets sent and of blocks received without sending an ACK,
+    /// retransmissions excluded.
+    progress: u64,
+    /// Blocks received since the last ACK, if a window size above 1 was negotiated.
+    window: Option<WindowedAck>,
+    /// Blocks in a row received with an unexpected id.
+    mismatched_blocks: u32,
+    /// Progress saved after every block written.
+    checkpoint: Option<ResumableTransfer>,
+    /// Block asked for in place of block 1, until the server sent it.
+    resume_id: Option<u16>,
+    /// Older block answered with its ACK again since the last block written or
+    /// timeout; more copies of it in the same burst are not answered.
+    reacked: Option<u16>,
+    on_block: Option<BlockCallback>,
+    on_progress: Option<ProgressCallback>,
+    on_size: Option<SizeCallback>,
+}
+
+impl<C: PacketSender + PacketReceiver, W: io::Write> Transfer<C, W> {
+    fn new(client: C, writer: W) -> Transfer<C, W> {
+        Transfer {
             client: client,
-            writer: writer,
+            writer: BufWriter::new(writer),
+            options: Vec::new(),
+            oack_received: false,
+            info: TransferInfo::default(),
+            max_file_size: None,
+            written: 0,
+            blocks: 0,
+            retransmit: Backoff::default(),
+            max_retries: 5,
+            retransmit_count: 0,
+            retransmits: 0,
+            progress: 0,
+            window: None,
+            mismatched_blocks: 0,
+            checkpoint: None,
+            resume_id: None,
+            reacked: None,
+            on_block: None,
+            on_progress: None,
+            on_size: None,
         }
     }
-}
 
-impl<'a> Client<'a> {
-    fn get(&mut self, path: &Path, mode: Mode) -> Result<()> {
-        let mut events = Events::with_capacity(1024);
-        let mut current_state = ClientStates::SendReadRequest(path, mode);
+    /// Retransmits unanswered packets on the given schedule, at most `max_retries` times.
+    fn with_retransmit(mut self, retransmit: Backoff, max_retries: u32) -> Transfer<C, W> {
+        self.retransmit = retransmit;
+        self.max_retries = max_retries;
+        self
+    }
 
-        try!(self.poll.register(&self.client.socket, CLIENT, Ready::writable(), PollOpt::level()));
+    /// Returns how long to wait for the server to answer the last packet sent.
+    fn retransmit_timeout(&self) -> Duration {
+        self.retransmit.timeout(self.retransmit_count)
+    }
 
-        loop {
-            try!(self.poll.poll(&mut events, None));
-            for event in events.iter() {
-                match event.token() {
-                    CLIENT => {
-                        current_state = try!(self.handle_event(current_state, event));
-                        if current_state.is_done() {
-                            return Ok(())
-                        }
+    /// Records that a new packet was sent, restarting the retransmission schedule.
+    fn packet_sent(&mut self) {
+        self.retransmit_count = 0;
+        self.progress += 1;
+    }
+
+    /// Records a block that is not acknowledged until its window is complete, which
+    /// restarts the retransmission schedule like a packet sent.
+    fn block_received(&mut self) {
+        self.retransmit_count = 0;
+        self.progress += 1;
+    }
+
+    /// Handles the server not answering within `retransmit_timeout`.
+    ///
+    /// While waiting for data the last packet is retransmitted, until `max_retries`
+    /// is exceeded and the transfer fails with `Error::Timeout`.
+    ///
+    /// A file that is a multiple of the block size ends with an empty block, which
+    /// some servers never send. If the server reported the size of the file with the
+    /// `tsize` option and all of it was received, the transfer ends instead.
+    fn handle_timeout<'b>(&mut self, current_state: ClientStates<'b>) -> Result<ClientStates<'b>> {
+        match current_state {
+            ClientStates::ReceivingData(_) if self.has_whole_file() => {
+                warn!("Server did not send the final empty block, the {} bytes it reported are all received",
+                      self.written);
+                try!(self.writer.flush());
+                info!("Transfer complete, {} bytes in {} blocks", self.written, self.blocks);
+                Ok(ClientStates::Done)
+            }
+            ClientStates::ReceivingData(current_id) => {
+                if self.retransmit_count >= self.max_retries {
+                    if self.resume_id == Some(current_id) {
+                        return Err(Error::ResumeRejected)
                     }
-                    _ => unreachable!(),
+                    return Err(Error::Timeout)
                 }
+                self.retransmit_count += 1;
+                self.retransmits += 1;
+                debug!("Timed out, retransmitting (attempt {})", self.retransmit_count);
+                trace!("timeout state=ReceivingData({}) retransmits={}", current_id, self.retransmit_count);
+                try!(self.client.retransmit_last_packet());
+                self.reacked = None;
+                Ok(current_state)
+            }
+            _ => Ok(current_state),
+        }
+    }
+
+    /// Whether the size of the file acknowledged with `tsize` has been received.
+    fn has_whole_file(&self) -> bool {
+        let file_size = self.checkpoint.as_ref().map_or(self.written, |checkpoint| checkpoint.byte_offset);
+        self.blocks > 0 && self.info.tsize == Some(file_size)
+    }
+
+    /// Aborts the transfer once the file is known to be larger than `max_file_size`.
+    fn with_max_file_size(mut self, max_file_size: Option<u64>) -> Transfer<C, W> {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Requests the given options from the server.
+    fn with_options(mut self, options: Vec<(String, String)>) -> Transfer<C, W> {
+        self.options = options;
+        self
+    }
+
+    /// Calls `on_block` with every block before it is written.
+    fn with_block_callback(mut self, on_block: Option<BlockCallback>) -> Transfer<C, W> {
+        self.on_block = on_block;
+        self
+    }
+
+    /// Calls `on_progress` after every block written.
+    fn with_progress_callback(mut self, on_progress: Option<ProgressCallback>) -> Transfer<C, W> {
+        self.on_progress = on_progress;
+        self
+    }
+
+    /// Continues the transfer saved in `checkpoint` and keeps it up to date.
+    ///
+    /// The writer has to be positioned at `byte_offset` already. Unless the transfer
+    /// starts at the beginning of the file, the first block is answered with an ACK of
+    /// the block before `block_id`, so the server carries on from there.
+    fn with_checkpoint(mut self, checkpoint: Option<ResumableTransfer>) -> Transfer<C, W> {
+        self.resume_id = match checkpoint {
+            Some(ref checkpoint) if checkpoint.byte_offset > 0 => Some(checkpoint.block_id),
+            _ => None,
+        };
+        self.checkpoint = checkpoint;
+        self
+    }
+
+    /// Handles the socket being ready in `current_state` and returns the next state.
+    ///
+    /// Every transition is logged at the trace level with the blocks and bytes
+    /// written so far, for example
+    /// `transition from=ReceivingData(3) to=SendAck(3) blocks=2 bytes=1024`.
+    fn handle_event<'b>(&mut self, current_state: ClientStates) -> Result<ClientStates<'b>> {
+        // The state is only formatted when the transition is going to be logged.
+        let from = if log_enabled!(Level::Trace) { Some(current_state.to_string()) } else { None };
+        let next = self.transition(current_state);
+        if let Some(from) = from {
+            match next {
+                Ok(ref to) => trace!("transition from={} to={} blocks={} bytes={}", from, to, self.blocks, self.written),
+                Err(ref err) => trace!("transition from={} error={:?} blocks={} bytes={}",
+                                       from, err.to_string(), self.blocks, self.written),
             }
         }
+        next
     }
 
-    fn handle_event<'b>(&mut self, current_state: ClientStates, event: Event) -> Result<ClientStates<'b>> {
+    fn transition<'b>(&mut self, current_state: ClientStates) -> Result<ClientStates<'b>> {
         match current_state {
             ClientStates::SendReadRequest(path, mode) => {
-                try!(self.client.send_read_request(path.to_str().unwrap(), mode));
-                println!("Starting transfer ...");
-                try!(self.poll.reregister(&self.client.socket, CLIENT, Ready::readable(), PollOpt::level()));
+                let filename = try!(request_filename(path));
+                try!(self.client.send_read_request(filename, mode, &self.options));
+                self.packet_sent();
+                info!("Requested {}", filename);
                 Ok(ClientStates::ReceivingData(1))
             }
             ClientStates::ReceivingData(current_id) => {
-                let data_packet = match try!(self.client.receive_data()) {
-                    Some(data_packet) => data_packet,
+                let data_packet = match try!(self.client.receive()) {
+                    // A server that does not support options ignores them and sends
+                    // the first block right away, the transfer uses the defaults then.
+                    Some(Received::Data(data_packet)) => {
+                        if current_id == 1 && !self.options.is_empty() && !self.oack_received {
+                            debug!("Server ignored the requested options, transferring with the defaults");
+                            self.info = TransferInfo::default();
+                        }
+                        data_packet
+                    }
+                    // Until the first block arrives, the server resends its OACK if our
+                    // ACK of block 0 was lost, so every copy is acknowledged again.
+                    Some(Received::Oack(ref oack)) if current_id == 1 && !self.options.is_empty() => {
+                        self.info = try!(TransferInfo::negotiated(&self.options, oack));
+                        self.oack_received = true;
+                        self.window = if self.info.window_size > 1 {
+                            Some(WindowedAck::new(self.info.window_size, 1))
+                        } else {
+                            None
+                        };
+                        if let (Some(max_file_size), Some(tsize)) = (self.max_file_size, self.info.tsize) {
+                            if tsize > max_file_size {
+                                try!(self.client.send_error(ErrorCode::DiskFull, "File too large"));
+                                return Err(Error::FileTooLarge(tsize))
+                            }
+                        }
+                        if let (Some(ref mut on_size), Some(tsize)) = (self.on_size.as_mut(), self.info.tsize) {
+                            on_size(tsize);
+                        }
+                        return self.handle_event(ClientStates::SendOptionsAck)
+                    }
+                    Some(Received::Oack(_)) => {
+                        debug!("Unexpected option acknowledgment");
+                        return Ok(ClientStates::ReceivingData(current_id))
+                    }
                     None => return Ok(ClientStates::ReceivingData(current_id)),
                 };
-                if current_id == data_packet.block_id() {
-                    self.handle_event(ClientStates::SendAck(data_packet), event)
-                } else {
-                    println!("Unexpected packet id: got={}, expected={}",
-                             data_packet.block_id(), current_id);
-                    Ok(ClientStates::ReceivingData(current_id))
+                let block_id = data_packet.block_id();
+                if data_packet.data().len() > self.info.block_size {
+                    let len = data_packet.data().len();
+                    self.client.put_buffer_data(data_packet.into_inner());
+                    try!(self.client.send_error(ErrorCode::IllegalOperation, "Block larger than the block size"));
+                    return Err(Error::ProtocolViolation(format!(
+                        "block {} has {} bytes, more than the block size of {}", block_id, len, self.info.block_size)))
                 }
-            }
-            ClientStates::SendAck(data_packet) => {
-                if try!(self.client.send_ack(data_packet.block_id())).is_none() {
-                    try!(self.poll.reregister(&self.client.socket, CLIENT, Ready::writable(), PollOpt::level()));
-                    println!("Could not send ack for packet id={}", data_packet.block_id());
-                    Ok(ClientStates::SendAck(data_packet))
-                } else {
-                    try!(self.writer.write_all(data_packet.data()));
-                    let data_len = data_packet.data().len();
-                    let next_id = data_packet.block_id() + 1;
+                if let Some(resume_id) = self.resume_id {
+                    if block_id == 1 {
+                        self.client.put_buffer_data(data_packet.into_inner());
+                        return self.request_resume(current_id, resume_id)
+                    }
+                    if block_id == resume_id {
+                        self.resume_id = None;
+                    }
+                }
+                if block_id == current_id {
+                    self.mismatched_blocks = 0;
+                    return self.handle_event(ClientStates::SendAck(data_packet))
+                }
+                self.mismatched_blocks += 1;
+                // The rest of a window is expected to follow a lost block.
+                let max_mismatched = MAX_MISMATCHED_BLOCKS + self.info.window_size as u32 - 1;
+                if self.mismatched_blocks > max_mismatched {
                     self.client.put_buffer_data(data_packet.into_inner());
-                    if data_len < MAX_DATA_SIZE {
-                        println!("Transfer complete");
-                        Ok(ClientStates::Done)
-                    } else {
-                        if event.kind().is_writable() {
-                            try!(self.poll.reregister(&self.client.socket, CLIENT, Ready::readable(), PollOpt::level()));
+                    return Err(Error::ProtocolViolation(format!(
+                        "block id mismatch: expected {}, got {} after {} unexpected blocks",
+                        current_id, block_id, max_mismatched)))
+                }
+                let buf = match block_id {
+                    // A block was lost within a window, the server is told about the
+                    // last block received in order so it sends the window again from
+                    // there. Only the first of the following blocks is answered.
+                    _ if self.info.window_size > 1 => {
+                        if self.mismatched_blocks == 1 {
+                            debug!("Block {} missing, resending the ack of the previous block", current_id);
+                            try!(self.client.send_ack(current_id.wrapping_sub(1)));
+                            self.packet_sent();
+                            if let Some(ref mut window) = self.window {
+                                window.restart(current_id);
+                            }
                         }
-                        Ok(ClientStates::ReceivingData(next_id))
+                        data_packet.into_inner()
                     }
-                }
-            }
-            _ => unreachable!()
-        }
+                    // The server resends a block it did not get the ACK for, the ACK was lost.
+                    // Copies of a block in a burst, duplicated on the way or resent by
+                    // the server while the ACK was underway, are answered once. A lost
+                    // re-ACK goes out again on the next timeout.
+                    id if is_older_block(id, current_id) && self.reacked == Some(id) => {
+                        debug!("Block {} received again, its ack was already resent", id);
+                        data_packet.into_inner()
+                    }
+                    id if is_older_block(id, current_id) => {
+                        debug!("Block {} received again, resending its ack", id);
+                        if try!(self.client.send_ack(id)).is_some() {
+                            self.reacked = Some(id);
+                        }
+                        data_packet.into_inner()
+                    }
+                    _ => {
+                        debug!("Unexpected {}, expected block {}", *data_packet, current_id);
+                        data_packet.into_inner()
+                    }
+                };
+                self.client.put_buffer_data(buf);
+                Ok(ClientStates::ReceivingData(current_id))
+            }
+            ClientStates::SendAck(data_packet) => {
+                // A block over the limit is refused, never acknowledged.
+                let written = self.written + data_packet.data().len() as u64;
+                if let Some(max_file_size) = self.max_file_size {
+                    if written > max_file_size {
+                        try!(self.client.send_error(ErrorCode::DiskFull, "File too large"));
+                        return Err(Error::FileTooLarge(written))
+                    }
+                }
+                let done = is_final_block(&data_packet, self.info.block_size);
+                let block_id = data_packet.block_id();
+                if let Some(ref mut window) = self.window {
+                    window.push(block_id);
+                }
+                // Within a window (RFC 7440) only its last block is acknowledged.
+                let window_complete = self.window.as_ref().map_or(true, |window| window.is_window_complete());
+                if done || window_complete {
+                    // Blocks arrive in order, but a rollover to block 1 leaves a gap in
+                    // the ids of the window.
+                    let ack_id = self.window.as_ref().and_then(|window| window.cumulative_ack()).unwrap_or(block_id);
+                    if try!(self.client.send_ack(ack_id)).is_none() {
+                        debug!("Could not send ack for packet id={}", ack_id);
+                        return Ok(ClientStates::SendAck(data_packet))
+                    }
+                    self.packet_sent();
+                    if let Some(ref mut window) = self.window {
+                        window.restart(next_block_id(ack_id, self.info.rollover));
+                    }
+                } else {
+                    self.block_received();
+                }
+
+                if let Some(ref mut on_block) = self.on_block {
+                    on_block(data_packet.block_id(), data_packet.data());
+                }
+                try!(self.write_block(data_packet.data()));
+                self.written = written;
+                self.blocks += 1;
+                self.reacked = None;
+                if let Some(ref mut on_progress) = self.on_progress {
+                    on_progress(&TransferProgress {
+                        bytes_transferred: written,
+                        total_bytes: self.info.tsize,
+                        block_id: data_packet.block_id(),
+                        block_size: self.info.block_size,
+                    });
+                }
+                let next_id = next_block_id(data_packet.block_id(), self.info.rollover);
+                if self.checkpoint.is_some() {
+                    // The checkpoint must not count bytes still in the buffer.
+                    try!(self.flush_writer());
+                }
+                if let Some(ref mut checkpoint) = self.checkpoint {
+                    checkpoint.block_id = next_id;
+                    checkpoint.byte_offset += data_packet.data().len() as u64;
+                    #[cfg(feature = "serde")]
+                    try!(checkpoint.save());
+                }
+                self.client.put_buffer_data(data_packet.into_inner());
+                if done {
+                    try!(self.flush_writer());
+                    info!("Transfer complete, {} bytes in {} blocks", self.written, self.blocks);
+                    Ok(ClientStates::Done)
+                } else {
+                    Ok(ClientStates::ReceivingData(next_id))
+                }
+            }
+            ClientStates::SendOptionsAck => {
+                if try!(self.client.send_ack(0)).is_none() {
+                    debug!("Could not send ack for the option acknowledgment");
+                    Ok(ClientStates::SendOptionsAck)
+                } else {
+                    self.packet_sent();
+                    Ok(ClientStates::ReceivingData(1))
+                }
+            }
+            _ => unreachable!()
+        }
+    }
+
+    fn write_block(&mut self, data: &[u8]) -> Result<()> {
+        match self.writer.write_all(data) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(self.write_failed(err)),
+        }
+    }
+
+    fn flush_writer(&mut self) -> Result<()> {
+        match self.writer.flush() {
+            Ok(()) => Ok(()),
+            Err(err) => Err(self.write_failed(err)),
+        }
+    }
+
+    /// Tells the server that the received data could not be written, so that it does
+    /// not wait for further ACKs.
+    ///
+    /// A writer piping the download to another process fails with a broken pipe once
+    /// that process exits, which is no reason to report a full disk.
+    fn write_failed(&mut self, err: io::Error) -> Error {
+        // The write error is the one reported, whether the server was told or not.
+        let _ = match err.kind() {
+            io::ErrorKind::BrokenPipe => self.client.send_error(ErrorCode::Undefined, "Output closed"),
+            _ => self.client.send_error(ErrorCode::DiskFull, "Could not write the file"),
+        };
+        Error::Io(err)
+    }
+
+    /// Handles block 1 of a resumed transfer, which is never written.
+    ///
+    /// The first copy is answered with an ACK of the block before `resume_id`. If the
+    /// server sends block 1 again it ignored that ACK, and the transfer is abandoned
+    /// with `Error::ResumeRejected`.
+    fn request_resume<'b>(&mut self, current_id: u16, resume_id: u16) -> Result<ClientStates<'b>> {
+        if current_id == resume_id {
+            // The resume failed either way, the server times out if it missed the error.
+            let _ = self.client.send_error(ErrorCode::Undefined, "Restarting transfer");
+            return Err(Error::ResumeRejected)
+        }
+        info!("Resuming transfer at block {}", resume_id);
+        if try!(self.client.send_ack(resume_id.wrapping_sub(1))).is_none() {
+            // Without the ACK the server resends block 1 once it timed out.
+            debug!("Could not send ack to resume the transfer");
+            return Ok(ClientStates::ReceivingData(current_id))
+        }
+        if let Some(ref mut window) = self.window {
+            window.restart(resume_id);
+        }
+        self.packet_sent();
+        Ok(ClientStates::ReceivingData(resume_id))
     }
 }
 
-pub fn get(path: &Path, mode: Mode, writer: &mut io::Write) {
-    println!("starting ...");
-    let remote_addr = "127.0.0.1:69".parse().unwrap();
-    let any = str::FromStr::from_str("0.0.0.0:0").unwrap();
-    let socket = UdpSocket::bind(&any).unwrap();
-    let poll =  Poll::new().unwrap();
-    let mut client = Client::new(poll, InternalClient::new(socket, remote_addr), writer);
-    client.get(path, mode).unwrap();
+/// A single transfer driven by the poll of its registration.
+struct Session<W: io::Write> {
+    registration: Registration,
+    transfer: Transfer<InternalClient, W>,
+    config: ClientConfig,
+}
+
+/// Token of the socket of a transfer in a poll of its own, the only one registered.
+const CLIENT: Token = Token(0);
+
+/// Called with the events of the other sources of a shared poll, see `Client::with_poll`.
+type EventCallback = Box<FnMut(Event) + Send>;
+
+/// The poll a transfer waits on, with the token its socket is registered under.
+struct Registration {
+    poll: Arc<Poll>,
+    token: Token,
+    /// Receives the events of other tokens, `None` for a poll of the client's own.
+    on_event: Option<EventCallback>,
+}
+
+impl Registration {
+    /// Returns a new poll for a single socket.
+    fn own() -> io::Result<Registration> {
+        Ok(Registration {
+            poll: Arc::new(try!(Poll::new())),
+            token: CLIENT,
+            on_event: None,
+        })
+    }
+
+    /// Returns true if `event` is one of the transfer, hands it to the source it
+    /// belongs to otherwise.
+    fn dispatch(&mut self, event: Event) -> bool {
+        if event.token() == self.token {
+            return true
+        }
+        match self.on_event {
+            Some(ref mut on_event) => on_event(event),
+            None => trace!("Ignoring event for token {:?}", event.token()),
+        }
+        false
+    }
+}
+
+impl<W: io::Write> Session<W> {
+    fn new(registration: Registration, client: InternalClient, writer: W, config: ClientConfig,
+           checkpoint: Option<ResumableTransfer>, on_block: Option<BlockCallback>,
+           on_progress: Option<ProgressCallback>) -> Session<W> {
+        Session {
+            registration: registration,
+            transfer: Transfer::new(client, writer)
+                .with_options(config.request_options())
+                .with_max_file_size(config.max_file_size)
+                .with_retransmit(config.retransmit.clone(), config.max_retries)
+                .with_checkpoint(checkpoint)
+                .with_block_callback(on_block)
+                .with_progress_callback(on_progress),
+            config: config,
+        }
+    }
+}
+
+impl<W: io::Write> Session<W> {
+    fn is_cancelled(&self) -> bool {
+        self.config.cancel.as_ref().map_or(false, |cancel| cancel.load(Ordering::SeqCst))
+    }
+
+    /// Returns the settings of the finished transfer, with the port the server
+    /// answered from, which the client adopted on the first block or OACK.
+    fn finished_info(&self) -> TransferInfo {
+        TransferInfo {
+            server_tid: self.transfer.client.remote_addr,
+            ..self.transfer.info.clone()
+        }
+    }
+
+    fn get(&mut self, path: &Path, mode: Mode) -> Result<TransferInfo> {
+        let result = self.run(path, mode);
+        // A shared poll outlives the transfer, and must not report the socket again.
+        if let Err(err) = self.registration.poll.deregister(&self.transfer.client.socket) {
+            debug!("Could not deregister the socket: {}", err);
+        }
+        result
+    }
+
+    fn run(&mut self, path: &Path, mode: Mode) -> Result<TransferInfo> {
+        let mut events = Events::with_capacity(self.config.events_capacity);
+        let mut current_state = ClientStates::SendReadRequest(path, mode);
+        let mut interest = current_state.interest();
+
+        try!(self.registration.poll.register(&self.transfer.client.socket, self.registration.token, interest,
+                                             PollOpt::level()));
+
+        let mut deadline = Instant::now() + self.transfer.retransmit_timeout();
+        let finish_by = self.config.deadline.map(|limit| Instant::now() + limit);
+        loop {
+            if self.is_cancelled() {
+                // The server learns about the cancellation on a best effort basis.
+                let _ = self.transfer.client.send_error(ErrorCode::Undefined, "Transfer cancelled");
+                return Err(Error::Cancelled)
+            }
+
+            let now = Instant::now();
+            if finish_by.map_or(false, |finish_by| now >= finish_by) {
+                // Best effort, like for a cancellation.
+                let _ = self.transfer.client.send_error(ErrorCode::Undefined, "Transfer deadline exceeded");
+                return Err(Error::DeadlineExceeded)
+            }
+            if now >= deadline {
+                current_state = try!(self.transfer.handle_timeout(current_state));
+                if current_state.is_done() {
+                    return Ok(self.finished_info())
+                }
+                deadline = now + self.transfer.retransmit_timeout();
+            }
+            let mut timeout = deadline - now;
+            if self.config.cancel.is_some() {
+                timeout = cmp::min(timeout, Duration::from_millis(CANCEL_POLL_INTERVAL_MS));
+            }
+            if let Some(finish_by) = finish_by {
+                timeout = cmp::min(timeout, finish_by - now);
+            }
+
+            match self.registration.poll.poll(&mut events, Some(timeout)) {
+                Ok(_) => {}
+                // A signal ended the poll early, the timeouts are checked again.
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(From::from(err)),
+            }
+            // Every event is handed on before the transfer may finish with this batch.
+            let mut ready = false;
+            for event in events.iter() {
+                ready |= self.registration.dispatch(event);
+            }
+            if !ready {
+                continue
+            }
+            let progress = self.transfer.progress;
+            current_state = try!(self.transfer.handle_event(current_state));
+            if current_state.is_done() {
+                return Ok(self.finished_info())
+            }
+            if self.transfer.progress != progress {
+                deadline = Instant::now() + self.transfer.retransmit_timeout();
+            }
+            if current_state.interest() != interest {
+                interest = current_state.interest();
+                try!(self.registration.poll.reregister(&self.transfer.client.socket, self.registration.token,
+                                                       interest, PollOpt::level()));
+            }
+        }
+    }
+}
+
+/// A TFTP client talking to the server at a fixed address.
+pub struct Client {
+    socket: UdpSocket,
+    remote_addr: SocketAddr,
+    config: ClientConfig,
+    audit_log: Option<Arc<AuditLog>>,
+    buffer: Option<Vec<u8>>,
+    on_block: Option<BlockCallback>,
+    on_progress: Option<ProgressCallback>,
+    /// Told the size of the file, for `get_into_vec` to reserve its buffer.
+    on_size: Option<SizeCallback>,
+    /// Poll and token handed in with `with_poll`, a new poll is created for every
+    /// transfer otherwise.
+    registration: Option<Registration>,
+}
+
+impl Client {
+    /// Creates a client for the server at `remote_addr`, bound to an ephemeral port.
+    pub fn new(remote_addr: SocketAddr) -> Result<Client> {
+        let any = str::FromStr::from_str("0.0.0.0:0").unwrap();
+        let socket = try!(UdpSocket::bind(&any));
+        Ok(Client::from_socket(socket, remote_addr))
+    }
+
+    /// Creates a client for the server at `remote_addr` that transfers over `socket`.
+    ///
+    /// The socket must already be bound and must not be registered with a `Poll` yet,
+    /// the client registers it with its own, or with the one given to `with_poll`.
+    pub fn from_socket(socket: UdpSocket, remote_addr: SocketAddr) -> Client {
+        Client {
+            socket: socket,
+            remote_addr: remote_addr,
+            config: ClientConfig::default(),
+            audit_log: None,
+            buffer: None,
+            on_block: None,
+            on_progress: None,
+            on_size: None,
+            registration: None,
+        }
+    }
+
+    /// Uses `config` for the transfer instead of the defaults.
+    pub fn with_config(mut self, mut config: ClientConfig) -> Client {
+        config.retransmit = config.retransmit.normalized();
+        self.config = config;
+        self
+    }
+
+    /// Requests the options of `options`, replacing the ones of the config.
+    ///
+    /// The mode of `options` is not kept, `get` and `into_stream` take the mode of
+    /// the transfer as an argument.
+    pub fn with_options(mut self, options: TransferOptions) -> Client {
+        self.config.block_size = options.blksize.map(|size| size as usize);
+        self.config.window_size = options.windowsize;
+        self.config.timeout = options.timeout_secs.map(|secs| Duration::from_secs(secs as u64));
+        self.config.tsize = options.request_tsize;
+        self
+    }
+
+    /// Reports every download to `audit_log`.
+    pub fn with_audit_log(mut self, audit_log: Arc<AuditLog>) -> Client {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Calls `on_block` with the id and the data of every block of a download, before
+    /// the block is written.
+    ///
+    /// Lets the data be hashed as it arrives. A block the server sends again is only
+    /// passed once, and the first block of a resumed download, which is not
+    /// written, not at all.
+    pub fn with_block_callback<F>(mut self, on_block: F) -> Client
+        where F: FnMut(u16, &[u8]) + Send + 'static
+    {
+        self.on_block = Some(Box::new(on_block));
+        self
+    }
+
+    /// Calls `on_progress` after every block of a download is written, with the
+    /// number of bytes written so far and the size of the file if the server told it.
+    ///
+    /// Request the size with `TransferOptions::request_tsize` to show a percentage.
+    /// This is the callback to use for progress reports; `with_block_callback` is
+    /// meant for code that needs the data itself.
+    pub fn with_progress_callback<F>(mut self, on_progress: F) -> Client
+        where F: FnMut(&TransferProgress) + Send + 'static
+    {
+        self.on_progress = Some(Box::new(on_progress));
+        self
+    }
+
+    /// Registers the socket with `poll` under `token`, instead of with a poll of its
+    /// own, for an application that drives other sources with the same poll.
+    ///
+    /// `token` must be unique among the tokens registered with `poll`: an event of
+    /// the socket registered under the token of another source would be taken for
+    /// an event of that source. The transfer blocks the thread and polls `poll`
+    /// itself until it is done; every event of another token it receives meanwhile
+    /// is passed to `on_event`, for the application to handle it as its own loop
+    /// would. The socket is deregistered from `poll` once the transfer ends.
+    /// Without `with_poll` the token is `Token(0)` of a poll of the client.
+    pub fn with_poll<F>(mut self, poll: Arc<Poll>, token: Token, on_event: F) -> Client
+        where F: FnMut(Event) + Send + 'static
+    {
+        self.registration = Some(Registration { poll: poll, token: token, on_event: Some(Box::new(on_event)) });
+        self
+    }
+
+    /// Receives the blocks of a download into `buffer` instead of allocating one.
+    ///
+    /// The buffer is grown if it can't hold a packet of the requested block size.
+    /// `get_reusing_buffer` hands it back, so one buffer can serve many transfers.
+    pub fn with_buffer(mut self, buffer: Vec<u8>) -> Client {
+        self.buffer = Some(buffer);
+        self
+    }
+
+    /// Downloads the file at `path` into `writer`.
+    ///
+    /// The blocks are buffered, `writer` gets few large writes and is flushed before
+    /// the transfer is reported as complete. The writer is a type parameter so the
+    /// writes need no dynamic dispatch; `&mut io::Write` works as well, as do the
+    /// `get_from` functions taking one.
+    pub fn get<W: io::Write>(self, path: &Path, mode: Mode, writer: W) -> Result<TransferInfo> {
+        self.transfer(path, mode, writer, None).map(|(info, _)| info)
+    }
+
+    /// Downloads the file at `path` into `writer` like `get`, in the default octet mode.
+    pub fn get_default_mode<W: io::Write>(self, path: &Path, writer: W) -> Result<TransferInfo> {
+        self.get(path, Mode::default(), writer)
+    }
+
+    /// Downloads the file at `path` into `writer` like `get`, and returns the receive
+    /// buffer whether the transfer succeeded or not.
+    ///
+    /// The buffer is the one given to `with_buffer`, or a newly allocated one. Pass it
+    /// to `with_buffer` of the next client to reuse it.
+    pub fn get_reusing_buffer<W: io::Write>(self, path: &Path, mode: Mode, writer: W)
+                              -> (Result<TransferInfo>, Vec<u8>) {
+        let (result, buffer) = self.transfer_with_buffer(path, mode, writer, None);
+        (result.map(|(info, _)| info), buffer)
+    }
+
+    /// Downloads the file at `path` into `writer`, continuing from `checkpoint` if given.
+    fn transfer<W: io::Write>(self, path: &Path, mode: Mode, writer: W,
+                checkpoint: Option<ResumableTransfer>) -> Result<(TransferInfo, TransferStats)> {
+        self.transfer_with_buffer(path, mode, writer, checkpoint).0
+    }
+
+    fn transfer_with_buffer<W: io::Write>(mut self, path: &Path, mode: Mode, writer: W,
+                                          checkpoint: Option<ResumableTransfer>)
+                                          -> (Result<(TransferInfo, TransferStats)>, Vec<u8>) {
+        // The server may ignore the options and send blocks of the default size.
+        let block_size = cmp::max(self.config.requested_block_size().unwrap_or(MAX_DATA_SIZE), MAX_DATA_SIZE);
+        let registration = match self.registration.take() {
+            Some(registration) => Ok(registration),
+            None => Registration::own(),
+        };
+        let (local_addr, registration) = match (self.socket.local_addr(), registration) {
+            (Ok(local_addr), Ok(registration)) => (local_addr, registration),
+            (Err(err), _) | (_, Err(err)) => {
+                let buffer = self.buffer.unwrap_or_else(|| vec![0; receive_buffer_size(block_size)]);
+                return (Err(From::from(err)), buffer)
+            }
+        };
+        let started = Instant::now();
+        let mut internal_client = InternalClient::new(self.socket, self.remote_addr, block_size);
+        if let Err(err) = internal_client.set_socket_buffer_sizes(self.config.socket_send_buf,
+                                                                  self.config.socket_recv_buf) {
+            let buffer = self.buffer.unwrap_or_else(|| vec![0; receive_buffer_size(block_size)]);
+            return (Err(err), buffer)
+        }
+        if let Some(buffer) = self.buffer {
+            internal_client = internal_client.with_receive_buffer(buffer);
+        }
+        let mut session = Session::new(registration, internal_client, writer, self.config, checkpoint, self.on_block,
+                                       self.on_progress);
+        session.transfer.on_size = self.on_size;
+        let result = session.get(path, mode);
+
+        if let Some(audit_log) = self.audit_log {
+            audit_log.record(AuditEntry {
+                timestamp: Utc::now(),
+                client: local_addr,
+                operation: "read",
+                path: &path.to_string_lossy(),
+                blocks: session.transfer.blocks,
+                bytes: session.transfer.written,
+                duration_ms: duration_ms(started.elapsed()),
+                error: result.as_ref().err().map(|err| err.to_string()),
+            });
+        }
+        let result = result.map(|info| {
+            let stats = TransferStats {
+                bytes: session.transfer.written,
+                blocks: session.transfer.blocks as u64,
+                retransmits: session.transfer.retransmits,
+            };
+            (info, stats)
+        });
+        (result, session.transfer.client.into_receive_buffer())
+    }
+
+    /// Asks the server at `remote_addr` for the size of the file at `path`, without
+    /// downloading it.
+    ///
+    /// The size is requested with the `tsize` option (RFC 2349) and the transfer is
+    /// aborted with an error packet as soon as the server answered. Returns `None` if
+    /// the server does not support options and starts sending the file instead.
+    pub fn probe(remote_addr: SocketAddr, path: &str) -> Result<Option<u64>> {
+        let filename = try!(request_filename(Path::new(path)));
+        let client = try!(Client::new(remote_addr));
+        let mut registration = try!(Registration::own());
+        try!(registration.poll.register(&client.socket, registration.token, Ready::readable(), PollOpt::level()));
+        try!(report_icmp_errors(&client.socket, true));
+
+        let request = RequestPacket::read_request_with_options(filename, Mode::Octet, &[("tsize", "0")]).encode();
+        let mut retransmits = 0;
+        let (size, from) = try!(exchange(&mut registration, &client.socket, request.packet_buf(), &remote_addr,
+                                         &client.config, &mut retransmits, |packet, _| {
+            match packet.opcode() {
+                Some(Opcode::OACK) => {
+                    let oack: OackPacket = try!(packet.decode());
+                    Ok(Some(oack.option("tsize").and_then(|size| size.parse().ok())))
+                }
+                Some(Opcode::DATA) => Ok(Some(None)),
+                Some(Opcode::ERROR) => Err(server_error(packet)),
+                _ => Ok(None),
+            }
+        }));
+
+        let abort = ErrorPacket::new(ErrorCode::Undefined, "Only the size was requested").encode();
+        try!(client.socket.send_to(abort.packet_buf(), &from));
+        Ok(size)
+    }
+
+    /// Asks the server at `remote_addr` whether the file at `path` exists, without
+    /// downloading it.
+    ///
+    /// The file is requested and the transfer aborted with an error packet as soon as
+    /// the server starts it. Returns false if the server answered with "File not
+    /// found", other server errors fail.
+    pub fn exists(remote_addr: SocketAddr, path: &Path) -> Result<bool> {
+        let filename = try!(request_filename(path));
+        let client = try!(Client::new(remote_addr));
+        let mut registration = try!(Registration::own());
+        try!(registration.poll.register(&client.socket, registration.token, Ready::readable(), PollOpt::level()));
+        try!(report_icmp_errors(&client.socket, true));
+
+        let request = RequestPacket::read_request(filename, Mode::Octet).encode();
+        let mut retransmits = 0;
+        let result = exchange(&mut registration, &client.socket, request.packet_buf(), &remote_addr, &client.config,
+                              &mut retransmits, |packet, _| {
+            match packet.opcode() {
+                Some(Opcode::DATA) | Some(Opcode::OACK) => Ok(Some(())),
+                Some(Opcode::ERROR) => Err(server_error(packet)),
+                _ => Ok(None),
+            }
+        });
+        let from = match result {
+            Ok(((), from)) => from,
+            Err(Error::Server(ref err)) if err.error() == ErrorCode::FileNotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        let abort = ErrorPacket::new(ErrorCode::Undefined, "Only the existence was checked").encode();
+        try!(client.socket.send_to(abort.packet_buf(), &from));
+        Ok(true)
+    }
+
+    /// Starts uploading to `path` and returns a stream that writes the file.
+    ///
+    /// Fails if the server does not acknowledge the write request. The `block_size`
+    /// and `timeout` options of the config are requested, blocks are 512 bytes unless
+    /// the server acknowledges another size.
+    pub fn into_stream(self, path: &Path, mode: Mode) -> Result<TftpWriteStream> {
+        let filename = try!(request_filename(path));
+        let registration = match self.registration {
+            Some(registration) => registration,
+            None => try!(Registration::own()),
+        };
+        try!(registration.poll.register(&self.socket, registration.token, Ready::readable(), PollOpt::level()));
+
+        let options = self.config.write_request_options();
+        let request = RequestPacket::write_request_with_options(filename, mode, &options);
+        let mut stream = TftpWriteStream {
+            registration: registration,
+            socket: self.socket,
+            remote_addr: self.remote_addr,
+            config: self.config,
+            block_size: MAX_DATA_SIZE,
+            block: Vec::with_capacity(MAX_DATA_SIZE),
+            block_id: 0,
+            packet: request.encode().into_bytes(),
+            stats: TransferStats::default(),
+            finished: false,
+        };
+        if let Some(info) = try!(stream.send_and_wait_for_ack(&options)) {
+            stream.block_size = info.block_size;
+        }
+        Ok(stream)
+    }
+}
+
+/// A download to a local file that can be continued after it failed.
+///
+/// While the file is downloaded, the progress is saved to a sidecar file next to it
+/// (`local_path` with `.tftp-resume` appended) after every block. If the transfer
+/// fails, `ResumableTransfer::load` reads the progress back and `resume` continues the
+/// download where it stopped. The sidecar file is removed once the transfer completes.
+/// Saving, loading and resuming need the `serde` feature, the sidecar file is JSON.
+///
+/// TFTP has no way to request a file from an offset. A resumed transfer requests the
+/// file again and answers block 1 with an ACK of the last block written, servers that
+/// accept it go on with the block after it. Servers that only implement RFC 1350 send
+/// block 1 again, then the file is downloaded again from the start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ResumableTransfer {
+    /// Id of the next block to receive.
+    pub block_id: u16,
+    /// Number of bytes of the file written so far.
+    pub byte_offset: u64,
+    /// Name of the file on the server.
+    pub remote_path: String,
+    /// Path the file is downloaded to.
+    pub local_path: PathBuf,
+}
+
+impl ResumableTransfer {
+    /// Creates a transfer of `remote_path` to `local_path` that starts at the beginning.
+    pub fn new(remote_path: &str, local_path: &Path) -> ResumableTransfer {
+        ResumableTransfer {
+            block_id: 1,
+            byte_offset: 0,
+            remote_path: remote_path.to_string(),
+            local_path: local_path.to_path_buf(),
+        }
+    }
+
+    /// Reads the progress of the download to `local_path` from its sidecar file.
+    ///
+    /// A malformed sidecar file is reported as an `InvalidData` error.
+    #[cfg(feature = "serde")]
+    pub fn load(local_path: &Path) -> io::Result<ResumableTransfer> {
+        let file = try!(File::open(resume_path(local_path)));
+        serde_json::from_reader(io::BufReader::new(file)).map_err(io::Error::from)
+    }
+
+    /// Returns the path of the sidecar file the progress is saved to.
+    pub fn resume_path(&self) -> PathBuf {
+        resume_path(&self.local_path)
+    }
+
+    /// Writes the progress to the sidecar file.
+    #[cfg(feature = "serde")]
+    pub fn save(&self) -> io::Result<()> {
+        let file = try!(File::create(self.resume_path()));
+        serde_json::to_writer(file, self).map_err(io::Error::from)
+    }
+
+    /// Downloads the rest of the file with `client`.
+    ///
+    /// The local file is cut off after `byte_offset` and the download continues from
+    /// there. If the server does not support resuming, or the local file is shorter
+    /// than `byte_offset`, it is downloaded again from the start, over a new socket.
+    #[cfg(feature = "serde")]
+    pub fn resume(self, client: Client, mode: Mode) -> Result<TransferInfo> {
+        let remote_addr = client.remote_addr;
+        let config = client.config.clone();
+        let audit_log = client.audit_log.clone();
+
+        let mut file = try!(fs::OpenOptions::new().write(true).create(true).truncate(false)
+            .open(&self.local_path));
+        let checkpoint = if try!(file.metadata()).len() >= self.byte_offset {
+            self
+        } else {
+            ResumableTransfer::new(&self.remote_path, &self.local_path)
+        };
+        let local_path = checkpoint.local_path.clone();
+        let restart = ResumableTransfer::new(&checkpoint.remote_path, &checkpoint.local_path);
+
+        let result = match checkpoint.download(client, mode, &mut file) {
+            Err(Error::ResumeRejected) => {
+                info!("Resuming was rejected, downloading the whole file");
+                let mut client = try!(Client::new(remote_addr)).with_config(config);
+                client.audit_log = audit_log;
+                restart.download(client, mode, &mut file)
+            }
+            result => result,
+        };
+        if result.is_ok() {
+            try!(file.sync_all());
+            try!(fs::remove_file(resume_path(&local_path)));
+        }
+        result
+    }
+
+    #[cfg(feature = "serde")]
+    fn download(self, client: Client, mode: Mode, file: &mut File) -> Result<TransferInfo> {
+        try!(file.set_len(self.byte_offset));
+        try!(file.seek(SeekFrom::Start(self.byte_offset)));
+        try!(self.save());
+        let remote_path = PathBuf::from(&self.remote_path);
+        client.transfer(&remote_path, mode, file, Some(self)).map(|(info, _)| info)
+    }
+}
+
+/// Returns the path of the sidecar file of a resumable download to `local_path`.
+fn resume_path(local_path: &Path) -> PathBuf {
+    let mut path = local_path.as_os_str().to_owned();
+    path.push(".tftp-resume");
+    PathBuf::from(path)
+}
+
+/// Statistics of a finished transfer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TransferStats {
+    /// Number of bytes of the file.
+    pub bytes: u64,
+
+    /// Number of data blocks sent or received, retransmissions excluded.
+    pub blocks: u64,
+
+    /// Number of packets sent again because the server did not acknowledge them in time.
+    pub retransmits: u64,
+}
+
+/// An upload to a TFTP server, written through `io::Write`.
+///
+/// Written bytes are buffered until a full block is assembled, which is sent before
+/// `write` returns, waiting for its acknowledgment. `flush` sends the remaining bytes
+/// as the final, short block and ends the transfer; `close` does the same and reports
+/// the statistics of the transfer.
+pub struct TftpWriteStream {
+    registration: Registration,
+    socket: UdpSocket,
+    remote_addr: SocketAddr,
+    config: ClientConfig,
+    /// Size of the blocks, 512 bytes unless another size was negotiated.
+    block_size: usize,
+    /// Bytes of the block being assembled.
+    block: Vec<u8>,
+    /// Id of the last block sent.
+    block_id: u16,
+    /// Bytes of the last packet sent, kept for retransmission.
+    packet: Vec<u8>,
+    stats: TransferStats,
+    finished: bool,
+}
+
+impl TftpWriteStream {
+    /// Sends the final block unless it was sent already and returns the statistics.
+    pub fn close(mut self) -> Result<TransferStats> {
+        try!(self.finish());
+        Ok(self.stats.clone())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if !self.finished {
+            try!(self.send_block());
+            self.finished = true;
+        }
+        Ok(())
+    }
+
+    /// Sends the assembled block, which may be short or empty, and waits for its ACK.
+    fn send_block(&mut self) -> Result<()> {
+        self.block_id = self.block_id.wrapping_add(1);
+        let packet = mem::replace(&mut self.packet, Vec::new());
+        self.packet = DataPacketOctet::from_slice(self.block_id, &self.block).encode_using(packet).into_bytes();
+        try!(self.send_and_wait_for_ack(&[]));
+        self.stats.bytes += self.block.len() as u64;
+        self.stats.blocks += 1;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Sends the packet in `packet` until the server acknowledges `block_id`.
+    ///
+    /// The write request is acknowledged from the port of the server that handles
+    /// the transfer, later acknowledgments are only accepted from that port. If the
+    /// write request carried `options`, the server may acknowledge it with an OACK
+    /// instead, the negotiated settings are returned then.
+    fn send_and_wait_for_ack(&mut self, options: &[(String, String)]) -> Result<Option<TransferInfo>> {
+        let block_id = self.block_id;
+        let remote_addr = self.remote_addr;
+        let (info, from) = try!(exchange(&mut self.registration, &self.socket, &self.packet, &remote_addr,
+                                         &self.config, &mut self.stats.retransmits, |packet, from| {
+            if block_id != 0 && from != remote_addr {
+                return Ok(None)
+            }
+            match packet.opcode() {
+                Some(Opcode::ACK) => {
+                    let ack: AckPacket = try!(packet.decode());
+                    Ok(if ack.block_id() == block_id { Some(None) } else { None })
+                }
+                Some(Opcode::OACK) if block_id == 0 && !options.is_empty() => {
+                    let oack: OackPacket = try!(packet.decode());
+                    TransferInfo::negotiated(options, &oack)
+                        .map(|info| Some(Some(TransferInfo { server_tid: from, ..info })))
+                }
+                Some(Opcode::ERROR) => Err(server_error(packet)),
+                _ => Ok(None),
+            }
+        }));
+        self.remote_addr = from;
+        Ok(info)
+    }
+}
+
+/// Sends `packet` to `remote_addr` and waits for a reply that `handle` accepts by
+/// returning a value, retransmitting the packet on the schedule of `config`.
+///
+/// `socket` must be registered with the poll of `registration` for readable events
+/// under its token, events of other tokens are dispatched by the registration.
+/// Returns the value and the address of the reply; retransmissions are added to
+/// `retransmits`.
+fn exchange<T, F>(registration: &mut Registration, socket: &UdpSocket, packet: &[u8], remote_addr: &SocketAddr,
+                  config: &ClientConfig, retransmits: &mut u64, mut handle: F) -> Result<(T, SocketAddr)>
+    where F: FnMut(&RawPacket, SocketAddr) -> Result<Option<T>>
+{
+    let mut events = Events::with_capacity(config.events_capacity);
+    let mut buf = vec![0; recv_buffer_size(MAX_DATA_SIZE)];
+    let mut retransmit_count = 0;
+    // A packet that would block is lost, the timeout sends it again.
+    try!(socket.send_to(packet, remote_addr));
+    let mut deadline = Instant::now() + config.retransmit.timeout(retransmit_count);
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            if retransmit_count >= config.max_retries {
+                return Err(Error::Timeout)
+            }
+            retransmit_count += 1;
+            *retransmits += 1;
+            debug!("Timed out, retransmitting (attempt {})", retransmit_count);
+            try!(socket.send_to(packet, remote_addr));
+            deadline = now + config.retransmit.timeout(retransmit_count);
+            continue
+        }
+        match registration.poll.poll(&mut events, Some(deadline - now)) {
+            Ok(_) => {}
+            // A signal ended the poll early, it is not a timeout.
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(From::from(err)),
+        }
+        let mut ready = false;
+        for event in events.iter() {
+            ready |= registration.dispatch(event);
+        }
+        if !ready {
+            continue
+        }
+
+        while let Some((n, from)) = try!(socket.recv_from(&mut buf)) {
+            let packet = RawPacket::new(mem::replace(&mut buf, Vec::new()), n);
+            let handled = try!(handle(&packet, from));
+            buf = packet.get_buffer();
+            if let Some(value) = handled {
+                return Ok((value, from))
+            }
+        }
+    }
+}
+
+/// Returns the error sent by the server in `packet`.
+fn server_error(packet: &RawPacket) -> Error {
+    match packet.decode::<ErrorPacket>() {
+        Ok(error) => Error::from_server(error.into_owned()),
+        Err(err) => Error::Protocol(err),
+    }
+}
+
+/// Converts a client error, as needed for `io::Write`, keeping I/O errors as they are.
+///
+/// Errors sent by the server get the kind matching their error code.
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            Error::Server(packet) => io::Error::new(io::ErrorKind::from(packet.error()), Error::Server(packet)),
+            err => io::Error::new(io::ErrorKind::Other, err),
+        }
+    }
+}
+
+impl Drop for TftpWriteStream {
+    fn drop(&mut self) {
+        // A shared poll outlives the stream, and must not report the socket again.
+        if let Err(err) = self.registration.poll.deregister(&self.socket) {
+            debug!("Could not deregister the socket: {}", err);
+        }
+    }
+}
+
+impl io::Write for TftpWriteStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::new(io::ErrorKind::Other, "transfer is already finished"))
+        }
+        let n = cmp::min(buf.len(), self.block_size - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if self.block.len() == self.block_size {
+            try!(self.send_block().map_err(io::Error::from));
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.finish().map_err(io::Error::from)
+    }
+}
+
+/// A fixed number of clients shared by downloads, which wait for a free client.
+///
+/// A client keeps its receive buffer and its poll from one transfer to the next.
+/// After every transfer it gets a new socket on an ephemeral port, so no server sees
+/// the transfer identifier of an earlier transfer; the old socket is deregistered
+/// from the poll and the new one registered for the next transfer.
+pub struct ClientPool {
+    inner: Mutex<PoolState>,
+    /// Signalled whenever a client is put back.
+    returned: Condvar,
+    config: ClientConfig,
+}
+
+struct PoolState {
+    /// Free clients, with the poll each of them is registered with.
+    clients: VecDeque<(Client, Arc<Poll>)>,
+    /// Polls of the clients that could not get a new socket after their transfer,
+    /// created again when they are checked out.
+    unbound: Vec<Arc<Poll>>,
+}
+
+impl ClientPool {
+    /// Creates a pool of `size` clients with the given configuration.
+    pub fn new(size: usize, config: ClientConfig) -> Result<ClientPool> {
+        let mut clients = VecDeque::with_capacity(size);
+        for _ in 0..size {
+            let poll = Arc::new(try!(Poll::new()));
+            clients.push_back((try!(pooled_client(&config, None, poll.clone())), poll));
+        }
+        Ok(ClientPool {
+            inner: Mutex::new(PoolState { clients: clients, unbound: Vec::new() }),
+            returned: Condvar::new(),
+            config: config,
+        })
+    }
+
+    /// Returns the number of clients free for a transfer.
+    pub fn available(&self) -> usize {
+        let state = self.inner.lock().unwrap();
+        state.clients.len() + state.unbound.len()
+    }
+
+    /// Downloads the file at `path` from the server at `remote_addr` into `writer`,
+    /// in octet mode.
+    ///
+    /// Blocks until a client of the pool is free.
+    pub fn get<W: io::Write>(&self, remote_addr: SocketAddr, path: &Path, writer: W) -> Result<TransferStats> {
+        let (mut client, poll) = try!(self.checkout());
+        client.remote_addr = remote_addr;
+        let (result, buffer) = client.transfer_with_buffer(path, Mode::default(), writer, None);
+        self.put_back(buffer, poll);
+        result.map(|(_, stats)| stats)
+    }
+
+    fn checkout(&self) -> Result<(Client, Arc<Poll>)> {
+        let mut state = self.inner.lock().unwrap();
+        loop {
+            if let Some(client) = state.clients.pop_front() {
+                return Ok(client)
+            }
+            if let Some(poll) = state.unbound.pop() {
+                drop(state);
+                return match pooled_client(&self.config, None, poll.clone()) {
+                    Ok(client) => Ok((client, poll)),
+                    Err(err) => {
+                        self.inner.lock().unwrap().unbound.push(poll);
+                        Err(err)
+                    }
+                }
+            }
+            state = self.returned.wait(state).unwrap();
+        }
+    }
+
+    fn put_back(&self, buffer: Vec<u8>, poll: Arc<Poll>) {
+        let client = pooled_client(&self.config, Some(buffer), poll.clone());
+        let mut state = self.inner.lock().unwrap();
+        match client {
+            Ok(client) => state.clients.push_back((client, poll)),
+            Err(err) => {
+                warn!("Could not bind a new socket for a pooled client: {}", err);
+                state.unbound.push(poll);
+            }
+        }
+        self.returned.notify_one();
+    }
+}
+
+/// Creates a client of a pool on a new socket, registered with `poll` for its
+/// transfer; the server is set at checkout.
+fn pooled_client(config: &ClientConfig, buffer: Option<Vec<u8>>, poll: Arc<Poll>) -> Result<Client> {
+    let unspecified = str::FromStr::from_str("0.0.0.0:0").unwrap();
+    let mut client = try!(Client::new(unspecified)).with_config(config.clone());
+    client.registration = Some(Registration { poll: poll, token: CLIENT, on_event: None });
+    Ok(match buffer {
+        Some(buffer) => client.with_buffer(buffer),
+        None => client,
+    })
+}
+
+/// Downloads the file at `path` from the server at `remote_addr` into `writer`.
+pub fn get_from(remote_addr: SocketAddr, path: &Path, mode: Mode, writer: &mut io::Write) -> Result<()> {
+    get_from_with_config(remote_addr, path, mode, writer, &ClientConfig::default()).map(|_| ())
+}
+
+/// Downloads the file at `path` from the server at `remote_addr` into `writer`, in
+/// the default octet mode.
+pub fn get_from_default_mode(remote_addr: SocketAddr, path: &Path, writer: &mut io::Write) -> Result<()> {
+    get_from(remote_addr, path, Mode::default(), writer)
+}
+
+/// Downloads the file at `path` from the server at `remote_addr` into `writer`,
+/// using the given configuration.
+///
+/// Returns the settings the transfer was conducted with, as negotiated with the server.
+pub fn get_from_with_config(remote_addr: SocketAddr, path: &Path, mode: Mode, writer: &mut io::Write,
+                            config: &ClientConfig) -> Result<TransferInfo> {
+    let client = try!(Client::new(remote_addr));
+    client.with_config(config.clone()).get(path, mode, writer)
+}
+
+/// Downloads the file at `path` from the server at `remote_addr` into `writer` like
+/// `get_from_with_config`, in octet mode, making up to `attempts` attempts.
+///
+/// A transfer that failed for a reason that may go away is started again: a timeout,
+/// a busy server, or an interrupted or would-block I/O error. Errors such as a file
+/// not found or an access violation are returned right away, as is every error of a
+/// transfer that wrote to `writer` already, since `writer` can't be rewound.
+///
+/// Before attempt `n + 2` the client waits `base * 2^n` plus a random part of up to
+/// `base`, where `base` is the first retransmission wait of `config`. Returns the error
+/// of the last attempt once all of them failed.
+pub fn tftp_get_retry(addr: SocketAddr, path: &Path, writer: &mut io::Write, config: &ClientConfig,
+                      attempts: u32) -> Result<TransferStats> {
+    let base = config.retransmit.base;
+    let mut attempt = 0;
+    loop {
+        let mut counting = CountingWriter { inner: &mut *writer, written: 0 };
+        let result = Client::new(addr).and_then(|client| {
+            client.with_config(config.clone()).transfer(path, Mode::Octet, &mut counting, None)
+        });
+        let err = match result {
+            Ok((_, stats)) => return Ok(stats),
+            Err(err) => err,
+        };
+        attempt += 1;
+        if attempt >= attempts || counting.written > 0 || !is_transient(&err) {
+            return Err(err)
+        }
+        let delay = base * (1 << cmp::min(attempt - 1, 16)) + jitter(base);
+        warn!("Attempt {} of {} failed: {}, retrying in {:?}", attempt, attempts, err, delay);
+        thread::sleep(delay);
+    }
+}
+
+/// Whether a transfer that failed with `err` may succeed when started again.
+fn is_transient(err: &Error) -> bool {
+    match *err {
+        Error::Timeout | Error::ServerBusy => true,
+        Error::Io(ref err) => err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::Interrupted,
+        _ => false,
+    }
+}
+
+/// Returns a random duration shorter than `max`, so that clients failing together
+/// don't retry together.
+fn jitter(max: Duration) -> Duration {
+    let nanos = max.as_nanos() as u64;
+    if nanos == 0 {
+        return Duration::from_secs(0)
+    }
+    // Every `RandomState` is seeded with new random keys.
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_nanos(random % nanos)
+}
+
+/// Passes writes on to `inner`, counting the bytes written.
+struct CountingWriter<W: io::Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: io::Write> io::Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Downloads the file at `path` from the server at `remote_addr` into `writer`,
+/// requesting `options`.
+///
+/// Returns the settings the transfer was conducted with, as negotiated with the server.
+pub fn get_with_options(remote_addr: SocketAddr, path: &Path, writer: &mut io::Write,
+                        options: &TransferOptions) -> Result<TransferInfo> {
+    let client = try!(Client::new(remote_addr));
+    client.with_options(options.clone()).get(path, options.mode, writer)
+}
+
+/// Uploads the contents of `reader` to `path` on the server at `remote_addr`,
+/// requesting `options`.
+///
+/// Of the options only `blksize` and `timeout_secs` apply to uploads.
+pub fn put_with_options(remote_addr: SocketAddr, path: &Path, reader: &mut io::Read,
+                        options: &TransferOptions) -> Result<TransferStats> {
+    let client = try!(Client::new(remote_addr));
+    let mut stream = try!(client.with_options(options.clone()).into_stream(path, options.mode));
+    try!(io::copy(reader, &mut stream));
+    stream.close()
+}
+
+/// Most bytes `get_into_vec` reserves up front, whatever size the server reports.
+const MAX_PREALLOCATED_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Downloads the file at `path` from the server at `remote_addr` and returns its contents.
+///
+/// The client requests the `tsize` option and reserves the buffer for the size the
+/// server acknowledges, up to `MAX_PREALLOCATED_SIZE` bytes so that a bogus size
+/// can't make it allocate much memory. Beyond that, or without the option, the
+/// buffer grows as blocks arrive.
+pub fn get_into_vec(remote_addr: SocketAddr, path: &Path, mode: Mode) -> Result<Vec<u8>> {
+    let contents = Arc::new(Mutex::new(Vec::new()));
+    let reserved = contents.clone();
+    let mut client = try!(Client::new(remote_addr))
+        .with_config(ClientConfig { tsize: true, ..ClientConfig::default() });
+    client.on_size = Some(Box::new(move |size| {
+        reserved.lock().unwrap().reserve_exact(preallocated_size(size));
+    }));
+    try!(client.get(path, mode, SharedVec(contents.clone())));
+    // The callback holding the other reference went away with the client.
+    let contents = Arc::try_unwrap(contents).map_err(|_| ()).expect("buffer still shared");
+    Ok(contents.into_inner().unwrap())
+}
+
+/// Number of bytes to reserve for a file the server says is `size` bytes long.
+fn preallocated_size(size: u64) -> usize {
+    cmp::min(size, MAX_PREALLOCATED_SIZE) as usize
+}
+
+/// Writes to a buffer shared with the size callback of `get_into_vec`.
+struct SharedVec(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for SharedVec {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Downloads the file named by `uri` and returns its contents, like `get_into_vec`.
+///
+/// The host of the URI is looked up first.
+pub fn get_uri_into_vec(uri: &TftpUri, mode: Mode) -> Result<Vec<u8>> {
+    let remote_addr = try!(uri.resolve());
+    get_into_vec(remote_addr, uri.path(), mode)
+}
+
+/// Downloads the file at `path` from the server at `remote_addr` into the file at `dest`.
+///
+/// The file is written to `dest` with `.part` appended and only renamed to `dest` once
+/// the transfer succeeded, so `dest` never holds a truncated file. The partial file is
+/// removed if the transfer fails and overwritten if it is left over from earlier.
+pub fn get_to_file(remote_addr: SocketAddr, path: &Path, mode: Mode, dest: &Path) -> Result<()> {
+    get_to_file_with_config(remote_addr, path, mode, dest, &ClientConfig::default())
+}
+
+/// Downloads the file at `path` from the server at `remote_addr` into the file at
+/// `dest` like `get_to_file`, using the given configuration.
+///
+/// With `sync_file` set, the file is synced to the disk before it is renamed to
+/// `dest`, a failure to do so fails the transfer with `Error::Io`.
+pub fn get_to_file_with_config(remote_addr: SocketAddr, path: &Path, mode: Mode, dest: &Path,
+                               config: &ClientConfig) -> Result<()> {
+    let client = try!(Client::new(remote_addr));
+    download_file(client.with_config(config.clone()), path, mode, dest).map(|_| ())
+}
+
+/// Downloads the files of `transfers` from the server at `remote_addr`, `parallelism`
+/// files at a time.
+///
+/// Every pair of `transfers` names the file on the server and the path to store it
+/// at. The files are downloaded in octet mode by `parallelism` threads, each file with
+/// a client of its own using `config`. Like with `get_to_file`, a failed download
+/// leaves no file behind, and does not stop the downloads of the other files.
+///
+/// Returns the result of every transfer, in the order of `transfers`.
+pub fn download_all(remote_addr: SocketAddr, transfers: &[(&Path, &Path)], config: &ClientConfig,
+                    parallelism: usize) -> Vec<Result<TransferStats>> {
+    let queue: VecDeque<(usize, PathBuf, PathBuf)> = transfers.iter().enumerate()
+        .map(|(index, &(path, dest))| (index, path.to_path_buf(), dest.to_path_buf()))
+        .collect();
+    let queue = Arc::new(Mutex::new(queue));
+    let (tx, rx) = mpsc::channel();
+
+    let workers: Vec<_> = (0..cmp::max(1, cmp::min(parallelism, transfers.len()))).map(|_| {
+        let queue = queue.clone();
+        let tx = tx.clone();
+        let config = config.clone();
+        thread::spawn(move || loop {
+            let next = queue.lock().unwrap().pop_front();
+            let (index, path, dest) = match next {
+                Some(transfer) => transfer,
+                None => return,
+            };
+            let result = Client::new(remote_addr)
+                .and_then(|client| download_file(client.with_config(config.clone()), &path, Mode::Octet, &dest));
+            tx.send((index, result)).unwrap();
+        })
+    }).collect();
+    drop(tx);
+
+    let mut results: Vec<Option<Result<TransferStats>>> = transfers.iter().map(|_| None).collect();
+    for (index, result) in rx {
+        results[index] = Some(result);
+    }
+    for worker in workers {
+        // A panic is reported below as the missing result of the transfer.
+        let _ = worker.join();
+    }
+    // Only a panicking worker leaves a transfer without a result.
+    results.into_iter().map(|result| result.unwrap_or_else(|| {
+        Err(Error::Io(io::Error::new(io::ErrorKind::Other, "download thread panicked")))
+    })).collect()
+}
+
+/// Downloads the file at `path` into `dest` through its part file.
+fn download_file(client: Client, path: &Path, mode: Mode, dest: &Path) -> Result<TransferStats> {
+    let part = part_path(dest);
+    let result = download_to(client, path, mode, &part)
+        .and_then(|stats| fs::rename(&part, dest).map(|_| stats).map_err(From::from));
+    if result.is_err() {
+        // The transfer error matters more than a part file that could not be removed.
+        let _ = fs::remove_file(&part);
+    }
+    result
+}
+
+/// Returns the path a download to `dest` is written to until it completes.
+fn part_path(dest: &Path) -> PathBuf {
+    let mut part = dest.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+fn download_to(client: Client, path: &Path, mode: Mode, file_path: &Path) -> Result<TransferStats> {
+    let sync_file = client.config.sync_file;
+    let mut file = try!(File::create(file_path));
+    let (_, stats) = try!(client.transfer(path, mode, &mut file, None));
+    if sync_file {
+        try!(file.sync_all());
+    }
+    Ok(stats)
+}
+
+pub fn get(path: &Path, mode: Mode, writer: &mut io::Write) {
+    let remote_addr = "127.0.0.1:69".parse().unwrap();
+    get_from(remote_addr, path, mode, writer).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "serde")]
+    extern crate serde_json;
+
+    use std::cell::RefCell;
+    use std::cmp;
+    use std::collections::VecDeque;
+    use std::fs;
+    use std::io;
+    use std::net;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+    use std::thread;
+
+    use log;
+    use mio::Ready;
+    use mio::udp::UdpSocket;
+    use socket2::SockRef;
+
+    use decodedpacket::DecodedPacket;
+    use test_server::{Fault, FaultConfig, TestServer, temp_dir};
+    use packet::{Mode, RequestPacket, DataPacketOctet, OackPacket, ErrorPacket, EncodePacket, DecodePacket,
+                 DecodeError, Error as ErrorCode, recv_buffer_size};
+
+    use std::time::Duration;
+
+    use super::{Client, ClientConfig, ClientPool, TransferOptions, download_all, get_from_default_mode, get_into_vec, get_with_options, preallocated_size, MAX_PREALLOCATED_SIZE, MAX_TID_MISMATCHES, MAX_MISMATCHED_BLOCKS, PacketSender, PacketReceiver, PacketBuffer, InternalClient, Transfer, TransferInfo, TransferProgress, ClientStates,
+                Received, Result, Error, Backoff, MIN_RETRANSMIT_WAIT, ResumableTransfer, WindowedAck, MAX_DATA_SIZE};
+
+    #[derive(Default)]
+    struct MockClient {
+        read_requests: Vec<(String, Mode, Vec<(String, String)>)>,
+        acks: Vec<u16>,
+        errors: Vec<(ErrorCode, String)>,
+        retransmits: usize,
+        blocked_acks: usize,
+        incoming: VecDeque<Option<Received>>,
+    }
+
+    impl MockClient {
+        fn with_blocks(blocks: &[(u16, Vec<u8>)]) -> MockClient {
+            let mut client = MockClient::default();
+            for &(block_id, ref data) in blocks {
+                client.push_block(block_id, data);
+            }
+            client
+        }
+
+        fn push_block(&mut self, block_id: u16, data: &[u8]) {
+            let raw = DataPacketOctet::from_slice(block_id, data).encode();
+            self.incoming.push_back(DecodedPacket::decode(raw).ok().map(Received::Data));
+        }
+
+        fn push_oack(&mut self, options: &[(&str, &str)]) {
+            self.incoming.push_back(Some(Received::Oack(OackPacket::new(options))));
+        }
+    }
+
+    fn options(options: &[(&str, &str)]) -> Vec<(String, String)> {
+        options.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    impl PacketSender for MockClient {
+        fn send_read_request(&mut self, path: &str, mode: Mode, options: &[(String, String)]) -> Result<()> {
+            self.read_requests.push((path.to_string(), mode, options.to_vec()));
+            Ok(())
+        }
+
+        fn send_ack(&mut self, block_id: u16) -> Result<Option<()>> {
+            if self.blocked_acks > 0 {
+                self.blocked_acks -= 1;
+                return Ok(None)
+            }
+            self.acks.push(block_id);
+            Ok(Some(()))
+        }
+
+        fn send_error(&mut self, error: ErrorCode, msg: &str) -> Result<()> {
+            self.errors.push((error, msg.to_string()));
+            Ok(())
+        }
+
+        fn retransmit_last_packet(&mut self) -> Result<()> {
+            self.retransmits += 1;
+            Ok(())
+        }
+    }
+
+    impl PacketReceiver for MockClient {
+        fn receive(&mut self) -> Result<Option<Received>> {
+            Ok(self.incoming.pop_front().and_then(|p| p))
+        }
+
+        fn put_buffer_data(&mut self, _: Vec<u8>) {}
+    }
+
+    #[test]
+    fn read_request_is_sent_before_receiving_first_block() {
+        let mut output = Vec::new();
+        let mut transfer = Transfer::new(MockClient::default(), &mut output);
+
+        let state = ClientStates::SendReadRequest(Path::new("file"), Mode::Octet);
+        assert_eq!(Ready::writable(), state.interest());
+
+        let state = transfer.handle_event(state).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(1)));
+        assert_eq!(Ready::readable(), state.interest());
+        assert_eq!(vec![("file".to_string(), Mode::Octet, vec![])], transfer.client.read_requests);
+    }
+
+    #[test]
+    fn transfer_moves_through_all_blocks_until_done() {
+        let blocks = [(1, vec![1; MAX_DATA_SIZE]), (2, vec![2; MAX_DATA_SIZE]), (3, vec![3; 10])];
+        let mut output = Vec::new();
+        {
+            let mut transfer = Transfer::new(MockClient::with_blocks(&blocks), &mut output);
+
+            let state = ClientStates::SendReadRequest(Path::new("file"), Mode::Octet);
+            let state = transfer.handle_event(state).unwrap();
+            assert!(matches!(state, ClientStates::ReceivingData(1)));
+            let state = transfer.handle_event(state).unwrap();
+            assert!(matches!(state, ClientStates::ReceivingData(2)));
+            let state = transfer.handle_event(state).unwrap();
+            assert!(matches!(state, ClientStates::ReceivingData(3)));
+            let state = transfer.handle_event(state).unwrap();
+            assert!(state.is_done());
+            assert_eq!(vec![1, 2, 3], transfer.client.acks);
+        }
+        let expected: Vec<u8> = blocks.iter().flat_map(|&(_, ref data)| data.clone()).collect();
+        assert_eq!(expected, output);
+    }
+
+    #[test]
+    fn empty_final_block_completes_transfer() {
+        let mut output = Vec::new();
+        let mut transfer = Transfer::new(MockClient::with_blocks(&[(1, vec![])]), &mut output);
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+        assert!(state.is_done());
+        assert_eq!(vec![1], transfer.client.acks);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn transfer_stats_roundtrip_through_json() {
+        let stats = super::TransferStats { bytes: 1300, blocks: 3, retransmits: 2 };
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!("{\"bytes\":1300,\"blocks\":3,\"retransmits\":2}", json);
+        assert_eq!(stats, serde_json::from_str::<super::TransferStats>(&json).unwrap());
+    }
+
+    // Log records of the calling thread, captured by `TraceCapture`.
+    thread_local!(static TRACES: RefCell<Vec<String>> = RefCell::new(Vec::new()));
+
+    struct TraceCapture;
+
+    impl log::Log for TraceCapture {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            TRACES.with(|traces| traces.borrow_mut().push(record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_traces() {
+        static CAPTURE: TraceCapture = TraceCapture;
+        static INSTALL: Once = ONCE_INIT;
+        INSTALL.call_once(|| {
+            log::set_logger(&CAPTURE).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        TRACES.with(|traces| traces.borrow_mut().clear());
+    }
+
+    #[test]
+    fn state_transitions_are_traced() {
+        capture_traces();
+        let mut output = Vec::new();
+        {
+            let mut transfer = Transfer::new(MockClient::with_blocks(&[(1, vec![1; 100])]), &mut output);
+            let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+            assert!(state.is_done());
+        }
+
+        let traces = TRACES.with(|traces| traces.borrow().clone());
+        assert!(traces.contains(&"transition from=SendAck(1) to=Done blocks=1 bytes=100".to_string()),
+                "{:?}", traces);
+        assert!(traces.contains(&"transition from=ReceivingData(1) to=Done blocks=1 bytes=100".to_string()),
+                "{:?}", traces);
+    }
+
+    #[test]
+    fn blocked_ack_is_retried_before_receiving_next_block() {
+        let mut output = Vec::new();
+        let mut client = MockClient::with_blocks(&[(1, vec![1; MAX_DATA_SIZE])]);
+        client.blocked_acks = 1;
+        let mut transfer = Transfer::new(client, &mut output);
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+        assert!(matches!(state, ClientStates::SendAck(_)));
+        assert_eq!(Ready::writable(), state.interest());
+        assert!(transfer.client.acks.is_empty());
+
+        let state = transfer.handle_event(state).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(2)));
+        assert_eq!(vec![1], transfer.client.acks);
+    }
+
+    #[test]
+    fn unexpected_block_is_not_acknowledged() {
+        let mut output = Vec::new();
+        let mut transfer = Transfer::new(MockClient::with_blocks(&[(5, vec![1; 10])]), &mut output);
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(1)));
+        assert!(transfer.client.acks.is_empty());
+    }
+
+    #[test]
+    fn older_block_is_acknowledged_again() {
+        let mut output = Vec::new();
+        {
+            let blocks = [(1, vec![1; MAX_DATA_SIZE]), (1, vec![1; MAX_DATA_SIZE]), (2, vec![2; 10])];
+            let mut transfer = Transfer::new(MockClient::with_blocks(&blocks), &mut output);
+
+            let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+            let state = transfer.handle_event(state).unwrap();
+            assert!(matches!(state, ClientStates::ReceivingData(2)));
+            assert_eq!(vec![1, 1], transfer.client.acks);
+            assert!(transfer.handle_event(state).unwrap().is_done());
+            assert_eq!(vec![1, 1, 2], transfer.client.acks);
+        }
+        assert_eq!(MAX_DATA_SIZE + 10, output.len());
+    }
+
+    #[test]
+    fn block_sent_again_is_passed_to_the_callback_once() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut output = Vec::new();
+        {
+            let blocks = [(1, vec![1; MAX_DATA_SIZE]), (1, vec![1; MAX_DATA_SIZE]), (2, vec![2; 10])];
+            let callback_received = received.clone();
+            let mut transfer = Transfer::new(MockClient::with_blocks(&blocks), &mut output)
+                .with_block_callback(Some(Box::new(move |block_id, data: &[u8]| {
+                    callback_received.lock().unwrap().push((block_id, data.to_vec()));
+                })));
+
+            let mut state = ClientStates::ReceivingData(1);
+            while !state.is_done() {
+                state = transfer.handle_event(state).unwrap();
+            }
+        }
+        assert_eq!(vec![(1, vec![1; MAX_DATA_SIZE]), (2, vec![2; 10])], *received.lock().unwrap());
+    }
+
+    #[test]
+    fn burst_of_copies_of_an_older_block_is_acknowledged_once() {
+        let mut output = Vec::new();
+        let mut blocks = vec![(1, vec![1; MAX_DATA_SIZE]); 4];
+        blocks.push((2, vec![2; MAX_DATA_SIZE]));
+        blocks.push((2, vec![2; MAX_DATA_SIZE]));
+        let mut transfer = Transfer::new(MockClient::with_blocks(&blocks), &mut output);
+
+        let mut state = ClientStates::ReceivingData(1);
+        for _ in 0..4 {
+            state = transfer.handle_event(state).unwrap();
+        }
+        assert!(matches!(state, ClientStates::ReceivingData(2)));
+        assert_eq!(vec![1, 1], transfer.client.acks);
+
+        // Block 2 moves the transfer on, its own copy is answered again.
+        let state = transfer.handle_event(state).unwrap();
+        let state = transfer.handle_event(state).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(3)));
+        assert_eq!(vec![1, 1, 2, 2], transfer.client.acks);
+    }
+
+    #[test]
+    fn copy_of_an_older_block_is_acknowledged_again_after_a_timeout() {
+        let mut output = Vec::new();
+        let blocks = vec![(1, vec![1; MAX_DATA_SIZE]); 3];
+        let mut transfer = Transfer::new(MockClient::with_blocks(&blocks), &mut output);
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+        let state = transfer.handle_event(state).unwrap();
+        assert_eq!(vec![1, 1], transfer.client.acks);
+        let state = transfer.handle_timeout(state).unwrap();
+        transfer.handle_event(state).unwrap();
+        assert_eq!(vec![1, 1, 1], transfer.client.acks);
+    }
+
+    #[test]
+    fn future_block_is_ignored() {
+        let mut output = Vec::new();
+        {
+            let blocks = [(3, vec![3; 10]), (2, vec![2; 10])];
+            let mut transfer = Transfer::new(MockClient::with_blocks(&blocks), &mut output);
+
+            let state = transfer.handle_event(ClientStates::ReceivingData(2)).unwrap();
+            assert!(matches!(state, ClientStates::ReceivingData(2)));
+            assert!(transfer.client.acks.is_empty());
+            assert!(transfer.handle_event(state).unwrap().is_done());
+            assert_eq!(vec![2], transfer.client.acks);
+        }
+        assert_eq!(vec![2; 10], output);
+    }
+
+    #[test]
+    fn next_block_id_wraps_to_rollover() {
+        assert_eq!(2, super::next_block_id(1, None));
+        assert_eq!(0, super::next_block_id(65535, None));
+        assert_eq!(0, super::next_block_id(65535, Some(0)));
+        assert_eq!(1, super::next_block_id(65535, Some(1)));
+    }
+
+    #[test]
+    fn block_ids_wrap_around_when_comparing_age() {
+        assert!(super::is_older_block(65535, 0));
+        assert!(super::is_older_block(4, 5));
+        assert!(!super::is_older_block(5, 5));
+        assert!(!super::is_older_block(0, 65535));
+    }
+
+    #[test]
+    fn waiting_for_data_without_packet_keeps_state() {
+        let mut output = Vec::new();
+        let mut transfer = Transfer::new(MockClient::default(), &mut output);
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(3)).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(3)));
+    }
+
+    #[test]
+    fn option_acknowledgment_is_acknowledged_with_block_zero() {
+        let mut output = Vec::new();
+        let mut client = MockClient::default();
+        client.push_oack(&[("blksize", "1024"), ("tsize", "1034")]);
+        client.push_block(1, &[1; 1024]);
+        client.push_block(2, &[2; 10]);
+        let mut transfer = Transfer::new(client, &mut output)
+            .with_options(options(&[("blksize", "1024"), ("tsize", "0")]));
+
+        let state = ClientStates::SendReadRequest(Path::new("file"), Mode::Octet);
+        let state = transfer.handle_event(state).unwrap();
+        assert_eq!(options(&[("blksize", "1024"), ("tsize", "0")]), transfer.client.read_requests[0].2);
+
+        let state = transfer.handle_event(state).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(1)));
+        assert_eq!(vec![0], transfer.client.acks);
+        assert_eq!(TransferInfo { block_size: 1024, tsize: Some(1034), ..TransferInfo::default() }, transfer.info);
+
+        // A full block of the negotiated size does not end the transfer.
+        let state = transfer.handle_event(state).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(2)));
+        let state = transfer.handle_event(state).unwrap();
+        assert!(state.is_done());
+        assert_eq!(vec![0, 1, 2], transfer.client.acks);
+    }
+
+    #[test]
+    fn acknowledged_size_is_reported_before_the_first_block() {
+        let mut output = Vec::new();
+        let mut client = MockClient::default();
+        client.push_oack(&[("tsize", "10")]);
+        client.push_block(1, &[1; 10]);
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        let reported = sizes.clone();
+        let mut transfer = Transfer::new(client, &mut output).with_options(options(&[("tsize", "0")]));
+        transfer.on_size = Some(Box::new(move |size| reported.lock().unwrap().push(size)));
+
+        let state = transfer.handle_event(ClientStates::SendReadRequest(Path::new("file"), Mode::Octet)).unwrap();
+        let state = transfer.handle_event(state).unwrap();
+        assert_eq!(vec![10], *sizes.lock().unwrap());
+        assert!(transfer.handle_event(state).unwrap().is_done());
+        assert_eq!(vec![10], *sizes.lock().unwrap());
+    }
+
+    #[test]
+    fn preallocation_is_capped() {
+        assert_eq!(1024, preallocated_size(1024));
+        assert_eq!(MAX_PREALLOCATED_SIZE as usize, preallocated_size(u64::max_value()));
+    }
+
+    /// Receives `blocks` after negotiating a block size of 1024, returns the finished transfer.
+    fn receive_with_block_size_1024<'a>(blocks: &[(u16, Vec<u8>)], output: &'a mut Vec<u8>)
+                                        -> Transfer<MockClient, &'a mut Vec<u8>> {
+        let mut client = MockClient::default();
+        client.push_oack(&[("blksize", "1024")]);
+        for &(block_id, ref data) in blocks {
+            client.push_block(block_id, data);
+        }
+        let mut transfer = Transfer::new(client, output).with_options(options(&[("blksize", "1024")]));
+        let mut state = ClientStates::ReceivingData(1);
+        while !state.is_done() {
+            state = transfer.handle_event(state).unwrap();
+        }
+        transfer
+    }
+
+    #[test]
+    fn short_block_ends_transfer_with_negotiated_block_size() {
+        let mut output = Vec::new();
+        let blocks = [(1, vec![1; 1024]), (2, vec![2; 1024]), (3, vec![3; 300])];
+        assert_eq!(vec![0, 1, 2, 3], receive_with_block_size_1024(&blocks, &mut output).client.acks);
+        assert_eq!(2 * 1024 + 300, output.len());
+    }
+
+    #[test]
+    fn block_larger_than_the_negotiated_size_is_rejected() {
+        let mut client = MockClient::default();
+        client.push_oack(&[("blksize", "1024")]);
+        client.push_block(1, &[1; 1025]);
+        let mut output = Vec::new();
+        let mut transfer = Transfer::new(client, &mut output).with_options(options(&[("blksize", "1024")]));
+
+        let mut state = ClientStates::ReceivingData(1);
+        let result = loop {
+            match transfer.handle_event(state) {
+                Ok(next) => state = next,
+                Err(err) => break err,
+            }
+        };
+        expect_violation::<()>(Err(result), "block 1 has 1025 bytes, more than the block size of 1024");
+        assert_eq!(vec![(ErrorCode::IllegalOperation, "Block larger than the block size".to_string())],
+                   transfer.client.errors);
+        assert_eq!(0, transfer.written);
+    }
+
+    #[test]
+    fn block_of_default_size_ends_transfer_with_larger_negotiated_block_size() {
+        let mut output = Vec::new();
+        let blocks = [(1, vec![1; 1024]), (2, vec![2; MAX_DATA_SIZE])];
+        assert_eq!(vec![0, 1, 2], receive_with_block_size_1024(&blocks, &mut output).client.acks);
+        assert_eq!(1024 + MAX_DATA_SIZE, output.len());
+    }
+
+    #[test]
+    fn server_ignoring_options_transfers_default_blocks() {
+        let mut output = Vec::new();
+        let client = MockClient::with_blocks(&[(1, vec![1; MAX_DATA_SIZE]), (2, vec![2; 10])]);
+        let mut transfer = Transfer::new(client, &mut output).with_options(options(&[("blksize", "1024")]));
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(2)));
+        let state = transfer.handle_event(state).unwrap();
+        assert!(state.is_done());
+        assert_eq!(TransferInfo::default(), transfer.info);
+    }
+
+    #[test]
+    fn option_acknowledgment_without_requested_options_is_ignored() {
+        let mut output = Vec::new();
+        let mut client = MockClient::default();
+        client.push_oack(&[("blksize", "1024")]);
+        let mut transfer = Transfer::new(client, &mut output);
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(1)));
+        assert!(transfer.client.acks.is_empty());
+    }
+
+    #[test]
+    fn larger_block_size_than_requested_is_rejected() {
+        let mut output = Vec::new();
+        let mut client = MockClient::default();
+        client.push_oack(&[("blksize", "2048")]);
+        let mut transfer = Transfer::new(client, &mut output).with_options(options(&[("blksize", "1024")]));
+
+        let result = transfer.handle_event(ClientStates::ReceivingData(1));
+        assert!(matches!(result, Err(Error::ProtocolViolation(_))));
+    }
+
+    #[test]
+    fn rollover_is_negotiated() {
+        let requested = options(&[("rollover", "0")]);
+        let info = TransferInfo::negotiated(&requested, &OackPacket::new(&[("rollover", "1")])).unwrap();
+        assert_eq!(Some(1), info.rollover);
+        assert!(TransferInfo::negotiated(&requested, &OackPacket::new(&[("rollover", "2")])).is_err());
+    }
+
+    #[test]
+    fn window_size_is_negotiated() {
+        let requested = options(&[("windowsize", "8")]);
+        let info = TransferInfo::negotiated(&requested, &OackPacket::new(&[("windowsize", "4")])).unwrap();
+        assert_eq!(4, info.window_size);
+        assert!(TransferInfo::negotiated(&requested, &OackPacket::new(&[("windowsize", "16")])).is_err());
+        assert!(TransferInfo::negotiated(&requested, &OackPacket::new(&[("windowsize", "0")])).is_err());
+    }
+
+    #[test]
+    fn data_in_place_of_an_option_acknowledgment_falls_back_to_the_defaults() {
+        let mut output = Vec::new();
+        let blocks = [(1, vec![1; MAX_DATA_SIZE]), (2, vec![2; 10])];
+        let requested = options(&[("blksize", "1024"), ("windowsize", "4"), ("tsize", "0")]);
+        {
+            let mut transfer = Transfer::new(MockClient::with_blocks(&blocks), &mut output).with_options(requested);
+            let mut state = ClientStates::ReceivingData(1);
+            while !state.is_done() {
+                state = transfer.handle_event(state).unwrap();
+            }
+            assert_eq!(TransferInfo::default(), transfer.info);
+            // Every block is acknowledged, there is no window.
+            assert_eq!(vec![1, 2], transfer.client.acks);
+        }
+        assert_eq!(MAX_DATA_SIZE + 10, output.len());
+    }
+
+    #[test]
+    fn window_is_acknowledged_up_to_the_last_contiguous_block() {
+        let mut window = WindowedAck::new(4, 1);
+        window.push(3);
+        assert_eq!(None, window.cumulative_ack());
+        window.push(1);
+        assert_eq!(None, window.cumulative_ack());
+        window.push(2);
+        assert_eq!(Some(3), window.cumulative_ack());
+        assert!(!window.is_window_complete());
+        window.push(4);
+        assert!(window.is_window_complete());
+        assert_eq!(Some(4), window.cumulative_ack());
+    }
+
+    #[test]
+    fn window_keeps_its_order_across_a_block_id_wraparound() {
+        let mut window = WindowedAck::new(4, 65534);
+        window.push(0);
+        window.push(65535);
+        assert_eq!(None, window.cumulative_ack());
+        window.push(65534);
+        assert_eq!(Some(0), window.cumulative_ack());
+        // Blocks of the next window are not part of this one.
+        window.push(2);
+        assert!(!window.is_window_complete());
+        window.restart(1);
+        assert_eq!(None, window.cumulative_ack());
+    }
+
+    /// Receives `blocks` after negotiating a window size of 4, returns the finished transfer.
+    fn receive_with_window_size_4<'a>(blocks: &[(u16, Vec<u8>)], output: &'a mut Vec<u8>)
+                                      -> Transfer<MockClient, &'a mut Vec<u8>> {
+        let mut client = MockClient::default();
+        client.push_oack(&[("windowsize", "4")]);
+        for &(block_id, ref data) in blocks {
+            client.push_block(block_id, data);
+        }
+        let mut transfer = Transfer::new(client, output).with_options(options(&[("windowsize", "4")]));
+        let mut state = ClientStates::ReceivingData(1);
+        while !state.is_done() {
+            state = transfer.handle_event(state).unwrap();
+        }
+        transfer
+    }
+
+    #[test]
+    fn only_the_last_block_of_a_window_is_acknowledged() {
+        let mut output = Vec::new();
+        let mut blocks: Vec<(u16, Vec<u8>)> = (1..10).map(|id| (id, vec![id as u8; MAX_DATA_SIZE])).collect();
+        blocks.push((10, vec![10; 10]));
+        assert_eq!(vec![0, 4, 8, 10], receive_with_window_size_4(&blocks, &mut output).client.acks);
+        assert_eq!(9 * MAX_DATA_SIZE + 10, output.len());
+    }
+
+    #[test]
+    fn lost_block_in_a_window_acknowledges_the_last_block_in_order() {
+        let mut output = Vec::new();
+        // Block 2 is lost, the server sends the window again from block 2.
+        let blocks: Vec<(u16, Vec<u8>)> = [1, 3, 4, 2, 3, 4].iter()
+            .map(|&id| (id, vec![id as u8; MAX_DATA_SIZE]))
+            .chain(Some((5, vec![5; 10])))
+            .collect();
+        assert_eq!(vec![0, 1, 5], receive_with_window_size_4(&blocks, &mut output).client.acks);
+        let mut expected = Vec::new();
+        for id in 1..5 {
+            expected.extend_from_slice(&[id; MAX_DATA_SIZE]);
+        }
+        expected.extend_from_slice(&[5; 10]);
+        assert_eq!(expected, output);
+    }
+
+    #[test]
+    fn block_ids_roll_over_to_zero_by_default() {
+        let mut output = Vec::new();
+        let mut client = MockClient::default();
+        client.push_block(65535, &[1; MAX_DATA_SIZE]);
+        client.push_block(0, &[2; 10]);
+        let mut transfer = Transfer::new(client, &mut output);
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(65535)).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(0)));
+        assert!(transfer.handle_event(state).unwrap().is_done());
+        assert_eq!(vec![65535, 0], transfer.client.acks);
+    }
+
+    #[test]
+    fn block_ids_roll_over_to_the_negotiated_block() {
+        let mut output = Vec::new();
+        let mut client = MockClient::default();
+        client.push_oack(&[("rollover", "1")]);
+        client.push_block(65535, &[1; MAX_DATA_SIZE]);
+        client.push_block(1, &[2; 10]);
+        let mut transfer = Transfer::new(client, &mut output).with_options(options(&[("rollover", "1")]));
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(1)));
+        assert_eq!(Some(1), transfer.info.rollover);
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(65535)).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(1)));
+        assert!(transfer.handle_event(state).unwrap().is_done());
+        assert_eq!(vec![0, 65535, 1], transfer.client.acks);
+    }
+
+    #[test]
+    fn missing_final_empty_block_ends_the_transfer_at_the_reported_size() {
+        let mut output = Vec::new();
+        {
+            let mut client = MockClient::default();
+            client.push_oack(&[("tsize", "1024")]);
+            client.push_block(1, &[1; MAX_DATA_SIZE]);
+            client.push_block(2, &[2; MAX_DATA_SIZE]);
+            let mut transfer = Transfer::new(client, &mut output).with_options(options(&[("tsize", "0")]));
+
+            let mut state = ClientStates::ReceivingData(1);
+            for _ in 0..3 {
+                state = transfer.handle_event(state).unwrap();
+            }
+            assert!(matches!(state, ClientStates::ReceivingData(3)));
+            assert!(transfer.handle_timeout(state).unwrap().is_done());
+            assert_eq!(0, transfer.retransmits);
+        }
+        assert_eq!(2 * MAX_DATA_SIZE, output.len());
+    }
+
+    #[test]
+    fn missing_final_empty_block_is_waited_for_without_transfer_size() {
+        let mut output = Vec::new();
+        let mut transfer = Transfer::new(MockClient::with_blocks(&[(1, vec![1; MAX_DATA_SIZE])]), &mut output);
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(2)));
+        let state = transfer.handle_timeout(state).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(2)));
+        assert_eq!(1, transfer.retransmits);
+    }
+
+    #[test]
+    fn unrequested_option_is_rejected() {
+        let mut output = Vec::new();
+        let mut client = MockClient::default();
+        client.push_oack(&[("tsize", "10")]);
+        let mut transfer = Transfer::new(client, &mut output).with_options(options(&[("blksize", "1024")]));
+
+        let result = transfer.handle_event(ClientStates::ReceivingData(1));
+        assert!(matches!(result, Err(Error::ProtocolViolation(_))));
+    }
+
+    #[test]
+    fn duplicate_option_acknowledgment_is_acknowledged_again() {
+        let mut output = Vec::new();
+        let mut client = MockClient::default();
+        client.push_oack(&[("blksize", "1024")]);
+        client.push_oack(&[("blksize", "1024")]);
+        client.push_block(1, &[1; 10]);
+        let mut transfer = Transfer::new(client, &mut output).with_options(options(&[("blksize", "1024")]));
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(1)));
+        let state = transfer.handle_event(state).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(1)));
+        assert_eq!(vec![0, 0], transfer.client.acks);
+
+        let state = transfer.handle_event(state).unwrap();
+        assert!(state.is_done());
+        assert_eq!(vec![0, 0, 1], transfer.client.acks);
+    }
+
+    #[test]
+    fn option_acknowledgment_after_first_block_is_ignored() {
+        let mut output = Vec::new();
+        let mut client = MockClient::default();
+        client.push_oack(&[("blksize", "1024")]);
+        let mut transfer = Transfer::new(client, &mut output).with_options(options(&[("blksize", "1024")]));
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(2)).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(2)));
+        assert!(transfer.client.acks.is_empty());
+    }
+
+    #[test]
+    fn filename_with_nul_byte_is_not_requested() {
+        let mut output = Vec::new();
+        let mut transfer = Transfer::new(MockClient::default(), &mut output);
+
+        let result = transfer.handle_event(ClientStates::SendReadRequest(Path::new("fi\0le"), Mode::Octet));
+        assert!(matches!(result, Err(Error::InvalidFilename(_))));
+        assert!(transfer.client.read_requests.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn filename_that_is_not_utf8_is_not_requested() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut output = Vec::new();
+        let mut transfer = Transfer::new(MockClient::default(), &mut output);
+
+        let path = Path::new(OsStr::from_bytes(b"fi\xffle"));
+        let result = transfer.handle_event(ClientStates::SendReadRequest(path, Mode::Octet));
+        assert!(matches!(result, Err(Error::InvalidFilename("filename is not valid UTF-8"))));
+        assert!(transfer.client.read_requests.is_empty());
+    }
+
+    #[test]
+    fn restored_packet_buffer_is_taken_again() {
+        let mut buffer = PacketBuffer::new(4);
+        let mut buf = buffer.take();
+        buf[0] = 1;
+        buffer.restore(buf);
+        assert_eq!(vec![1, 0, 0, 0], buffer.take());
+    }
+
+    #[test]
+    #[should_panic(expected = "packet buffer is already taken")]
+    fn taking_packet_buffer_twice_panics() {
+        let mut buffer = PacketBuffer::new(4);
+        buffer.take();
+        buffer.take();
+    }
+
+    #[test]
+    #[should_panic(expected = "packet buffer is already present")]
+    fn restoring_present_packet_buffer_panics() {
+        let mut buffer = PacketBuffer::new(4);
+        buffer.restore(vec![0; 4]);
+    }
+
+    #[test]
+    fn transfer_over_max_file_size_is_aborted() {
+        let blocks = [(1, vec![1; MAX_DATA_SIZE]), (2, vec![2; 10])];
+        let mut output = Vec::new();
+        {
+            let mut transfer = Transfer::new(MockClient::with_blocks(&blocks), &mut output)
+                .with_max_file_size(Some(MAX_DATA_SIZE as u64 + 5));
+
+            let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+            assert!(matches!(state, ClientStates::ReceivingData(2)));
+            let result = transfer.handle_event(state);
+            assert!(matches!(result, Err(Error::FileTooLarge(522))));
+            assert_eq!(vec![(ErrorCode::DiskFull, "File too large".to_string())], transfer.client.errors);
+            assert_eq!(vec![1], transfer.client.acks);
+        }
+        assert_eq!(vec![1; MAX_DATA_SIZE], output);
+    }
+
+    #[test]
+    fn transfer_of_exactly_max_file_size_completes() {
+        let mut output = Vec::new();
+        let mut transfer = Transfer::new(MockClient::with_blocks(&[(1, vec![1; 10])]), &mut output)
+            .with_max_file_size(Some(10));
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+        assert!(state.is_done());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let backoff = Backoff::default();
+        let timeouts: Vec<u64> = (0..6).map(|count| backoff.timeout(count).as_secs()).collect();
+        assert_eq!(vec![1, 2, 4, 8, 8, 8], timeouts);
+    }
+
+    #[test]
+    fn backoff_with_multiplier_of_one_is_constant() {
+        let backoff = Backoff {
+            base: Duration::from_millis(300),
+            multiplier: 1,
+            max: Duration::from_secs(1),
+        };
+        assert_eq!(Duration::from_millis(300), backoff.timeout(10));
+    }
+
+    #[test]
+    fn backoff_growing_past_the_largest_duration_stops_at_max() {
+        let backoff = Backoff {
+            base: Duration::from_secs(u64::max_value() / 2),
+            multiplier: 4,
+            max: Duration::from_secs(u64::max_value()),
+        };
+        assert_eq!(backoff.max, backoff.timeout(1));
+    }
+
+    #[test]
+    fn backoff_never_waits_zero() {
+        let backoff = Backoff { base: Duration::from_secs(0), multiplier: 0, ..Backoff::default() }.normalized();
+        assert_eq!(MIN_RETRANSMIT_WAIT, backoff.timeout(0));
+        assert_eq!(MIN_RETRANSMIT_WAIT, backoff.timeout(3));
+
+        let backoff = Backoff { multiplier: 0, ..Backoff::default() }.normalized();
+        assert_eq!(Duration::from_secs(1), backoff.timeout(3));
+    }
+
+    #[test]
+    fn client_config_backoff_is_normalized() {
+        let config = ClientConfig {
+            retransmit: Backoff { base: Duration::from_secs(0), multiplier: 0, ..Backoff::default() },
+            ..ClientConfig::default()
+        };
+        let client = Client::new("127.0.0.1:69".parse().unwrap()).unwrap().with_config(config);
+        assert_eq!(MIN_RETRANSMIT_WAIT, client.config.retransmit.base);
+        assert_eq!(1, client.config.retransmit.multiplier);
+    }
+
+    #[test]
+    fn timeout_retransmits_until_max_retries() {
+        let mut output = Vec::new();
+        let mut transfer = Transfer::new(MockClient::default(), &mut output)
+            .with_retransmit(Backoff::default(), 2);
+
+        let state = transfer.handle_timeout(ClientStates::ReceivingData(1)).unwrap();
+        assert_eq!(Duration::from_secs(2), transfer.retransmit_timeout());
+        let state = transfer.handle_timeout(state).unwrap();
+        assert_eq!(2, transfer.client.retransmits);
+        assert!(matches!(transfer.handle_timeout(state), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn received_block_restarts_retransmission_schedule() {
+        let mut output = Vec::new();
+        let mut transfer = Transfer::new(MockClient::with_blocks(&[(1, vec![1; MAX_DATA_SIZE])]), &mut output);
+
+        let state = transfer.handle_timeout(ClientStates::ReceivingData(1)).unwrap();
+        assert_eq!(1, transfer.retransmit_count);
+        let state = transfer.handle_event(state).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(2)));
+        assert_eq!(0, transfer.retransmit_count);
+    }
+
+    /// Binds a server socket and a client sending to it.
+    fn connected_client() -> (net::UdpSocket, InternalClient) {
+        let server = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = InternalClient::new(socket, server.local_addr().unwrap(), MAX_DATA_SIZE);
+        (server, client)
+    }
+
+    fn recv(server: &net::UdpSocket) -> Vec<u8> {
+        let mut buf = vec![0; 1024];
+        let (n, _) = server.recv_from(&mut buf).unwrap();
+        buf.truncate(n);
+        buf
+    }
+
+    #[test]
+    fn socket_buffer_sizes_are_set() {
+        let (_server, client) = connected_client();
+        client.set_socket_buffer_sizes(Some(64 * 1024), Some(256 * 1024)).unwrap();
+        let socket = SockRef::from(&client.socket);
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.recv_buffer_size().unwrap() >= 256 * 1024);
+
+        let recv_size = socket.recv_buffer_size().unwrap();
+        client.set_socket_buffer_sizes(Some(1 << 30), None).unwrap();
+        assert_eq!(recv_size, socket.recv_buffer_size().unwrap());
+    }
+
+    #[test]
+    fn file_is_downloaded_with_larger_socket_buffers() {
+        let server = TestServer::new();
+        server.create_file("file.bin", &pattern(3 * 512 + 10));
+        let config = ClientConfig {
+            socket_send_buf: Some(64 * 1024),
+            socket_recv_buf: Some(1 << 30),
+            ..ClientConfig::default()
+        };
+
+        let mut output = Vec::new();
+        Client::new(server.addr()).unwrap().with_config(config)
+            .get(Path::new("file.bin"), Mode::Octet, &mut output).unwrap();
+        assert_eq!(pattern(3 * 512 + 10), output);
+    }
+
+    #[test]
+    fn retransmitted_read_request_uses_cached_bytes() {
+        let (server, mut client) = connected_client();
+        let options = vec![("blksize".to_string(), "1024".to_string())];
+        client.send_read_request("file.bin", Mode::Octet, &options).unwrap();
+        let sent = recv(&server);
+
+        client.retransmit_last_packet().unwrap();
+        assert_eq!(sent, recv(&server));
+        assert_eq!(&sent[..], &client.last_sent[..]);
+        let encoded = RequestPacket::read_request_with_options("file.bin", Mode::Octet, &options).encode();
+        assert_eq!(encoded.packet_buf(), &sent[..]);
+    }
+
+    #[test]
+    fn retransmitted_ack_goes_to_the_address_it_was_sent_to() {
+        let (server, mut client) = connected_client();
+        client.send_ack(3).unwrap();
+        assert_eq!(vec![0, 4, 0, 3], recv(&server));
+
+        // The server changed its port in the meantime.
+        client.remote_addr = "127.0.0.1:9".parse().unwrap();
+        client.retransmit_last_packet().unwrap();
+        assert_eq!(vec![0, 4, 0, 3], recv(&server));
+    }
+
+    #[test]
+    fn retransmit_without_packet_sent_does_nothing() {
+        let (server, mut client) = connected_client();
+        client.retransmit_last_packet().unwrap();
+        server.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        assert!(server.recv_from(&mut [0; 16]).is_err());
+    }
+
+    #[test]
+    fn probe_reports_the_file_size() {
+        let server = TestServer::new();
+        server.create_file("file.bin", &[7; 1500]);
+
+        assert_eq!(Some(1500), Client::probe(server.addr(), "file.bin").unwrap());
+        // The server treats the abort as the end of the transfer.
+        server.wait_for_active_clients(0);
+    }
+
+    #[test]
+    fn probe_of_a_missing_file_is_a_server_error() {
+        let server = TestServer::new();
+
+        match Client::probe(server.addr(), "missing.bin") {
+            Err(Error::Server(err)) => assert_eq!(ErrorCode::FileNotFound, err.error()),
+            other => panic!("expected a server error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn existing_file_is_found_without_downloading_it() {
+        let server = TestServer::new();
+        server.create_file("file.bin", &[7; 5000]);
+
+        assert!(Client::exists(server.addr(), Path::new("file.bin")).unwrap());
+        // The server treats the abort as the end of the transfer.
+        server.wait_for_active_clients(0);
+    }
+
+    #[test]
+    fn missing_file_does_not_exist() {
+        let server = TestServer::new();
+        assert!(!Client::exists(server.addr(), Path::new("missing.bin")).unwrap());
+    }
+
+    #[test]
+    fn other_server_errors_fail_the_existence_check() {
+        let server = TestServer::new();
+
+        match Client::exists(server.addr(), Path::new("../outside.bin")) {
+            Err(Error::Server(err)) => assert_eq!(ErrorCode::AccessViolation, err.error()),
+            other => panic!("expected a server error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_data_packet_is_a_protocol_error() {
+        let (server, mut client) = connected_client();
+        let client_addr = client.socket.local_addr().unwrap();
+        server.send_to(&[0, 3, 0], client_addr).unwrap();
+        server.send_to(&[0, 3, 0, 1, 9], client_addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        match client.receive() {
+            Err(Error::Protocol(DecodeError::TooShort)) => {}
+            other => panic!("expected a protocol error, got {:?}", other.map(|_| ())),
+        }
+        // The receive buffer is kept, later packets are still received.
+        match client.receive().unwrap() {
+            Some(Received::Data(data_packet)) => assert_eq!(&[9], data_packet.data()),
+            _ => panic!("expected a data packet"),
+        }
+    }
+
+    fn expect_violation<T>(result: Result<T>, expected: &str) {
+        match result {
+            Err(Error::ProtocolViolation(ref description)) if description == expected => {}
+            Err(err) => panic!("expected protocol violation {:?}, got {:?}", expected, err),
+            Ok(_) => panic!("expected protocol violation {:?}", expected),
+        }
+    }
+
+    #[test]
+    fn packets_from_another_port_violate_the_protocol_when_repeated() {
+        let (server, mut client) = connected_client();
+        let client_addr = client.socket.local_addr().unwrap();
+        server.send_to(&[0, 3, 0, 1], client_addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let data_packet = match client.receive().unwrap() {
+            Some(Received::Data(data_packet)) => data_packet,
+            _ => panic!("expected a data packet"),
+        };
+        client.put_buffer_data(data_packet.into_inner());
+
+        let stray = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        stray.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for _ in 0..MAX_TID_MISMATCHES {
+            stray.send_to(&[0, 3, 0, 2], client_addr).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            assert!(client.receive().unwrap().is_none());
+
+            let error = ErrorPacket::decode(&recv(&stray)).map(|error| error.error());
+            assert_eq!(Ok(ErrorCode::UnknownTransferId), error);
+        }
+        stray.send_to(&[0, 3, 0, 2], client_addr).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        let expected = format!("TID mismatch: expected port {}, got {}",
+                               server.local_addr().unwrap().port(), stray.local_addr().unwrap().port());
+        expect_violation(client.receive(), &expected);
+    }
+
+    #[test]
+    fn unexpected_opcode_violates_the_protocol() {
+        let (server, mut client) = connected_client();
+        let client_addr = client.socket.local_addr().unwrap();
+        server.send_to(&[0, 4, 0, 1], client_addr).unwrap();
+        server.send_to(&[0, 99, 0, 1], client_addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        expect_violation(client.receive(), "unexpected ACK packet during a download");
+        expect_violation(client.receive(), "unknown opcode 99");
+    }
+
+    #[test]
+    fn too_many_mismatched_blocks_violate_the_protocol() {
+        let mut output = Vec::new();
+        let mut client = MockClient::default();
+        for _ in 0..MAX_MISMATCHED_BLOCKS + 1 {
+            client.push_block(7, &[1; 10]);
+        }
+        let mut transfer = Transfer::new(client, &mut output);
+
+        let mut state = ClientStates::ReceivingData(3);
+        for _ in 0..MAX_MISMATCHED_BLOCKS {
+            state = transfer.handle_event(state).unwrap();
+        }
+        expect_violation(transfer.handle_event(state),
+                         "block id mismatch: expected 3, got 7 after 8 unexpected blocks");
+    }
+
+    /// Records the writes it gets, and fails to flush if `fail_flush` is set.
+    #[derive(Default)]
+    struct RecordingWriter {
+        writes: Vec<usize>,
+        fail_flush: bool,
+    }
+
+    impl io::Write for RecordingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.push(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            if self.fail_flush {
+                return Err(io::Error::new(io::ErrorKind::Other, "disk gone"))
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn blocks_are_written_in_one_write() {
+        let mut writer = RecordingWriter::default();
+        let mut blocks: Vec<(u16, Vec<u8>)> = (1..6).map(|id| (id, vec![id as u8; MAX_DATA_SIZE])).collect();
+        blocks.push((6, vec![6; 10]));
+        {
+            let mut transfer = Transfer::new(MockClient::with_blocks(&blocks), &mut writer);
+            let mut state = ClientStates::ReceivingData(1);
+            while !state.is_done() {
+                state = transfer.handle_event(state).unwrap();
+            }
+        }
+        assert_eq!(vec![5 * MAX_DATA_SIZE + 10], writer.writes);
+    }
+
+    #[test]
+    fn failed_flush_fails_the_transfer() {
+        let mut writer = RecordingWriter { fail_flush: true, ..RecordingWriter::default() };
+        let mut transfer = Transfer::new(MockClient::with_blocks(&[(1, vec![1; 10])]), &mut writer);
+        assert!(matches!(transfer.handle_event(ClientStates::ReceivingData(1)), Err(Error::Io(_))));
+        assert_eq!(vec![(ErrorCode::DiskFull, "Could not write the file".to_string())], transfer.client.errors);
+    }
+
+    /// Accepts `remaining` bytes, then fails every write.
+    struct FailingWriter {
+        remaining: usize,
+    }
+
+    impl io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "pipe closed"))
+            }
+            let n = cmp::min(self.remaining, buf.len());
+            self.remaining -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_write_is_reported_to_the_server() {
+        let blocks: Vec<(u16, Vec<u8>)> = (1..41).map(|id| (id, vec![id as u8; MAX_DATA_SIZE])).collect();
+        let mut transfer = Transfer::new(MockClient::with_blocks(&blocks), FailingWriter { remaining: 10000 });
+        let mut state = ClientStates::ReceivingData(1);
+        let err = loop {
+            match transfer.handle_event(state) {
+                Ok(next) => state = next,
+                Err(err) => break err,
+            }
+        };
+        match err {
+            Error::Io(ref err) => assert_eq!(io::ErrorKind::BrokenPipe, err.kind()),
+            ref err => panic!("unexpected error {:?}", err),
+        }
+        assert_eq!(vec![(ErrorCode::Undefined, "Output closed".to_string())], transfer.client.errors);
+        assert!(transfer.blocks < 40);
+    }
+
+    fn checkpoint(dir: &Path, block_id: u16, byte_offset: u64) -> ResumableTransfer {
+        ResumableTransfer {
+            block_id: block_id,
+            byte_offset: byte_offset,
+            ..ResumableTransfer::new("file.bin", &dir.join("file.bin"))
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn checkpoint_is_saved_after_every_block() {
+        let dir = temp_dir("checkpoint");
+        let mut output = Vec::new();
+        let blocks: Vec<(u16, Vec<u8>)> = (1..6).map(|id| (id, vec![id as u8; MAX_DATA_SIZE])).collect();
+        let mut transfer = Transfer::new(MockClient::with_blocks(&blocks), &mut output)
+            .with_retransmit(Backoff::default(), 0)
+            .with_checkpoint(Some(checkpoint(&dir, 1, 0)));
+
+        let mut state = ClientStates::ReceivingData(1);
+        for _ in 0..5 {
+            state = transfer.handle_event(state).unwrap();
+        }
+        assert!(matches!(state, ClientStates::ReceivingData(6)));
+        assert!(matches!(transfer.handle_timeout(state), Err(Error::Timeout)));
+
+        assert_eq!(checkpoint(&dir, 6, 5 * MAX_DATA_SIZE as u64),
+                   ResumableTransfer::load(&dir.join("file.bin")).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn resumed_transfer_acknowledges_the_block_before_the_resume_point() {
+        let dir = temp_dir("resume");
+        let mut output = Vec::new();
+        let mut client = MockClient::default();
+        client.push_block(1, &[1; MAX_DATA_SIZE]);
+        client.push_block(6, &[6; MAX_DATA_SIZE]);
+        client.push_block(7, &[7; 10]);
+        let mut transfer = Transfer::new(client, &mut output)
+            .with_checkpoint(Some(checkpoint(&dir, 6, 5 * MAX_DATA_SIZE as u64)));
+
+        let mut state = ClientStates::ReceivingData(1);
+        while !state.is_done() {
+            state = transfer.handle_event(state).unwrap();
+        }
+
+        assert_eq!(vec![5, 6, 7], transfer.client.acks);
+        assert_eq!(2, transfer.blocks);
+        drop(transfer);
+        let mut expected = vec![6; MAX_DATA_SIZE];
+        expected.extend_from_slice(&[7; 10]);
+        assert_eq!(expected, output);
+        assert_eq!(checkpoint(&dir, 8, 5 * MAX_DATA_SIZE as u64 + MAX_DATA_SIZE as u64 + 10),
+                   ResumableTransfer::load(&dir.join("file.bin")).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn first_block_sent_again_rejects_the_resume() {
+        let mut output = Vec::new();
+        let client = MockClient::with_blocks(&[(1, vec![1; MAX_DATA_SIZE]), (1, vec![1; MAX_DATA_SIZE])]);
+        let mut transfer = Transfer::new(client, &mut output)
+            .with_checkpoint(Some(checkpoint(Path::new("unused"), 3, 2 * MAX_DATA_SIZE as u64)));
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+        assert!(matches!(state, ClientStates::ReceivingData(3)));
+        assert!(matches!(transfer.handle_event(state), Err(Error::ResumeRejected)));
+        assert_eq!(vec![2], transfer.client.acks);
+        assert_eq!(1, transfer.client.errors.len());
+        assert_eq!(0, transfer.blocks);
+    }
+
+    #[test]
+    fn unanswered_resume_is_rejected() {
+        let mut output = Vec::new();
+        let client = MockClient::with_blocks(&[(1, vec![1; MAX_DATA_SIZE])]);
+        let mut transfer = Transfer::new(client, &mut output)
+            .with_retransmit(Backoff::default(), 1)
+            .with_checkpoint(Some(checkpoint(Path::new("unused"), 3, 2 * MAX_DATA_SIZE as u64)));
+
+        let state = transfer.handle_event(ClientStates::ReceivingData(1)).unwrap();
+        let state = transfer.handle_timeout(state).unwrap();
+        assert!(matches!(transfer.handle_timeout(state), Err(Error::ResumeRejected)));
+        assert_eq!(1, transfer.client.retransmits);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn resume_state_is_read_back() {
+        let resume = ResumableTransfer {
+            block_id: 65535,
+            byte_offset: 1 << 40,
+            remote_path: "dir/\"odd\" name".to_string(),
+            local_path: PathBuf::from("/tmp/local file"),
+        };
+        let json = serde_json::to_string(&resume).unwrap();
+        assert_eq!(resume, serde_json::from_str(&json).unwrap());
+        assert_eq!(PathBuf::from("/tmp/local file.tftp-resume"), resume.resume_path());
+
+        assert!(serde_json::from_str::<ResumableTransfer>("{\"block_id\":1,\"byte_offset\":0}").is_err());
+        assert!(serde_json::from_str::<ResumableTransfer>(&json.replace("65535", "65536")).is_err());
+        assert!(serde_json::from_str::<ResumableTransfer>("not json").is_err());
+    }
+
+    /// Creates files of different sizes on `server`, returns their names and contents.
+    fn create_files(server: &TestServer, count: usize) -> Vec<(PathBuf, Vec<u8>)> {
+        (0..count).map(|i| {
+            let name = format!("file{}.bin", i);
+            let contents = vec![i as u8; 700 * i];
+            server.create_file(&name, &contents);
+            (PathBuf::from(name), contents)
+        }).collect()
+    }
+
+    #[test]
+    fn files_are_downloaded_in_parallel() {
+        let server = TestServer::new();
+        let dir = temp_dir("download-all");
+        let files = create_files(&server, 6);
+        let dests: Vec<PathBuf> = files.iter().map(|&(ref name, _)| dir.join(name)).collect();
+        let transfers: Vec<(&Path, &Path)> = files.iter().zip(&dests)
+            .map(|(&(ref name, _), dest)| (name.as_path(), dest.as_path()))
+            .collect();
+
+        let results = download_all(server.addr(), &transfers, &ClientConfig::default(), 2);
+
+        assert_eq!(6, results.len());
+        for ((result, &(_, ref contents)), dest) in results.into_iter().zip(&files).zip(&dests) {
+            let stats = result.unwrap();
+            assert_eq!(contents.len() as u64, stats.bytes);
+            assert_eq!(contents.len() as u64 / MAX_DATA_SIZE as u64 + 1, stats.blocks);
+            assert_eq!(*contents, fs::read(dest).unwrap());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_file_does_not_stop_other_downloads() {
+        let server = TestServer::new();
+        let dir = temp_dir("download-all-missing");
+        let files = create_files(&server, 6);
+        fs::remove_file(server.root().join(&files[3].0)).unwrap();
+        let dests: Vec<PathBuf> = files.iter().map(|&(ref name, _)| dir.join(name)).collect();
+        let transfers: Vec<(&Path, &Path)> = files.iter().zip(&dests)
+            .map(|(&(ref name, _), dest)| (name.as_path(), dest.as_path()))
+            .collect();
+
+        let results = download_all(server.addr(), &transfers, &ClientConfig::default(), 2);
+
+        for (i, result) in results.iter().enumerate() {
+            match *result {
+                Err(Error::Server(_)) if i == 3 => assert!(!dests[i].exists()),
+                Ok(ref stats) if i != 3 => assert_eq!(files[i].1.len() as u64, stats.bytes),
+                ref other => panic!("unexpected result for file {}: {:?}", i, other),
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn progress_of_a_download_includes_the_reported_size() {
+        let server = TestServer::new();
+        server.create_file("file.bin", &[3; 2 * 512 + 300]);
+
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let callback_progress = progress.clone();
+        let mut output = Vec::new();
+        Client::new(server.addr()).unwrap()
+            .with_options(TransferOptions { request_tsize: true, ..TransferOptions::default() })
+            .with_progress_callback(move |progress| callback_progress.lock().unwrap().push(progress.clone()))
+            .get(Path::new("file.bin"), Mode::Octet, &mut output).unwrap();
+        let progress = progress.lock().unwrap();
+        let positions: Vec<(u16, u64)> = progress.iter().map(|p| (p.block_id, p.bytes_transferred)).collect();
+        assert_eq!(vec![(1, 512), (2, 1024), (3, 1324)], positions);
+        assert!(progress.iter().all(|p| p.total_bytes == Some(1324) && p.block_size == 512));
+    }
+
+    #[test]
+    fn progress_without_tsize_has_no_total() {
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let callback_progress = progress.clone();
+        let mut output = Vec::new();
+        let blocks = [(1, vec![1; MAX_DATA_SIZE]), (2, vec![2; 10])];
+        let mut transfer = Transfer::new(MockClient::with_blocks(&blocks), &mut output)
+            .with_progress_callback(Some(Box::new(move |progress: &TransferProgress| {
+                callback_progress.lock().unwrap().push(progress.clone())
+            })));
+        let mut state = ClientStates::ReceivingData(1);
+        while !state.is_done() {
+            state = transfer.handle_event(state).unwrap();
+        }
+        assert_eq!(vec![TransferProgress { bytes_transferred: 512, total_bytes: None, block_id: 1, block_size: 512 },
+                         TransferProgress { bytes_transferred: 522, total_bytes: None, block_id: 2, block_size: 512 }],
+                   *progress.lock().unwrap());
+    }
+
+    #[test]
+    fn blocks_of_a_download_are_passed_to_the_callback() {
+        let server = TestServer::new();
+        let contents: Vec<u8> = (0..2 * 512 + 300).map(|i| i as u8).collect();
+        server.create_file("file.bin", &contents);
+
+        let received = Arc::new(Mutex::new((Vec::new(), Vec::new())));
+        let callback_received = received.clone();
+        let mut output = Vec::new();
+        Client::new(server.addr()).unwrap()
+            .with_block_callback(move |block_id, data| {
+                let mut received = callback_received.lock().unwrap();
+                received.0.push(block_id);
+                received.1.extend_from_slice(data);
+            })
+            .get(Path::new("file.bin"), Mode::Octet, &mut output).unwrap();
+        let received = received.lock().unwrap();
+        assert_eq!(vec![1, 2, 3], received.0);
+        assert_eq!(contents, received.1);
+        assert_eq!(contents, output);
+    }
+
+    #[test]
+    fn sequential_downloads_share_the_clients_of_a_pool() {
+        let server = TestServer::new();
+        let pool = ClientPool::new(2, ClientConfig::default()).unwrap();
+        for i in 0..10 {
+            let contents: Vec<u8> = (0..600 + i * 100).map(|b| b as u8).collect();
+            let name = format!("file{}.bin", i);
+            server.create_file(&name, &contents);
+
+            let mut output = Vec::new();
+            let stats = pool.get(server.addr(), Path::new(&name), &mut output).unwrap();
+            assert_eq!(contents, output);
+            assert_eq!(contents.len() as u64, stats.bytes);
+            assert_eq!(2, pool.available());
+        }
+    }
+
+    #[test]
+    fn pooled_clients_keep_their_poll() {
+        let server = TestServer::new();
+        server.create_file("file.bin", &[7; 600]);
+        let pool = ClientPool::new(1, ClientConfig::default()).unwrap();
+        let poll = pool.inner.lock().unwrap().clients[0].1.clone();
+        for _ in 0..3 {
+            let mut output = Vec::new();
+            pool.get(server.addr(), Path::new("file.bin"), &mut output).unwrap();
+            assert_eq!(vec![7; 600], output);
+        }
+        let state = pool.inner.lock().unwrap();
+        let &(ref client, ref pooled) = &state.clients[0];
+        assert!(Arc::ptr_eq(&poll, pooled));
+        assert!(Arc::ptr_eq(&poll, &client.registration.as_ref().unwrap().poll));
+    }
+
+    #[test]
+    fn concurrent_downloads_wait_for_a_free_client() {
+        let server = TestServer::new();
+        let contents: Vec<u8> = (0..3000).map(|b| b as u8).collect();
+        server.create_file("file.bin", &contents);
+        let pool = Arc::new(ClientPool::new(2, ClientConfig::default()).unwrap());
+
+        let downloads: Vec<_> = (0..6).map(|_| {
+            let pool = pool.clone();
+            let addr = server.addr();
+            thread::spawn(move || {
+                let mut output = Vec::new();
+                pool.get(addr, Path::new("file.bin"), &mut output).unwrap();
+                assert!(pool.available() <= 2);
+                output
+            })
+        }).collect();
+        for download in downloads {
+            assert_eq!(contents, download.join().unwrap());
+        }
+        assert_eq!(2, pool.available());
+    }
+
+    #[test]
+    fn cancel_flag_is_created_once() {
+        let mut config = ClientConfig::default();
+        let flag = config.cancel_flag();
+        assert!(Arc::ptr_eq(&flag, &config.cancel_flag()));
+        assert!(Arc::ptr_eq(&flag, config.cancel.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn requested_block_size_is_clamped_to_the_rfc_range() {
+        let requested = |size| {
+            ClientConfig { block_size: Some(size), ..ClientConfig::default() }.request_options()
+        };
+        assert_eq!(options(&[("blksize", "8")]), requested(8));
+        assert_eq!(options(&[("blksize", "8")]), requested(7));
+        assert_eq!(options(&[("blksize", "8")]), requested(0));
+        assert_eq!(options(&[("blksize", "65464")]), requested(65464));
+        assert_eq!(options(&[("blksize", "65464")]), requested(65465));
+        assert_eq!(options(&[("blksize", "65464")]), requested(70000));
+        assert!(ClientConfig::default().request_options().is_empty());
+    }
+
+    #[test]
+    fn busy_server_is_recognized_by_its_message() {
+        let busy = |code, msg| Error::from_server(ErrorPacket::new(code, msg).into_owned());
+        assert!(matches!(busy(ErrorCode::Undefined, "Server busy"), Error::ServerBusy));
+        assert!(matches!(busy(ErrorCode::Undefined, "too BUSY, come back later"), Error::ServerBusy));
+        assert!(matches!(busy(ErrorCode::Undefined, "Transfer cancelled"), Error::Server(_)));
+        assert!(matches!(busy(ErrorCode::AccessViolation, "busy"), Error::Server(_)));
+    }
+
+    #[test]
+    fn requests_carry_the_configured_options() {
+        let config = ClientConfig { block_size: Some(1024), tsize: true, ..ClientConfig::default() };
+        let read = config.read_request(Path::new("boot.img"), Mode::Octet).unwrap().encode();
+        assert_eq!(&b"\x00\x01boot.img\0octet\0blksize\01024\0tsize\00\0"[..], read.packet_buf());
+        // Uploads have no size to ask for.
+        let write = config.write_request(Path::new("boot.img"), Mode::Octet).unwrap().encode();
+        assert_eq!(&b"\x00\x02boot.img\0octet\0blksize\01024\0"[..], write.packet_buf());
+        assert!(config.read_request(Path::new("a\0b"), Mode::Octet).is_err());
+    }
+
+    fn download_with_max_file_size(server: &TestServer, max_file_size: u64, tsize: bool) -> Result<Vec<u8>> {
+        let config = ClientConfig { max_file_size: Some(max_file_size), tsize: tsize, ..ClientConfig::default() };
+        let mut output = Vec::new();
+        try!(Client::new(server.addr()).unwrap().with_config(config)
+             .get(Path::new("file.bin"), Mode::Octet, &mut output));
+        Ok(output)
+    }
+
+    #[test]
+    fn reported_size_over_the_limit_fails_before_the_first_block() {
+        let server = TestServer::new();
+        server.create_file("file.bin", &[1; 2048]);
+        assert!(matches!(download_with_max_file_size(&server, 1024, true), Err(Error::FileTooLarge(2048))));
+    }
+
+    #[test]
+    fn reported_size_at_the_limit_is_downloaded() {
+        let server = TestServer::new();
+        server.create_file("file.bin", &[1; 1024]);
+        assert_eq!(vec![1; 1024], download_with_max_file_size(&server, 1024, true).unwrap());
+    }
+
+    #[test]
+    fn unreported_size_over_the_limit_fails_at_the_block_over_it() {
+        let server = TestServer::new();
+        server.create_file("file.bin", &[1; 2048]);
+        assert!(matches!(download_with_max_file_size(&server, 1024, false), Err(Error::FileTooLarge(1536))));
+    }
+
+    #[test]
+    fn vec_is_reserved_for_the_reported_size() {
+        let server = TestServer::new();
+        server.create_file("file.bin", &[1; 20000]);
+        let contents = get_into_vec(server.addr(), Path::new("file.bin"), Mode::Octet).unwrap();
+        assert_eq!(vec![1; 20000], contents);
+        // Grown block by block, the buffer would have doubled past the file size.
+        assert_eq!(20000, contents.capacity());
+    }
+
+    #[test]
+    fn file_is_downloaded_with_negotiated_block_size() {
+        let server = TestServer::new();
+        let contents: Vec<u8> = (0..2 * 1024 + 300).map(|i| i as u8).collect();
+        server.create_file("file.bin", &contents);
+        let config = ClientConfig { block_size: Some(1024), ..ClientConfig::default() };
+
+        let mut output = Vec::new();
+        let info = Client::new(server.addr()).unwrap().with_config(config)
+            .get(Path::new("file.bin"), Mode::Octet, &mut output).unwrap();
+        assert_eq!(1024, info.block_size);
+        assert_eq!(contents, output);
+    }
+
+    #[test]
+    fn binary_file_is_downloaded_unchanged_in_the_default_mode() {
+        let server = TestServer::new();
+        let contents = b"\x00line\r\nbreak\n\r\x00".to_vec();
+        server.create_file("file.bin", &contents);
+
+        let mut output = Vec::new();
+        Client::new(server.addr()).unwrap().get_default_mode(Path::new("file.bin"), &mut output).unwrap();
+        assert_eq!(contents, output);
+        let mut output = Vec::new();
+        get_from_default_mode(server.addr(), Path::new("file.bin"), &mut output).unwrap();
+        assert_eq!(contents, output);
+        assert_eq!(Mode::Octet, TransferOptions::default().mode);
+    }
+
+    #[test]
+    fn buffer_is_reused_across_downloads() {
+        let server = TestServer::new();
+        server.create_file("first.bin", &[1; 1300]);
+        server.create_file("second.bin", &[2; 700]);
+        let buffer = vec![0; 1024];
+        let allocation = buffer.as_ptr();
+
+        let mut output = Vec::new();
+        let (result, buffer) = Client::new(server.addr()).unwrap().with_buffer(buffer)
+            .get_reusing_buffer(Path::new("first.bin"), Mode::Octet, &mut output);
+        result.unwrap();
+        assert_eq!(allocation, buffer.as_ptr());
+
+        let (result, buffer) = Client::new(server.addr()).unwrap().with_buffer(buffer)
+            .get_reusing_buffer(Path::new("second.bin"), Mode::Octet, &mut output);
+        result.unwrap();
+        assert_eq!(allocation, buffer.as_ptr());
+        assert_eq!([vec![1; 1300], vec![2; 700]].concat(), output);
+    }
+
+    #[test]
+    fn buffer_is_returned_when_the_download_fails() {
+        let server = TestServer::new();
+        let buffer = vec![0; 1024];
+        let allocation = buffer.as_ptr();
+
+        let (result, buffer) = Client::new(server.addr()).unwrap().with_buffer(buffer)
+            .get_reusing_buffer(Path::new("missing.bin"), Mode::Octet, &mut Vec::new());
+        assert!(result.is_err());
+        assert_eq!(allocation, buffer.as_ptr());
+    }
+
+    #[test]
+    fn small_buffer_is_grown_to_hold_a_block() {
+        let server = TestServer::new();
+        server.create_file("file.bin", &[3; 2000]);
+        let config = ClientConfig { block_size: Some(1024), ..ClientConfig::default() };
+
+        let mut output = Vec::new();
+        let (result, buffer) = Client::new(server.addr()).unwrap().with_config(config).with_buffer(vec![0; 4])
+            .get_reusing_buffer(Path::new("file.bin"), Mode::Octet, &mut output);
+        assert_eq!(1024, result.unwrap().block_size);
+        assert_eq!(recv_buffer_size(1024) + 1, buffer.len());
+        assert_eq!(vec![3; 2000], output);
+    }
+
+    /// Downloads `name` from `server`, retransmitting every 200 milliseconds.
+    fn download_with_retries(server: &TestServer, name: &str, max_retries: u32) -> Result<Vec<u8>> {
+        let config = ClientConfig {
+            retransmit: Backoff {
+                base: Duration::from_millis(200),
+                multiplier: 1,
+                max: Duration::from_millis(200),
+            },
+            max_retries: max_retries,
+            ..ClientConfig::default()
+        };
+        let mut output = Vec::new();
+        try!(Client::new(server.addr()).unwrap().with_config(config)
+            .get(Path::new(name), Mode::Octet, &mut output));
+        Ok(output)
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn download_recovers_from_lost_data_blocks() {
+        let server = TestServer::with_faults(FaultConfig { data_drop_rate: 0.3, ..FaultConfig::default() });
+        server.create_file("file.bin", &pattern(10 * 512 + 100));
+
+        assert_eq!(pattern(10 * 512 + 100), download_with_retries(&server, "file.bin", 20).unwrap());
+        assert!(server.faults().iter().any(|fault| match *fault {
+            Fault::DroppedData(_) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn download_recovers_from_lost_acks() {
+        let server = TestServer::with_faults(FaultConfig { ack_drop_rate: 0.3, ..FaultConfig::default() });
+        server.create_file("file.bin", &pattern(10 * 512 + 100));
+
+        assert_eq!(pattern(10 * 512 + 100), download_with_retries(&server, "file.bin", 20).unwrap());
+        assert!(server.faults().iter().any(|fault| match *fault {
+            Fault::DroppedAck(_) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn download_survives_reordered_and_duplicated_packets() {
+        let server = TestServer::with_faults(FaultConfig {
+            reorder_count: 3,
+            duplicate_rate: 0.3,
+            ..FaultConfig::default()
+        });
+        server.create_file("file.bin", &pattern(10 * 512 + 100));
+
+        assert_eq!(pattern(10 * 512 + 100), download_with_retries(&server, "file.bin", 20).unwrap());
+        let faults = server.faults();
+        assert_eq!(3, faults.iter().filter(|fault| match **fault {
+            Fault::Reordered(_) => true,
+            _ => false,
+        }).count());
+        assert!(faults.iter().any(|fault| match *fault {
+            Fault::Duplicated(_) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn download_times_out_when_every_block_is_lost() {
+        let server = TestServer::with_faults(FaultConfig { data_drop_rate: 1.0, ..FaultConfig::default() });
+        server.create_file("file.bin", &pattern(1000));
+
+        match download_with_retries(&server, "file.bin", 2) {
+            Err(Error::Timeout) => {}
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(server.faults().iter().all(|fault| match *fault {
+            Fault::DroppedData(1) => true,
+            _ => false,
+        }));
+    }
+
+    /// Returns the read request sent by `get_with_options` with `transfer_options`.
+    fn read_request_bytes(transfer_options: TransferOptions) -> Vec<u8> {
+        let server = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = server.local_addr().unwrap();
+        let download = thread::spawn(move || {
+            let mut output = Vec::new();
+            get_with_options(addr, Path::new("file.bin"), &mut output, &transfer_options)
+        });
+
+        let mut buf = [0; 512];
+        let (n, client) = server.recv_from(&mut buf).unwrap();
+        let error = ErrorPacket::new(ErrorCode::FileNotFound, "no such file").encode();
+        server.send_to(error.packet_buf(), client).unwrap();
+        assert!(matches!(download.join().unwrap(), Err(Error::Server(_))));
+        buf[..n].to_vec()
+    }
+
+    #[test]
+    fn transfer_options_are_sent_with_the_read_request() {
+        let transfer_options = TransferOptions { blksize: Some(1468), request_tsize: true, ..Default::default() };
+        assert_eq!(&b"\x00\x01file.bin\x00octet\x00blksize\x001468\x00tsize\x000\x00"[..],
+                   &read_request_bytes(transfer_options)[..]);
+
+        let transfer_options = TransferOptions {
+            windowsize: Some(4),
+            timeout_secs: Some(3),
+            mode: Mode::NetAscii,
+            ..Default::default()
+        };
+        assert_eq!(&b"\x00\x01file.bin\x00netascii\x00timeout\x003\x00windowsize\x004\x00"[..],
+                   &read_request_bytes(transfer_options)[..]);
+    }
+
+    #[test]
+    fn default_transfer_options_request_no_options() {
+        assert_eq!(&b"\x00\x01file.bin\x00octet\x00"[..], &read_request_bytes(TransferOptions::default())[..]);
+    }
 }
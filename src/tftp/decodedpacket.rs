@@ -1,22 +1,24 @@
-use std::mem;
-use std::ops::Deref;
+use alloc::vec::Vec;
+use core::mem;
+use core::ops::{Deref, DerefMut};
 
-use packet::{RawPacket, DecodePacket};
+use packet::{RawPacket, DecodePacket, DecodeError, Opcode, UnknownOpcodeError, DataPacketOctet, AckPacket,
+             ErrorPacket, RequestPacket, OackPacket};
 
 pub struct DecodedPacket<P: Sized> {
-    raw: RawPacket,
     packet: P,
+    raw: RawPacket,
 }
 
 impl<P: DecodePacket<'static>> DecodedPacket<P> {
-    pub fn decode(raw: RawPacket) -> Option<DecodedPacket<P>> {
-        let mut p = DecodedPacket {
-            raw: raw,
-            packet: unsafe { mem::uninitialized() },
-        };
-        P::decode(unsafe { extend_buf_lifetime(&p.raw.packet_buf()) }).map(|packet| {
-            p.packet = packet;
-            p
+    pub fn decode(raw: RawPacket) -> Result<DecodedPacket<P>, DecodeError> {
+        // The decoded packet borrows from the heap allocated buffer which is not
+        // moved together with `raw`, so the extended borrow stays valid.
+        P::decode(unsafe { extend_buf_lifetime(raw.packet_buf()) }).map(|packet| {
+            DecodedPacket {
+                packet: packet,
+                raw: raw,
+            }
         })
     }
 
@@ -33,6 +35,186 @@ impl<P: DecodePacket<'static>> Deref for DecodedPacket<P> {
     }
 }
 
+/// A packet type whose decoded value owns its contents rather than borrowing the
+/// buffer of the raw packet.
+///
+/// Only such packets are handed out mutably by `DecodedPacket`: replacing one that
+/// borrows the buffer would let the borrow outlive it. An `ErrorPacket` keeps its
+/// message in the buffer, so it can't be modified:
+///
+/// ```compile_fail
+/// use tftp::decodedpacket::DecodedPacket;
+/// use tftp::packet::{EncodePacket, Error, ErrorPacket};
+///
+/// let raw = ErrorPacket::new(Error::Undefined, "borrowed").encode();
+/// let mut decoded: DecodedPacket<ErrorPacket> = DecodedPacket::decode(raw).unwrap();
+/// let _ = ::std::mem::replace(&mut *decoded, ErrorPacket::new(Error::Undefined, ""));
+/// ```
+pub unsafe trait OwnedPacket {}
+
+unsafe impl OwnedPacket for AckPacket {}
+
+// Decoding copies the data out of the buffer.
+unsafe impl OwnedPacket for DataPacketOctet<'static> {}
+
+impl<P: DecodePacket<'static> + OwnedPacket> DerefMut for DecodedPacket<P> {
+    fn deref_mut(&mut self) -> &mut P {
+        &mut self.packet
+    }
+}
+
+/// A packet of any type, decoded according to its opcode.
+///
+/// Lets a receiver look at whatever arrived instead of deciding up front which
+/// packet type it waits for.
+pub enum AnyPacket {
+    Data(DecodedPacket<DataPacketOctet<'static>>),
+    Ack(AckPacket),
+    Error(ErrorPacket<'static>),
+    Rrq(RequestPacket<'static>),
+    Wrq(RequestPacket<'static>),
+    Oack(OackPacket<'static>),
+    /// A packet with an opcode this crate does not know, and the bytes following it.
+    Unknown(u16, Vec<u8>),
+}
+
+impl AnyPacket {
+    /// Decodes `raw` according to its opcode.
+    ///
+    /// A DATA packet keeps the buffer of `raw`, the other packets are copied out of it.
+    pub fn decode(raw: RawPacket) -> Result<AnyPacket, DecodeError> {
+        if raw.opcode() == Some(Opcode::DATA) {
+            return DecodedPacket::decode(raw).map(AnyPacket::Data)
+        }
+        AnyPacket::decode_copy(&raw)
+    }
+
+    /// Decodes `raw` like `decode`, but copies DATA packets as well, so the buffer
+    /// of `raw` can be reused.
+    pub fn decode_copy(raw: &RawPacket) -> Result<AnyPacket, DecodeError> {
+        if raw.len() < 2 {
+            return Err(DecodeError::TooShort)
+        }
+        let opcode = match raw.try_opcode() {
+            Ok(opcode) => opcode,
+            Err(UnknownOpcodeError(opcode)) => return Ok(AnyPacket::Unknown(opcode, raw.packet_buf()[2..].to_vec())),
+        };
+        match opcode {
+            Opcode::RRQ => raw.decode::<RequestPacket>().map(|request| AnyPacket::Rrq(request.into_owned())),
+            Opcode::WRQ => raw.decode::<RequestPacket>().map(|request| AnyPacket::Wrq(request.into_owned())),
+            Opcode::DATA => {
+                let copy = RawPacket::new(raw.packet_buf().to_vec(), raw.len());
+                DecodedPacket::decode(copy).map(AnyPacket::Data)
+            }
+            Opcode::ACK => raw.decode::<AckPacket>().map(AnyPacket::Ack),
+            Opcode::ERROR => raw.decode::<ErrorPacket>().map(|error| AnyPacket::Error(error.into_owned())),
+            Opcode::OACK => raw.decode::<OackPacket>().map(|oack| AnyPacket::Oack(oack.into_owned())),
+        }
+    }
+
+    /// Returns the opcode of the packet, `None` if it is unknown.
+    pub fn opcode(&self) -> Option<Opcode> {
+        match *self {
+            AnyPacket::Data(..) => Some(Opcode::DATA),
+            AnyPacket::Ack(..) => Some(Opcode::ACK),
+            AnyPacket::Error(..) => Some(Opcode::ERROR),
+            AnyPacket::Rrq(..) => Some(Opcode::RRQ),
+            AnyPacket::Wrq(..) => Some(Opcode::WRQ),
+            AnyPacket::Oack(..) => Some(Opcode::OACK),
+            AnyPacket::Unknown(..) => None,
+        }
+    }
+}
+
 unsafe fn extend_buf_lifetime<'a>(r: &'a [u8]) -> &'static [u8] {
     mem::transmute(r)
 }
+
+#[cfg(test)]
+mod test {
+    use packet::{AckPacket, DataPacketOctet, EncodePacket, ErrorPacket, Error, Mode, OackPacket, RawPacket,
+                 RequestPacket, DecodeError};
+
+    use super::{AnyPacket, DecodedPacket};
+
+    #[test]
+    fn packet_methods_are_available_through_deref() {
+        let raw = DataPacketOctet::from_slice(3, &[1, 2, 3]).encode();
+        let decoded: DecodedPacket<DataPacketOctet> = DecodedPacket::decode(raw).unwrap();
+        assert_eq!(3, decoded.block_id());
+        assert_eq!(&[1, 2, 3], decoded.data());
+    }
+
+    #[test]
+    fn packet_is_modified_through_deref_mut() {
+        let raw = AckPacket::new(1).encode();
+        let mut decoded: DecodedPacket<AckPacket> = DecodedPacket::decode(raw).unwrap();
+        *decoded = AckPacket::new(7);
+        assert_eq!(7, decoded.block_id());
+    }
+
+    #[test]
+    fn data_packet_is_modified_through_deref_mut() {
+        let raw = DataPacketOctet::from_slice(1, &[1, 2, 3]).encode();
+        let mut decoded: DecodedPacket<DataPacketOctet> = DecodedPacket::decode(raw).unwrap();
+        let data = ::core::mem::replace(&mut *decoded, DataPacketOctet::from_vec(2, vec![4], 1));
+        drop(decoded);
+        assert_eq!(&[1, 2, 3], data.data());
+    }
+
+    fn decode(bytes: &[u8]) -> Result<AnyPacket, DecodeError> {
+        AnyPacket::decode(RawPacket::new(bytes.to_vec(), bytes.len()))
+    }
+
+    #[test]
+    fn any_packet_is_decoded_by_its_opcode() {
+        match decode(b"\x00\x01file\0octet\0").unwrap() {
+            AnyPacket::Rrq(request) => assert_eq!(RequestPacket::read_request("file", Mode::Octet), request),
+            _ => panic!("not a read request"),
+        }
+        match decode(b"\x00\x02file\0netascii\0").unwrap() {
+            AnyPacket::Wrq(request) => assert_eq!(RequestPacket::write_request("file", Mode::NetAscii), request),
+            _ => panic!("not a write request"),
+        }
+        match decode(b"\x00\x03\x00\x07abc").unwrap() {
+            AnyPacket::Data(data) => {
+                assert_eq!(7, data.block_id());
+                assert_eq!(b"abc", data.data());
+            }
+            _ => panic!("not a data packet"),
+        }
+        match decode(b"\x00\x04\x00\x09").unwrap() {
+            AnyPacket::Ack(ack) => assert_eq!(AckPacket::new(9), ack),
+            _ => panic!("not an ack"),
+        }
+        match decode(b"\x00\x05\x00\x01missing\0").unwrap() {
+            AnyPacket::Error(error) => assert_eq!(ErrorPacket::new(Error::FileNotFound, "missing"), error),
+            _ => panic!("not an error"),
+        }
+        match decode(b"\x00\x06blksize\01024\0").unwrap() {
+            AnyPacket::Oack(oack) => assert_eq!(OackPacket::new(&[("blksize", "1024")]), oack),
+            _ => panic!("not an oack"),
+        }
+        match decode(b"\x00\x2aabc").unwrap() {
+            AnyPacket::Unknown(opcode, rest) => {
+                assert_eq!(42, opcode);
+                assert_eq!(b"abc".to_vec(), rest);
+            }
+            _ => panic!("not an unknown packet"),
+        }
+    }
+
+    #[test]
+    fn malformed_any_packet_is_an_error() {
+        assert_eq!(Some(DecodeError::TooShort), decode(b"\x00").err());
+        assert!(decode(b"\x00\x04\x00").is_err());
+        assert!(decode(b"\x00\x01file").is_err());
+    }
+
+    #[test]
+    fn buffer_is_recovered() {
+        let raw = AckPacket::new(1).encode();
+        let decoded: DecodedPacket<AckPacket> = DecodedPacket::decode(raw).unwrap();
+        assert_eq!(vec![0; 4], decoded.into_inner());
+    }
+}
@@ -12,18 +12,60 @@
 //! RFCs implemented:
 //!
 //! - RFC 1350 - TFTP Protocol (revision 2) (http://tools.ietf.org/html/rfc1350)
+//! - RFC 2347 - TFTP Option Extension (http://tools.ietf.org/html/rfc2347)
+//! - RFC 2348 - TFTP Blocksize Option (http://tools.ietf.org/html/rfc2348)
+//! - RFC 2349 - TFTP Timeout Interval and Transfer Size Options (http://tools.ietf.org/html/rfc2349)
+//! - RFC 7440 - TFTP Windowsize Option (http://tools.ietf.org/html/rfc7440), client side
+//! - RFC 3617 - Uniform Resource Identifier (URI) Scheme for TFTP (http://tools.ietf.org/html/rfc3617),
+//!   in `uri`
+//!
+//! The client and the server need the `std` feature, which is enabled by default.
+//! Without it only the packet encoding and decoding of `packet`, `netascii` and
+//! `decodedpacket` is built, for targets that bring their own UDP stack.
+//!
+//! The client and the server report their progress through the `log` crate. Every
+//! state transition of a download is logged at the `trace` level, as `key=value`
+//! fields: with `env_logger`, `RUST_LOG=tftp=trace` shows them.
+//!
+//! The optional `serde` feature makes `packet::Mode`, `packet::Error` and
+//! `client::TransferStats` serializable. A mode is written as its name on the wire
+//! and an error code as its number. It also brings `audit::JsonLineLogger`, which
+//! writes the audit entries of transfers as JSON lines, and the saving and resuming
+//! of `client::ResumableTransfer`, whose progress is kept in a JSON file.
 
 #![crate_name = "tftp"]
 #![cfg_attr(test, feature(test))]
+#![cfg_attr(not(feature = "std"), no_std)]
+#![deny(unused_must_use)]
 
-extern crate mio;
-#[macro_use(try_nb)] extern crate tokio_core;
-extern crate futures;
-#[macro_use(quick_error)] extern crate quick_error;
+#[macro_use] extern crate alloc;
+// Without `std`, `core` is linked in place of it.
+#[cfg(feature = "std")] extern crate core;
+#[cfg(feature = "std")] extern crate chrono;
+#[cfg(all(feature = "std", target_os = "linux"))] extern crate libc;
+#[cfg(feature = "std")] extern crate mio;
+#[cfg(feature = "std")] extern crate socket2;
+#[cfg(feature = "tokio")] extern crate tokio;
+#[cfg(feature = "std")] #[macro_use(quick_error)] extern crate quick_error;
+#[cfg(feature = "std")] #[macro_use] extern crate log;
+#[cfg(feature = "serde")] #[macro_use] extern crate serde;
+#[cfg(all(feature = "std", feature = "serde"))] extern crate serde_json;
 
 pub mod packet;
 pub mod netascii;
 pub mod decodedpacket;
+pub mod options;
+#[cfg(feature = "std")]
+pub mod audit;
 
+#[cfg(feature = "std")]
 pub mod client;
+#[cfg(all(feature = "std", feature = "tokio"))]
+pub mod tokio_client;
+#[cfg(feature = "std")]
 pub mod server;
+#[cfg(feature = "std")]
+pub mod uri;
+
+#[cfg(test)]
+mod test_server;
@@ -1,6 +1,7 @@
 //! Netascii string utilities.
-use std::borrow::Cow;
-use std::convert::From;
+use alloc::borrow::Cow;
+use alloc::string::String;
+use core::convert::From;
 
 /// Netascii encoded string
 pub type NetasciiString<'a> = Cow<'a, str>;
@@ -0,0 +1,278 @@
+//! Negotiation of the options appended to a request (RFC 2347).
+//!
+//! A client proposes options in its request and the server acknowledges the ones
+//! it accepts, possibly with other values, in an OACK. `OptionNegotiator` serves both
+//! ends: the server accepts the proposed options one at a time and builds the OACK
+//! from them, the client checks the OACK it receives against its request.
+//!
+//! Like `packet`, the module only needs `core` and `alloc`.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp;
+use core::fmt;
+use core::ops::RangeInclusive;
+#[cfg(feature = "std")]
+use std::error;
+
+use packet::{OackPacket, RequestPacket};
+
+/// Block size of a transfer without the `blksize` option.
+const DEFAULT_BLOCK_SIZE: u16 = 512;
+
+/// Smallest block size allowed by RFC 2348.
+const MIN_BLOCK_SIZE: u16 = 8;
+
+/// Options proposed in a request, and the values accepted for them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptionNegotiator {
+    /// Proposed option names in lower case and their values, in the order of the request.
+    proposed: Vec<(String, String)>,
+    /// Value to acknowledge for each proposed option, `None` until it is accepted.
+    accepted: Vec<Option<String>>,
+}
+
+impl OptionNegotiator {
+    /// Creates a negotiator for the options of `packet`.
+    pub fn from_request(packet: &RequestPacket) -> OptionNegotiator {
+        OptionNegotiator::new(&packet.options().collect::<Vec<_>>())
+    }
+
+    /// Creates a negotiator for the given proposed option name and value pairs.
+    pub fn new<K: AsRef<str>, V: AsRef<str>>(options: &[(K, V)]) -> OptionNegotiator {
+        OptionNegotiator {
+            proposed: options.iter()
+                .map(|&(ref name, ref value)| (name.as_ref().to_ascii_lowercase(), value.as_ref().to_string()))
+                .collect(),
+            accepted: vec![None; options.len()],
+        }
+    }
+
+    /// Returns the proposed value of an option, ignoring the case of its name.
+    pub fn proposed(&self, name: &str) -> Option<&str> {
+        self.position(name).map(|i| &self.proposed[i].1[..])
+    }
+
+    /// Accepts the proposed `blksize` option (RFC 2348), clamped to `max`.
+    ///
+    /// Block sizes below 512 bytes are not accepted, the transfer keeps the default
+    /// size then. Returns the accepted block size.
+    pub fn negotiate_blksize(&mut self, max: u16) -> Option<u16> {
+        let size = match self.proposed("blksize").and_then(|value| value.parse::<u64>().ok()) {
+            Some(size) if size >= DEFAULT_BLOCK_SIZE as u64 => cmp::min(size, max as u64) as u16,
+            _ => return None,
+        };
+        self.accept("blksize", size.to_string());
+        Some(size)
+    }
+
+    /// Accepts the proposed `timeout` option (RFC 2349) if it lies within `range`.
+    ///
+    /// Returns the accepted timeout in seconds.
+    pub fn negotiate_timeout(&mut self, range: RangeInclusive<u8>) -> Option<u8> {
+        let secs = match self.proposed("timeout").and_then(|value| value.parse::<u8>().ok()) {
+            Some(secs) if range.contains(&secs) => secs,
+            _ => return None,
+        };
+        self.accept("timeout", secs.to_string());
+        Some(secs)
+    }
+
+    /// Answers the proposed `tsize` option (RFC 2349) with the size of the file.
+    ///
+    /// Returns `file_size` if the option was proposed.
+    pub fn tsize(&mut self, file_size: u64) -> Option<u64> {
+        if self.proposed("tsize").is_none() {
+            return None
+        }
+        self.accept("tsize", file_size.to_string());
+        Some(file_size)
+    }
+
+    /// Whether any option was accepted; without one no OACK is sent.
+    pub fn has_accepted(&self) -> bool {
+        self.accepted.iter().any(Option::is_some)
+    }
+
+    /// Builds the OACK of the accepted options, in the order they were proposed.
+    pub fn build_oack(&self) -> OackPacket<'static> {
+        let accepted: Vec<(&str, &str)> = self.proposed.iter().zip(&self.accepted)
+            .filter_map(|(&(ref name, _), value)| value.as_ref().map(|value| (&name[..], &value[..])))
+            .collect();
+        OackPacket::new(&accepted)
+    }
+
+    /// Checks an OACK received in answer to the proposed options.
+    ///
+    /// Fails if the OACK acknowledges an option that was not proposed, or a value
+    /// the proposal does not allow, such as a block or window size larger than the
+    /// proposed one. Unknown options that were proposed are skipped.
+    pub fn validate_oack(&self, oack: &OackPacket) -> Result<AcknowledgedOptions, OptionError> {
+        let mut acknowledged = AcknowledgedOptions::default();
+        for (name, value) in oack.options() {
+            let proposed = match self.proposed(name) {
+                Some(proposed) => proposed,
+                None => return Err(OptionError::NotRequested(name.to_string())),
+            };
+            let invalid = || OptionError::InvalidValue(name.to_ascii_lowercase(), value.to_string());
+            match &name.to_ascii_lowercase()[..] {
+                "blksize" => match (value.parse::<u16>(), proposed.parse::<u16>()) {
+                    (Ok(size), Ok(max)) if size >= MIN_BLOCK_SIZE && size <= max => acknowledged.blksize = Some(size),
+                    _ => return Err(invalid()),
+                },
+                "timeout" => match value.parse::<u8>() {
+                    Ok(secs) if secs >= 1 => acknowledged.timeout = Some(secs),
+                    _ => return Err(invalid()),
+                },
+                "tsize" => match value.parse::<u64>() {
+                    Ok(size) => acknowledged.tsize = Some(size),
+                    Err(_) => return Err(invalid()),
+                },
+                "rollover" => match value.parse::<u16>() {
+                    Ok(block_id) if block_id <= 1 => acknowledged.rollover = Some(block_id),
+                    _ => return Err(invalid()),
+                },
+                "windowsize" => match (value.parse::<u16>(), proposed.parse::<u16>()) {
+                    (Ok(size), Ok(max)) if size >= 1 && size <= max => acknowledged.windowsize = Some(size),
+                    _ => return Err(invalid()),
+                },
+                _ => {}
+            }
+        }
+        Ok(acknowledged)
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.proposed.iter().position(|&(ref proposed, _)| proposed.eq_ignore_ascii_case(name))
+    }
+
+    fn accept(&mut self, name: &str, value: String) {
+        if let Some(i) = self.position(name) {
+            self.accepted[i] = Some(value);
+        }
+    }
+}
+
+/// Options acknowledged by a server, `None` for the ones it left out of its OACK.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AcknowledgedOptions {
+    /// Block size of the transfer.
+    pub blksize: Option<u16>,
+    /// Retransmission timeout in seconds.
+    pub timeout: Option<u8>,
+    /// Size of the file; 0 means the file is empty.
+    pub tsize: Option<u64>,
+    /// Block id following block 65535.
+    pub rollover: Option<u16>,
+    /// Number of blocks sent per ACK.
+    pub windowsize: Option<u16>,
+}
+
+/// An OACK that does not answer the request it was sent for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptionError {
+    /// The named option was acknowledged without being requested.
+    NotRequested(String),
+    /// The named option was acknowledged with a malformed value, or one the request
+    /// did not allow.
+    InvalidValue(String, String),
+}
+
+impl fmt::Display for OptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OptionError::NotRequested(ref name) =>
+                write!(f, "server acknowledged option {} that was not requested", name),
+            OptionError::InvalidValue(ref name, ref value) =>
+                write!(f, "server acknowledged option {} with invalid value {:?}", name, value),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for OptionError {
+    fn description(&self) -> &str { "invalid option acknowledgment" }
+}
+
+#[cfg(test)]
+mod test {
+    use packet::{Mode, OackPacket, RequestPacket};
+
+    use super::{AcknowledgedOptions, OptionError, OptionNegotiator};
+
+    fn request<'a>(options: &[(&str, &str)]) -> RequestPacket<'a> {
+        RequestPacket::read_request_with_options("file", Mode::Octet, options)
+    }
+
+    #[test]
+    fn server_accepting_all_options_acknowledges_them_in_request_order() {
+        let request = request(&[("TSIZE", "0"), ("blksize", "1468"), ("timeout", "3")]);
+        let mut negotiator = OptionNegotiator::from_request(&request);
+        assert!(!negotiator.has_accepted());
+        assert_eq!(Some(1468), negotiator.negotiate_blksize(65464));
+        assert_eq!(Some(3), negotiator.negotiate_timeout(1..=255));
+        assert_eq!(Some(4000), negotiator.tsize(4000));
+
+        let oack = negotiator.build_oack();
+        assert_eq!(vec![("tsize", "4000"), ("blksize", "1468"), ("timeout", "3")],
+                   oack.options().collect::<Vec<_>>());
+        let acknowledged = OptionNegotiator::from_request(&request).validate_oack(&oack).unwrap();
+        assert_eq!(AcknowledgedOptions { blksize: Some(1468), timeout: Some(3), tsize: Some(4000), rollover: None,
+                                         windowsize: None },
+                   acknowledged);
+    }
+
+    #[test]
+    fn server_limits_block_size_and_timeout() {
+        let mut negotiator = OptionNegotiator::new(&[("blksize", "100000"), ("timeout", "9")]);
+        assert_eq!(Some(1428), negotiator.negotiate_blksize(1428));
+        assert_eq!(None, negotiator.negotiate_timeout(1..=5));
+        assert_eq!(vec![("blksize", "1428")], negotiator.build_oack().options().collect::<Vec<_>>());
+
+        let mut negotiator = OptionNegotiator::new(&[("blksize", "256"), ("timeout", "0")]);
+        assert_eq!(None, negotiator.negotiate_blksize(65464));
+        assert_eq!(None, negotiator.negotiate_timeout(1..=255));
+        assert!(!negotiator.has_accepted());
+    }
+
+    #[test]
+    fn options_that_were_not_proposed_are_not_accepted() {
+        let mut negotiator = OptionNegotiator::new(&[("windowsize", "4")]);
+        assert_eq!(None, negotiator.negotiate_blksize(65464));
+        assert_eq!(None, negotiator.tsize(10));
+        assert!(!negotiator.has_accepted());
+    }
+
+    #[test]
+    fn server_rejecting_block_size_leaves_it_out_of_the_oack() {
+        let negotiator = OptionNegotiator::new(&[("blksize", "1024"), ("tsize", "0")]);
+        let acknowledged = negotiator.validate_oack(&OackPacket::new(&[("tsize", "10")])).unwrap();
+        assert_eq!(None, acknowledged.blksize);
+        assert_eq!(Some(10), acknowledged.tsize);
+    }
+
+    #[test]
+    fn larger_block_size_than_proposed_is_a_violation() {
+        let negotiator = OptionNegotiator::new(&[("blksize", "1024")]);
+        assert_eq!(Err(OptionError::InvalidValue("blksize".to_string(), "2048".to_string())),
+                   negotiator.validate_oack(&OackPacket::new(&[("blksize", "2048")])));
+        assert!(negotiator.validate_oack(&OackPacket::new(&[("blksize", "4")])).is_err());
+        assert!(negotiator.validate_oack(&OackPacket::new(&[("blksize", "big")])).is_err());
+    }
+
+    #[test]
+    fn unrequested_option_is_a_violation() {
+        let negotiator = OptionNegotiator::new(&[("blksize", "1024")]);
+        assert_eq!(Err(OptionError::NotRequested("tsize".to_string())),
+                   negotiator.validate_oack(&OackPacket::new(&[("tsize", "10")])));
+    }
+
+    #[test]
+    fn zero_transfer_size_means_the_file_is_empty() {
+        let mut negotiator = OptionNegotiator::new(&[("tsize", "0")]);
+        assert_eq!(Some(0), negotiator.tsize(0));
+        let oack = negotiator.build_oack();
+        assert_eq!(Some("0"), oack.option("tsize"));
+        assert_eq!(Some(0), negotiator.validate_oack(&oack).unwrap().tsize);
+    }
+}
@@ -1,21 +1,31 @@
 //! A Trivial File Transfer Protocol (TFTP) packet utilities.
+//!
+//! Packets are encoded to and decoded from plain byte slices, the module only needs
+//! `core` and `alloc` and is available without the `std` feature.
 
 extern crate byteorder;
 
-use std::io::{Write, Cursor};
-use std::borrow::Cow;
-use std::convert::From;
+use alloc::borrow::Cow;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::{From, TryFrom};
+use core::fmt;
+use core::str::{self, FromStr};
+#[cfg(feature = "std")]
 use std::error;
-use std::fmt;
-use std::str::{self, FromStr};
+#[cfg(feature = "std")]
+use std::io;
 
 use netascii::{NetasciiString, to_netascii, from_netascii};
 
-use self::byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use self::byteorder::{ByteOrder, BigEndian};
 
 
 /// Opcode that represents packet's type.
+///
+/// More opcodes may be added, matches outside this crate need a wildcard arm.
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[non_exhaustive]
 pub enum Opcode {
     /// Read request
     RRQ   = 1,
@@ -31,6 +41,9 @@ pub enum Opcode {
 
     /// Error
     ERROR = 5,
+
+    /// Option acknowledgment (RFC 2347)
+    OACK  = 6,
 }
 
 impl Opcode {
@@ -38,19 +51,57 @@ impl Opcode {
     ///
     /// If numeric opcode is invalid `None` is returned.
     pub fn from_u16(opcode: u16) -> Option<Opcode> {
+        Opcode::try_from(opcode).ok()
+    }
+}
+
+/// Shows the name of the packet type and its number, `DATA (3)`.
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} ({})", self, *self as u16)
+    }
+}
+
+impl TryFrom<u16> for Opcode {
+    type Error = UnknownOpcodeError;
+
+    fn try_from(opcode: u16) -> Result<Opcode, UnknownOpcodeError> {
         match opcode {
-            1 => Some(Opcode::RRQ),
-            2 => Some(Opcode::WRQ),
-            3 => Some(Opcode::DATA),
-            4 => Some(Opcode::ACK),
-            5 => Some(Opcode::ERROR),
-            _ => None
+            1 => Ok(Opcode::RRQ),
+            2 => Ok(Opcode::WRQ),
+            3 => Ok(Opcode::DATA),
+            4 => Ok(Opcode::ACK),
+            5 => Ok(Opcode::ERROR),
+            6 => Ok(Opcode::OACK),
+            _ => Err(UnknownOpcodeError(opcode))
         }
     }
 }
 
+/// An opcode value that does not name a known packet type.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct UnknownOpcodeError(pub u16);
+
+impl fmt::Display for UnknownOpcodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown TFTP opcode: {}", self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for UnknownOpcodeError {
+    fn description(&self) -> &str { "unknown TFTP opcode" }
+}
+
 /// Mode of data transfer
+///
+/// RFC 1350 also defines a `mail` mode, which it already calls obsolete. It is deliberately
+/// not represented: no request can be built with it, and parsing `mail`, like any other
+/// unknown mode, fails.
+///
+/// With the `serde` feature a mode is serialized as its name on the wire.
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(into = "&'static str", try_from = "&str"))]
 pub enum Mode {
     /// Netascii transfer mode.
     ///
@@ -74,6 +125,15 @@ impl Mode {
     }
 }
 
+/// Octet, which transfers any file unchanged.
+///
+/// Netascii is never the default, it alters the line endings of binary files.
+impl Default for Mode {
+    fn default() -> Mode {
+        Mode::Octet
+    }
+}
+
 #[derive(Debug)]
 pub struct ParseModeError;
 
@@ -83,6 +143,7 @@ impl fmt::Display for ParseModeError {
     }
 }
 
+#[cfg(feature = "std")]
 impl error::Error for ParseModeError {
     fn description(&self) -> &str { "failed to parse Mode" }
 }
@@ -99,8 +160,27 @@ impl FromStr for Mode {
     }
 }
 
+impl<'a> TryFrom<&'a str> for Mode {
+    type Error = ParseModeError;
+
+    fn try_from(s: &'a str) -> Result<Mode, ParseModeError> {
+        Mode::from_str(s)
+    }
+}
+
+impl From<Mode> for &'static str {
+    fn from(mode: Mode) -> &'static str {
+        mode.as_str()
+    }
+}
+
 /// Error codes
+///
+/// With the `serde` feature an error code is serialized as its number. More error
+/// codes may be added, matches outside this crate need a wildcard arm.
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(into = "u16", try_from = "u16"))]
+#[non_exhaustive]
 pub enum Error {
     /// Not defined, see error message.
     Undefined                 = 0,
@@ -146,6 +226,35 @@ impl Error {
     }
 }
 
+impl TryFrom<u16> for Error {
+    type Error = DecodeError;
+
+    fn try_from(code: u16) -> Result<Error, DecodeError> {
+        Error::from_u16(code).ok_or(DecodeError::UnknownErrorCode(code))
+    }
+}
+
+impl From<Error> for u16 {
+    fn from(error: Error) -> u16 {
+        error as u16
+    }
+}
+
+/// Maps an error code to the closest I/O error kind, `Other` if there is none.
+#[cfg(feature = "std")]
+impl From<Error> for io::ErrorKind {
+    fn from(error: Error) -> io::ErrorKind {
+        match error {
+            Error::FileNotFound => io::ErrorKind::NotFound,
+            Error::AccessViolation => io::ErrorKind::PermissionDenied,
+            Error::DiskFull => io::ErrorKind::StorageFull,
+            Error::FileAlreadyExists => io::ErrorKind::AlreadyExists,
+            Error::IllegalOperation => io::ErrorKind::InvalidInput,
+            Error::Undefined | Error::UnknownTransferId | Error::NoSuchUser => io::ErrorKind::Other,
+        }
+    }
+}
+
 impl<'a> fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -161,6 +270,41 @@ impl<'a> fmt::Display for Error {
     }
 }
 
+/// Length of the opcode and block number preceding the payload of a data packet.
+pub const TFTP_HEADER_LEN: usize = 4;
+
+/// Returns the size of a buffer able to receive a data packet carrying `block_size`
+/// bytes of payload.
+pub fn recv_buffer_size(block_size: usize) -> usize {
+    block_size + TFTP_HEADER_LEN
+}
+
+/// Splits `data` into blocks of `blksize` bytes, paired with their block ids starting
+/// at 1. Ids wrap around from 65535 to 0.
+///
+/// The last block holds the remaining bytes. If it is a full block, the empty block
+/// that ends the transfer is not included and must be sent by the caller. Empty
+/// `data` yields that single empty block 1.
+///
+/// Panics if `blksize` is 0.
+pub fn split_into_blocks<'a>(data: &'a [u8], blksize: u16) -> impl Iterator<Item = (u16, &'a [u8])> + 'a {
+    let empty: &'a [u8] = &[];
+    let terminator = if data.is_empty() { Some(empty) } else { None };
+    data.chunks(blksize as usize).chain(terminator).enumerate()
+        .map(|(i, block)| ((i as u16).wrapping_add(1), block))
+}
+
+/// Returns the number of data packets needed to send a file of `file_size` bytes in
+/// blocks of `blksize` bytes.
+///
+/// A transfer ends with the first block shorter than `blksize`. When the size is a
+/// multiple of `blksize`, that is an extra empty block, which is counted too.
+///
+/// Panics if `blksize` is 0.
+pub fn compute_block_count(file_size: u64, blksize: u16) -> u64 {
+    file_size / blksize as u64 + 1
+}
+
 /// A trait to represent common packet data.
 pub trait Packet {
     /// Returns opcode value associated with that packet.
@@ -168,6 +312,11 @@ pub trait Packet {
 
     /// Returns number of bytes of the encoded packet.
     fn len(&self) -> usize;
+
+    /// Whether the encoded packet has no bytes, which never holds for a valid packet.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 /// General packet decoding.
@@ -177,35 +326,240 @@ pub trait Packet {
 pub trait DecodePacket<'a> : Sized {
     /// Decode a packet from a given byte slice.
     ///
-    /// If the packet can't be decoded `None` is returned.
+    /// If the packet can't be decoded the reason is returned as a `DecodeError`.
     #[inline]
-    fn decode(&'a [u8]) -> Option<Self>;
+    fn decode(&'a [u8]) -> Result<Self, DecodeError>;
+}
+
+/// Error returned when bytes can't be decoded into a packet.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum DecodeError {
+    /// The packet ends before a required field.
+    TooShort,
+    /// The opcode is unknown or belongs to another type of packet.
+    BadOpcode(u16),
+    /// A string field is not terminated by a NUL byte.
+    MissingNulTerminator,
+    /// The transfer mode of a request is unknown.
+    InvalidMode,
+    /// A string field is not valid UTF-8.
+    InvalidUtf8,
+    /// The error code of an error packet is unknown.
+    UnknownErrorCode(u16),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::TooShort => write!(f, "packet too short"),
+            DecodeError::BadOpcode(opcode) => write!(f, "unexpected opcode: {}", opcode),
+            DecodeError::MissingNulTerminator => write!(f, "string field is not NUL terminated"),
+            DecodeError::InvalidMode => write!(f, "unknown transfer mode"),
+            DecodeError::InvalidUtf8 => write!(f, "string field is not valid UTF-8"),
+            DecodeError::UnknownErrorCode(code) => write!(f, "unknown error code: {}", code),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for DecodeError {
+    fn description(&self) -> &str { "malformed packet" }
+}
+
+/// Reads the opcode of a packet that must be one of `expected`.
+fn read_opcode(data: &[u8], expected: &[Opcode]) -> Result<Opcode, DecodeError> {
+    let value = try!(read_u16(data, 0));
+    match Opcode::from_u16(value) {
+        Some(opcode) if expected.contains(&opcode) => Ok(opcode),
+        _ => Err(DecodeError::BadOpcode(value)),
+    }
+}
+
+/// Reads the big endian `u16` starting at `offset`.
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, DecodeError> {
+    if data.len() < offset + 2 {
+        return Err(DecodeError::TooShort)
+    }
+    Ok(BigEndian::read_u16(&data[offset..]))
+}
+
+/// Writes the fields of a packet one after another.
+///
+/// The buffer must have been checked to hold the whole packet, writing past its end
+/// panics.
+struct FieldWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> FieldWriter<'a> {
+    fn new(buf: &'a mut [u8]) -> FieldWriter<'a> {
+        FieldWriter {
+            buf: buf,
+            pos: 0,
+        }
+    }
+
+    fn write_u16(&mut self, value: u16) {
+        BigEndian::write_u16(&mut self.buf[self.pos..], value);
+        self.pos += 2;
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    /// Writes `bytes` followed by a NUL terminator.
+    fn write_str(&mut self, bytes: &[u8]) {
+        self.write(bytes);
+        self.write(&[0]);
+    }
+}
+
+/// Error returned when a packet can't be encoded into a given buffer.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum EncodeError {
+    /// The buffer can't hold the encoded packet.
+    BufferTooSmall {
+        /// Number of bytes of the encoded packet.
+        needed: usize,
+        /// Number of bytes available in the buffer.
+        available: usize,
+    },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::BufferTooSmall { needed, available } => {
+                write!(f, "buffer too small: {} bytes needed, {} available", needed, available)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for EncodeError {
+    fn description(&self) -> &str { "buffer too small for packet" }
+}
+
+/// Checks that `buf` can hold a packet of `len` bytes.
+fn check_len(len: usize, buf: &[u8]) -> Result<usize, EncodeError> {
+    if buf.len() < len {
+        Err(EncodeError::BufferTooSmall { needed: len, available: buf.len() })
+    } else {
+        Ok(len)
+    }
 }
 
 /// General packet encoding.
 pub trait EncodePacket : Packet {
     /// Encode a packet using a newly allocated buffer.
     ///
-    /// This method is provided only for convenience, use `encode_using` for
-    /// maximal buffer reuse when possible.
+    /// This method is provided only for convenience, use `encode_using` or
+    /// `encode_into` for maximal buffer reuse when possible.
     #[inline]
     fn encode(&self) -> RawPacket {
         self.encode_using(vec![0u8; self.len()])
     }
 
     /// Encode a packet using the the provided buffer.
+    ///
+    /// The buffer is grown if it is too small to hold the packet.
     #[inline]
-    fn encode_using(&self, buf: Vec<u8>) -> RawPacket;
+    fn encode_using(&self, mut buf: Vec<u8>) -> RawPacket {
+        let len = self.len();
+        if buf.len() < len {
+            buf.resize(len, 0);
+        }
+        let len = self.encode_into(&mut buf).unwrap();
+        RawPacket::new(buf, len)
+    }
+
+    /// Encode a packet into the provided slice, returning the number of bytes written.
+    ///
+    /// Fails without writing anything if the slice can't hold the packet.
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, EncodeError>;
+}
+
+/// Options appended to a request or an option acknowledgment (RFC 2347).
+///
+/// Stored in the wire format, as a sequence of NUL terminated option names each
+/// followed by its NUL terminated value.
+pub type RawOptions<'a> = Cow<'a, str>;
+
+/// Encodes option name and value pairs in the wire format.
+fn encode_options<K: AsRef<str>, V: AsRef<str>>(options: &[(K, V)]) -> RawOptions<'static> {
+    let mut encoded = String::new();
+    for &(ref name, ref value) in options {
+        encoded.push_str(name.as_ref());
+        encoded.push('\0');
+        encoded.push_str(value.as_ref());
+        encoded.push('\0');
+    }
+    Cow::from(encoded)
+}
+
+/// An iterator over option name and value pairs.
+///
+/// Only pairs whose name and value are both NUL terminated are yielded. Iteration
+/// stops at the end of the options, at an empty name or at a trailing incomplete
+/// pair, so malformed options never cause a panic.
+pub struct OptionsIter<'a> {
+    rest: &'a str,
+}
+
+impl<'a> OptionsIter<'a> {
+    fn new(options: &'a str) -> OptionsIter<'a> {
+        OptionsIter {
+            rest: options,
+        }
+    }
+
+    /// Splits off the next NUL terminated token.
+    fn token(&mut self) -> Option<&'a str> {
+        self.rest.find('\0').map(|end| {
+            let token = &self.rest[..end];
+            self.rest = &self.rest[end + 1..];
+            token
+        })
+    }
+}
+
+impl<'a> Iterator for OptionsIter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        match (self.token(), self.token()) {
+            (Some(name), Some(value)) if !name.is_empty() => Some((name, value)),
+            _ => {
+                self.rest = "";
+                None
+            }
+        }
+    }
 }
 
 /// Request packet
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum RequestPacket<'a> {
     /// Read request packet
-    ReadRequest(NetasciiString<'a>, Mode),
+    ReadRequest(RawFilename<'a>, Mode, RawOptions<'a>),
 
     /// Write request packet
-    WriteRequest(NetasciiString<'a>, Mode),
+    WriteRequest(RawFilename<'a>, Mode, RawOptions<'a>),
+}
+
+/// File name of a request as it is sent, any bytes but NUL.
+pub type RawFilename<'a> = Cow<'a, [u8]>;
+
+/// Returns the bytes of a netascii encoded file name.
+fn netascii_bytes(filename: NetasciiString) -> RawFilename {
+    match filename {
+        Cow::Borrowed(filename) => Cow::Borrowed(filename.as_bytes()),
+        Cow::Owned(filename) => Cow::Owned(filename.into_bytes()),
+    }
 }
 
 impl<'a> RequestPacket<'a> {
@@ -213,36 +567,116 @@ impl<'a> RequestPacket<'a> {
     ///
     /// Filename is converted to netascii if required.
     pub fn read_request<'b>(filename: &'b str, mode: Mode) -> RequestPacket<'b> {
-        RequestPacket::ReadRequest(to_netascii(filename), mode)
+        RequestPacket::ReadRequest(netascii_bytes(to_netascii(filename)), mode, Cow::from(""))
+    }
+
+    /// Creates a new read request for a file name that is not necessarily UTF-8,
+    /// such as a Latin-1 name on the server.
+    ///
+    /// The bytes are sent as they are, without netascii conversion. Returns `None` if
+    /// the file name contains a NUL byte, which would end it early.
+    pub fn read_request_bytes<'b>(filename: &'b [u8], mode: Mode) -> Option<RequestPacket<'b>> {
+        if filename.contains(&0) {
+            return None
+        }
+        Some(RequestPacket::ReadRequest(Cow::from(filename), mode, Cow::from("")))
+    }
+
+    /// Creates a new read request carrying the given options (RFC 2347).
+    ///
+    /// Filename is converted to netascii if required.
+    pub fn read_request_with_options<'b, K, V>(filename: &'b str, mode: Mode, options: &[(K, V)]) -> RequestPacket<'b>
+        where K: AsRef<str>, V: AsRef<str>
+    {
+        RequestPacket::ReadRequest(netascii_bytes(to_netascii(filename)), mode, encode_options(options))
     }
 
     /// Create a new write request.
     ///
     /// Filename is converted to netascii if required.
     pub fn write_request<'b>(filename: &'b str, mode: Mode) -> RequestPacket<'b> {
-        RequestPacket::WriteRequest(to_netascii(filename), mode)
+        RequestPacket::WriteRequest(netascii_bytes(to_netascii(filename)), mode, Cow::from(""))
+    }
+
+    /// Creates a new write request carrying the given options (RFC 2347).
+    ///
+    /// Filename is converted to netascii if required.
+    pub fn write_request_with_options<'b, K, V>(filename: &'b str, mode: Mode, options: &[(K, V)]) -> RequestPacket<'b>
+        where K: AsRef<str>, V: AsRef<str>
+    {
+        RequestPacket::WriteRequest(netascii_bytes(to_netascii(filename)), mode, encode_options(options))
+    }
+
+    /// Whether the request is a read request, downloading a file from the server.
+    pub fn is_read(&self) -> bool {
+        self.opcode() == Opcode::RRQ
+    }
+
+    /// Whether the request is a write request, uploading a file to the server.
+    pub fn is_write(&self) -> bool {
+        self.opcode() == Opcode::WRQ
     }
 
     /// Returns a file name that the request is for.
     ///
-    /// If netascii encoding is invalid `None` is returned.
+    /// If the file name is not UTF-8 or its netascii encoding is invalid `None` is
+    /// returned.
     pub fn filename<'b>(&'b self) -> Option<Cow<'b, str>> {
-        from_netascii(self.filename_raw())
+        str::from_utf8(self.filename_raw()).ok().and_then(from_netascii)
     }
 
-    /// Returns a raw file name netascii encoded.
-    pub fn filename_raw(&self) -> &str {
+    /// Returns the requested path as it was sent, netascii encoded.
+    ///
+    /// Bytes that are not UTF-8 are replaced with U+FFFD.
+    pub fn path(&self) -> Cow<str> {
+        String::from_utf8_lossy(self.filename_raw())
+    }
+
+    /// Returns the bytes of the requested path as it was sent.
+    pub fn path_bytes(&self) -> &[u8] {
+        self.filename_raw()
+    }
+
+    /// Returns a raw file name as it was sent, netascii encoded unless the request
+    /// was created from bytes.
+    pub fn filename_raw(&self) -> &[u8] {
         match *self {
-            RequestPacket::ReadRequest(ref filename, _) => &filename[..],
-            RequestPacket::WriteRequest(ref filename, _) => &filename[..],
+            RequestPacket::ReadRequest(ref filename, _, _) => &filename[..],
+            RequestPacket::WriteRequest(ref filename, _, _) => &filename[..],
         }
     }
 
     /// Returns a transfer mode.
+    ///
+    /// Requests with a missing or unknown mode are rejected when decoding, so a
+    /// decoded request always has a valid mode.
     pub fn mode(&self) -> Mode {
         match *self {
-            RequestPacket::ReadRequest(_, mode) => mode,
-            RequestPacket::WriteRequest(_, mode) => mode
+            RequestPacket::ReadRequest(_, mode, _) => mode,
+            RequestPacket::WriteRequest(_, mode, _) => mode
+        }
+    }
+
+    /// Returns the options appended to the request in the wire format.
+    pub fn options_raw(&self) -> &str {
+        match *self {
+            RequestPacket::ReadRequest(_, _, ref options) => &options[..],
+            RequestPacket::WriteRequest(_, _, ref options) => &options[..],
+        }
+    }
+
+    /// Returns an iterator over the option name and value pairs of the request.
+    pub fn options(&self) -> OptionsIter {
+        OptionsIter::new(self.options_raw())
+    }
+
+    /// Copies borrowed fields, so the request outlives the buffer it was decoded from.
+    pub fn into_owned(self) -> RequestPacket<'static> {
+        match self {
+            RequestPacket::ReadRequest(filename, mode, options) =>
+                RequestPacket::ReadRequest(Cow::Owned(filename.into_owned()), mode, Cow::Owned(options.into_owned())),
+            RequestPacket::WriteRequest(filename, mode, options) =>
+                RequestPacket::WriteRequest(Cow::Owned(filename.into_owned()), mode, Cow::Owned(options.into_owned())),
         }
     }
 }
@@ -250,55 +684,47 @@ impl<'a> RequestPacket<'a> {
 impl<'a> Packet for RequestPacket<'a> {
     fn opcode(&self) -> Opcode {
         match *self {
-            RequestPacket::ReadRequest(_, _) => Opcode::RRQ,
-            RequestPacket::WriteRequest(_, _) => Opcode::WRQ
+            RequestPacket::ReadRequest(..) => Opcode::RRQ,
+            RequestPacket::WriteRequest(..) => Opcode::WRQ
         }
     }
 
     fn len(&self) -> usize {
-        2 + self.filename_raw().len() + 1 + self.mode().as_str().len() + 1
+        2 + self.filename_raw().len() + 1 + self.mode().as_str().len() + 1 + self.options_raw().len()
     }
 }
 
 impl<'a> DecodePacket<'a> for RequestPacket<'a> {
-    fn decode(data: &'a [u8]) -> Option<RequestPacket<'a>> {
-        let mut cur = Cursor::new(data);
-        let opcode = cur.read_u16::<BigEndian>().ok().and_then(Opcode::from_u16);
-
-        if opcode != Some(Opcode::RRQ) && opcode != Some(Opcode::WRQ) {
-            return None
+    fn decode(data: &'a [u8]) -> Result<RequestPacket<'a>, DecodeError> {
+        let opcode = try!(read_opcode(data, &[Opcode::RRQ, Opcode::WRQ]));
+        // Only the file name may be any bytes, the mode and options are text.
+        let fields = &data[2..];
+        let filename_len = try!(fields.iter().position(|&b| b == 0).ok_or(DecodeError::MissingNulTerminator));
+        let filename = Cow::from(&fields[..filename_len]);
+        let fields = try!(str::from_utf8(&fields[filename_len + 1..]).map_err(|_| DecodeError::InvalidUtf8));
+        let mut parts = fields.splitn(2, '\0');
+        let mode = try!(parts.next().ok_or(DecodeError::MissingNulTerminator));
+        // Only present if the mode is NUL terminated.
+        let options = Cow::from(try!(parts.next().ok_or(DecodeError::MissingNulTerminator)));
+        let mode = try!(Mode::from_str(mode).map_err(|_| DecodeError::InvalidMode));
+        if opcode == Opcode::RRQ {
+            Ok(RequestPacket::ReadRequest(filename, mode, options))
+        } else {
+            Ok(RequestPacket::WriteRequest(filename, mode, options))
         }
-        // FIXME
-        str::from_utf8(&data[2..]).ok().map(|s| s.split('\0')).and_then(|mut parts| {
-            let filename = parts.next().map(|s| Cow::from(s));
-            let mode = parts.next().and_then(|m| FromStr::from_str(m).ok());
-            match (filename, mode) {
-                (Some(filename), Some(mode)) => {
-                    if opcode.unwrap() == Opcode::RRQ {
-                        Some(RequestPacket::ReadRequest(filename, mode))
-                    } else {
-                        Some(RequestPacket::WriteRequest(filename, mode))
-                    }
-                }
-                _ => None
-            }
-        })
     }
 }
 
 impl<'a> EncodePacket for RequestPacket<'a> {
-    fn encode_using(&self, buf: Vec<u8>) -> RawPacket {
-        let mut b = Cursor::new(buf);
-        b.write_u16::<BigEndian>(self.opcode() as u16).unwrap();
-        b.write(self.filename_raw().as_bytes()).unwrap();
-        b.write_u8(0).unwrap();
-        b.write(self.mode().as_str().as_bytes()).unwrap();
-        b.write_u8(0).unwrap();
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        let len = try!(check_len(self.len(), buf));
+        let mut b = FieldWriter::new(buf);
+        b.write_u16(self.opcode() as u16);
+        b.write_str(self.filename_raw());
+        b.write_str(self.mode().as_str().as_bytes());
+        b.write(self.options_raw().as_bytes());
 
-        RawPacket {
-            buf: b.into_inner(),
-            len: self.len()
-        }
+        Ok(len)
     }
 }
 
@@ -316,6 +742,12 @@ impl AckPacket {
         }
     }
 
+    /// Encodes the acknowledgment on the stack.
+    pub fn encode_into_array(&self) -> [u8; 4] {
+        let opcode = Opcode::ACK as u16;
+        [(opcode >> 8) as u8, opcode as u8, (self.block_id >> 8) as u8, self.block_id as u8]
+    }
+
     /// Returns the block number that this acknowledgment is for.
     pub fn block_id(&self) -> u16 {
         self.block_id
@@ -331,30 +763,28 @@ impl Packet for AckPacket {
 }
 
 impl<'a> DecodePacket<'a> for AckPacket {
-    fn decode(data: &'a [u8]) -> Option<AckPacket> {
-        let mut cur = Cursor::new(data);
-        let opcode = cur.read_u16::<BigEndian>().ok().and_then(Opcode::from_u16);
-        match opcode {
-            Some(Opcode::ACK) => cur.read_u16::<BigEndian>().ok().map(AckPacket::new),
-            _ => None
-        }
+    fn decode(data: &'a [u8]) -> Result<AckPacket, DecodeError> {
+        try!(read_opcode(data, &[Opcode::ACK]));
+        read_u16(data, 2).map(AckPacket::new)
     }
 }
 
 impl EncodePacket for AckPacket {
-    fn encode_using(&self, buf: Vec<u8>) -> RawPacket {
-        let mut b = Cursor::new(buf);
-        b.write_u16::<BigEndian>(Opcode::ACK as u16).unwrap();
-        b.write_u16::<BigEndian>(self.block_id).unwrap();
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        let len = try!(check_len(self.len(), buf));
+        let mut b = FieldWriter::new(buf);
+        b.write_u16(Opcode::ACK as u16);
+        b.write_u16(self.block_id);
 
-        RawPacket{
-            buf: b.into_inner(),
-            len: self.len()
-        }
+        Ok(len)
     }
 }
 
 /// Data packet using octet encoding
+///
+/// The packet keeps the block id apart from the payload, the 4 byte header is only
+/// written when the packet is encoded. `data` holds the payload in its first `len`
+/// bytes, a buffer from `from_vec` may be larger.
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct DataPacketOctet<'a> {
     block_id: u16,
@@ -391,6 +821,15 @@ impl<'a> DataPacketOctet<'a> {
         &self.data[..self.len]
     }
 
+    /// Returns the payload for changing it in place, such as decrypting it before
+    /// it is written.
+    ///
+    /// The header is not part of it, the block id stays the same. A packet created
+    /// with `from_slice` copies the slice first, the slice itself is not changed.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data.to_mut()[..self.len]
+    }
+
     /// Tries to move the buffer out of this object and returns it, consuming the `RawPacket`.
     ///
     /// Returns `None` if contained buffer is a slice.
@@ -402,6 +841,13 @@ impl<'a> DataPacketOctet<'a> {
     }
 }
 
+/// Shows the block id and the number of bytes, `DATA block 3 (512 bytes)`.
+impl<'a> fmt::Display for DataPacketOctet<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DATA block {} ({} bytes)", self.block_id, self.data().len())
+    }
+}
+
 impl<'a> Packet for DataPacketOctet<'a> {
     fn opcode(&self) -> Opcode {
         Opcode::DATA
@@ -413,33 +859,24 @@ impl<'a> Packet for DataPacketOctet<'a> {
 }
 
 impl<'a> DecodePacket<'a> for DataPacketOctet<'static> {
-    fn decode(data: &'a [u8]) -> Option<DataPacketOctet<'static>> {
-        let mut cur = Cursor::new(data);
-        let opcode = cur.read_u16::<BigEndian>().ok().and_then(Opcode::from_u16);
-        match opcode {
-            Some(Opcode::DATA) => {
-                cur.read_u16::<BigEndian>().ok().map(|block_id| {
-                    let payload = data[4..].to_vec();
-                    let len = payload.len();
-                    DataPacketOctet::from_vec(block_id, payload, len)
-                })
-            }
-            _ => None
-        }
+    fn decode(data: &'a [u8]) -> Result<DataPacketOctet<'static>, DecodeError> {
+        try!(read_opcode(data, &[Opcode::DATA]));
+        let block_id = try!(read_u16(data, 2));
+        let payload = data[4..].to_vec();
+        let len = payload.len();
+        Ok(DataPacketOctet::from_vec(block_id, payload, len))
     }
 }
 
 impl<'a> EncodePacket for DataPacketOctet<'a> {
-    fn encode_using(&self, buf: Vec<u8>) -> RawPacket {
-        let mut b = Cursor::new(buf);
-        b.write_u16::<BigEndian>(Opcode::DATA as u16).unwrap();
-        b.write_u16::<BigEndian>(self.block_id).unwrap();
-        b.write(&self.data[..self.len]).unwrap();
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        let len = try!(check_len(self.len(), buf));
+        let mut b = FieldWriter::new(buf);
+        b.write_u16(Opcode::DATA as u16);
+        b.write_u16(self.block_id);
+        b.write(&self.data[..self.len]);
 
-        RawPacket {
-            buf: b.into_inner(),
-            len: self.len()
-        }
+        Ok(len)
     }
 }
 
@@ -447,18 +884,33 @@ impl<'a> EncodePacket for DataPacketOctet<'a> {
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct ErrorPacket<'a> {
     error: Error,
-    message: NetasciiString<'a>,
+    /// Netascii encoded message as it was sent, not necessarily UTF-8.
+    message: Cow<'a, [u8]>,
 }
 
+/// Shows the error code, its meaning and the message, `error 1 (file not found): no such file`.
+///
+/// Bytes of the message that are not UTF-8 are replaced with U+FFFD.
 impl<'a> fmt::Display for ErrorPacket<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}: {}", self.error, self.message)
+        let lossy = String::from_utf8_lossy(&self.message);
+        let message = from_netascii(&lossy).unwrap_or(Cow::from(&lossy[..]));
+        write!(f, "error {} ({}): {}", self.error as u16, self.error, message)
+    }
+}
+
+/// An I/O error of the kind matching the error code, described by the packet.
+#[cfg(feature = "std")]
+impl<'a, 'b> From<&'a ErrorPacket<'b>> for io::Error {
+    fn from(packet: &'a ErrorPacket<'b>) -> io::Error {
+        io::Error::new(io::ErrorKind::from(packet.error), packet.to_string())
     }
 }
 
+#[cfg(feature = "std")]
 impl<'a> error::Error for ErrorPacket<'a> {
     fn description(&self) -> &str {
-        &self.message
+        str::from_utf8(&self.message).unwrap_or("TFTP error with a message that is not UTF-8")
     }
 }
 
@@ -468,7 +920,7 @@ impl<'a> ErrorPacket<'a> {
     pub fn new(error: Error, msg: &'a str) -> ErrorPacket<'a> {
         ErrorPacket{
             error: error,
-            message: to_netascii(msg)
+            message: netascii_bytes(to_netascii(msg))
         }
     }
 
@@ -476,8 +928,23 @@ impl<'a> ErrorPacket<'a> {
         self.error
     }
 
+    /// Returns the message, `None` if it is not UTF-8 or its netascii encoding is
+    /// invalid.
     pub fn message(&'a self) -> Option<Cow<'a, str>> {
-        from_netascii(&self.message[..])
+        str::from_utf8(&self.message).ok().and_then(from_netascii)
+    }
+
+    /// Returns the bytes of the message as it was sent, netascii encoded.
+    pub fn message_bytes(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// Converts the packet into one that owns its message.
+    pub fn into_owned(self) -> ErrorPacket<'static> {
+        ErrorPacket {
+            error: self.error,
+            message: Cow::Owned(self.message.into_owned()),
+        }
     }
 }
 
@@ -492,38 +959,91 @@ impl<'a> Packet for ErrorPacket<'a> {
 }
 
 impl<'a> DecodePacket<'a> for ErrorPacket<'a> {
-    fn decode(data: &'a [u8]) -> Option<ErrorPacket<'a>> {
-        let mut cur = Cursor::new(data);
-        let opcode = cur.read_u16::<BigEndian>().ok().and_then(Opcode::from_u16);
-        match opcode {
-            Some(Opcode::ERROR) => {
-                let error = cur.read_u16::<BigEndian>().ok().and_then(Error::from_u16);
-                // FIXME
-                let msg = str::from_utf8(&data[4..]).ok().map(|s| s.split('\0'))
-                                                            .and_then(|mut i| i.next());
-                match (error, msg) {
-                    (Some(error), Some(msg)) => Some(ErrorPacket::new(error, msg)),
-                    _ => None
-                }
-            }
-            _ => None
-        }
+    fn decode(data: &'a [u8]) -> Result<ErrorPacket<'a>, DecodeError> {
+        try!(read_opcode(data, &[Opcode::ERROR]));
+        let code = try!(read_u16(data, 2));
+        let error = try!(Error::from_u16(code).ok_or(DecodeError::UnknownErrorCode(code)));
+        // Servers may send messages that are not UTF-8, the error code matters more.
+        let msg = &data[4..];
+        let msg_len = msg.iter().position(|&b| b == 0).unwrap_or(msg.len());
+        Ok(ErrorPacket {
+            error: error,
+            message: Cow::from(&msg[..msg_len]),
+        })
     }
 }
 
 impl<'a> EncodePacket for ErrorPacket<'a> {
-    fn encode_using(&self, buf: Vec<u8>) -> RawPacket {
-        let mut b = Cursor::new(buf);
-        b.write_u16::<BigEndian>(Opcode::ERROR as u16).unwrap();
-        b.write_u16::<BigEndian>(self.error  as u16).unwrap();
-        b.write(&self.message.as_bytes()).unwrap();
-        b.write_u8(0).unwrap();
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        let len = try!(check_len(self.len(), buf));
+        let mut b = FieldWriter::new(buf);
+        b.write_u16(Opcode::ERROR as u16);
+        b.write_u16(self.error as u16);
+        b.write_str(&self.message);
 
-        RawPacket{
-            buf: b.into_inner(),
-            len: self.len()
+        Ok(len)
+    }
+}
+
+/// Option acknowledgment packet (RFC 2347)
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct OackPacket<'a> {
+    options: RawOptions<'a>,
+}
+
+impl<'a> OackPacket<'a> {
+    /// Creates an option acknowledgment for the given option name and value pairs.
+    pub fn new<K: AsRef<str>, V: AsRef<str>>(options: &[(K, V)]) -> OackPacket<'static> {
+        OackPacket {
+            options: encode_options(options),
         }
     }
+
+    /// Returns an iterator over the acknowledged option name and value pairs.
+    pub fn options(&self) -> OptionsIter {
+        OptionsIter::new(&self.options)
+    }
+
+    /// Copies the options if required, detaching the packet from the decoded buffer.
+    pub fn into_owned(self) -> OackPacket<'static> {
+        OackPacket {
+            options: Cow::Owned(self.options.into_owned()),
+        }
+    }
+
+    /// Returns the value of an acknowledged option, ignoring the case of its name.
+    pub fn option(&self, name: &str) -> Option<&str> {
+        self.options().find(|&(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+    }
+}
+
+impl<'a> Packet for OackPacket<'a> {
+    fn opcode(&self) -> Opcode {
+        Opcode::OACK
+    }
+
+    fn len(&self) -> usize {
+        2 + self.options.len()
+    }
+}
+
+impl<'a> DecodePacket<'a> for OackPacket<'a> {
+    fn decode(data: &'a [u8]) -> Result<OackPacket<'a>, DecodeError> {
+        try!(read_opcode(data, &[Opcode::OACK]));
+        let options = try!(str::from_utf8(&data[2..]).map_err(|_| DecodeError::InvalidUtf8));
+        Ok(OackPacket { options: Cow::from(options) })
+    }
+}
+
+impl<'a> EncodePacket for OackPacket<'a> {
+    fn encode_into(&self, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        let len = try!(check_len(self.len(), buf));
+        let mut b = FieldWriter::new(buf);
+        b.write_u16(Opcode::OACK as u16);
+        b.write(self.options.as_bytes());
+
+        Ok(len)
+    }
 }
 
 /// A Trivial File Transfer Protocol encoded packet.
@@ -552,13 +1072,21 @@ impl RawPacket {
     ///
     /// Return `None` if read opcode value is unknown.
     pub fn opcode(&self) -> Option<Opcode> {
-        self.packet_buf().read_u16::<BigEndian>().ok().and_then(Opcode::from_u16)
+        self.try_opcode().ok()
+    }
+
+    /// Returns opcode of an encoded packet, or the unknown value that was read.
+    ///
+    /// A packet too short to hold an opcode is reported as opcode 0.
+    pub fn try_opcode(&self) -> Result<Opcode, UnknownOpcodeError> {
+        let opcode = read_u16(self.packet_buf(), 0).unwrap_or(0);
+        Opcode::try_from(opcode)
     }
 
     /// Decode a packet of specified type.
     ///
-    /// Returns `None` if the packet can't be decoded to a required type.
-    pub fn decode<'a, P: Packet + DecodePacket<'a>>(&'a self) -> Option<P> {
+    /// Returns an error if the packet can't be decoded to a required type.
+    pub fn decode<'a, P: Packet + DecodePacket<'a>>(&'a self) -> Result<P, DecodeError> {
         DecodePacket::decode(self.packet_buf())
     }
 
@@ -567,6 +1095,34 @@ impl RawPacket {
         self.len
     }
 
+    /// Whether the packet has no bytes at all, which no encoded packet does.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the bytes of the packet, without the unused part of the buffer.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.packet_buf()
+    }
+
+    /// Returns the bytes following the 4 byte header of DATA, ACK and ERROR packets.
+    ///
+    /// For a DATA packet that is the block, for an ERROR packet the message. Requests
+    /// and OACKs have a 2 byte header, their payload starts at `as_bytes()[2..]`.
+    /// A packet shorter than the header has an empty payload.
+    pub fn payload(&self) -> &[u8] {
+        self.as_bytes().get(TFTP_HEADER_LEN..).unwrap_or(&[])
+    }
+
+    /// Returns the bytes of the packet, consuming the `RawPacket`.
+    ///
+    /// Unlike `get_buffer`, the buffer is truncated to the packet and not zeroed.
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut buffer = self.buf;
+        buffer.truncate(self.len);
+        buffer
+    }
+
     /// Moves the buffer out of this object and returns it, consuming the `RawPacket`.
     ///
     /// This method should be used for maximal buffer reuse. Memory is zeroed before returning.
@@ -584,16 +1140,21 @@ impl RawPacket {
 mod test {
     extern crate quickcheck;
     extern crate rand;
+    #[cfg(feature = "serde")]
+    extern crate serde_json;
 
     use std::borrow::Cow;
-    use std::convert::From;
+    use std::convert::{From, TryFrom};
+    use std::env;
+    use std::io;
 
     use self::rand::Rng;
-    use self::quickcheck::{quickcheck, Arbitrary, Gen};
+    use self::quickcheck::{quickcheck, Arbitrary, Gen, QuickCheck};
 
-    use super::{Mode, Error, EncodePacket, DecodePacket};
+    use super::{Mode, Error, Packet, EncodePacket, DecodePacket};
     use super::{RequestPacket, AckPacket, DataPacketOctet,
-                ErrorPacket};
+                ErrorPacket, OackPacket, RawPacket, Opcode, UnknownOpcodeError, EncodeError,
+                DecodeError, compute_block_count, recv_buffer_size, split_into_blocks};
 
     impl Arbitrary for RequestPacket<'static> {
         fn arbitrary<G: Gen>(g: &mut G) -> RequestPacket<'static> {
@@ -601,9 +1162,9 @@ mod test {
             let str_len = g.gen_range(0usize, 50);
             let filename: String = g.gen_ascii_chars().take(str_len).collect();
             if g.gen() {
-                RequestPacket::ReadRequest(Cow::from(filename), transfer_type)
+                RequestPacket::ReadRequest(Cow::from(filename.into_bytes()), transfer_type, Cow::from(""))
             } else {
-                RequestPacket::WriteRequest(Cow::from(filename), transfer_type)
+                RequestPacket::WriteRequest(Cow::from(filename.into_bytes()), transfer_type, Cow::from(""))
             }
         }
     }
@@ -630,11 +1191,79 @@ mod test {
             let message: String = g.gen_ascii_chars().take(msg_len).collect();
             ErrorPacket{
                 error: error,
-                message: Cow::from(message)
+                message: Cow::from(message.into_bytes())
             }
         }
     }
 
+    #[test]
+    fn receive_buffer_holds_header_and_block() {
+        assert_eq!(516, recv_buffer_size(512));
+        let packet = DataPacketOctet::from_slice(1, &[0; 1468]).encode();
+        assert_eq!(recv_buffer_size(1468), packet.len());
+    }
+
+    #[test]
+    fn known_opcode_is_converted() {
+        assert_eq!(Ok(Opcode::DATA), Opcode::try_from(3u16));
+    }
+
+    #[test]
+    fn unknown_opcode_is_reported() {
+        assert_eq!(Err(UnknownOpcodeError(99)), Opcode::try_from(99u16));
+        assert_eq!("unknown TFTP opcode: 99", UnknownOpcodeError(99).to_string());
+    }
+
+    #[test]
+    fn raw_packet_with_unknown_opcode_is_reported() {
+        let raw_packet = RawPacket::new(vec![0, 99, 0, 1], 4);
+        assert_eq!(None, raw_packet.opcode());
+        assert_eq!(Err(UnknownOpcodeError(99)), raw_packet.try_opcode());
+    }
+
+    #[test]
+    fn read_request_path_and_mode_are_decoded() {
+        let bytes = b"\x00\x01firmware.bin\x00octet\x00";
+        let raw_packet = RawPacket::new(bytes.to_vec(), bytes.len());
+        let packet: RequestPacket = raw_packet.decode().expect("expected a request");
+        assert_eq!("firmware.bin", packet.path());
+        assert_eq!(b"firmware.bin", packet.path_bytes());
+        assert_eq!(Mode::Octet, packet.mode());
+    }
+
+    #[test]
+    fn read_request_with_latin1_filename_is_sent_as_is() {
+        let packet = RequestPacket::read_request_bytes(b"caf\xe9\r\n.bin", Mode::Octet).unwrap();
+        let encoded = packet.encode();
+        assert_eq!(&b"\x00\x01caf\xe9\r\n.bin\x00octet\x00"[..], encoded.packet_buf());
+
+        let decoded: RequestPacket = encoded.decode().expect("expected a request");
+        assert_eq!(b"caf\xe9\r\n.bin", decoded.path_bytes());
+        assert_eq!("caf\u{fffd}\r\n.bin", decoded.path());
+        assert_eq!(None, decoded.filename());
+        assert_eq!(Mode::Octet, decoded.mode());
+    }
+
+    #[test]
+    fn read_request_bytes_rejects_nul() {
+        assert_eq!(None, RequestPacket::read_request_bytes(b"fi\0le", Mode::Octet));
+        assert!(RequestPacket::read_request_bytes(b"", Mode::Octet).is_some());
+    }
+
+    #[test]
+    fn request_without_terminated_path_is_rejected() {
+        let bytes = b"\x00\x01firmware.bin";
+        let raw_packet = RawPacket::new(bytes.to_vec(), bytes.len());
+        assert_eq!(Err(DecodeError::MissingNulTerminator), raw_packet.decode::<RequestPacket>());
+    }
+
+    #[test]
+    fn request_without_terminated_mode_is_rejected() {
+        let bytes = b"\x00\x01firmware.bin\x00octet";
+        let raw_packet = RawPacket::new(bytes.to_vec(), bytes.len());
+        assert_eq!(Err(DecodeError::MissingNulTerminator), raw_packet.decode::<RequestPacket>());
+    }
+
     #[test]
     fn packet_read_request_with_escape_is_encoded() {
         let packet = RequestPacket::read_request("foo", Mode::Octet);
@@ -659,6 +1288,26 @@ mod test {
         assert_eq!(expected, raw_packet.packet_buf());
     }
 
+    #[test]
+    fn write_request_is_encoded_with_the_wrq_opcode() {
+        let packet = RequestPacket::write_request("file", Mode::Octet);
+        assert!(packet.is_write());
+        assert!(!RequestPacket::read_request("file", Mode::Octet).is_write());
+        assert_eq!(&[0, 2, b'f', b'i', b'l', b'e', 0, b'o', b'c', b't', b'e', b't', 0][..],
+                   packet.encode().packet_buf());
+        assert!(RequestPacket::decode(packet.encode().packet_buf()).unwrap().is_write());
+    }
+
+    #[test]
+    fn raw_write_request_is_decoded_as_a_write() {
+        let raw = RawPacket::new(vec![0, 2, b'f', b'o', b'o', 0, b'o', b'c', b't', b'e', b't', 0], 12);
+        let packet: RequestPacket = raw.decode().unwrap();
+        assert!(packet.is_write());
+        assert!(!packet.is_read());
+        assert_eq!("foo", packet.path());
+        assert!(RequestPacket::read_request("foo", Mode::Octet).is_read());
+    }
+
     #[test]
     fn packet_write_request_without_escape_is_encoded() {
         let packet = RequestPacket::write_request("foo\nbar", Mode::Octet);
@@ -667,6 +1316,55 @@ mod test {
         assert_eq!(expected, raw_packet.packet_buf());
     }
 
+    #[test]
+    fn read_request_with_options_is_encoded() {
+        let packet = RequestPacket::read_request_with_options("foo", Mode::Octet, &[("blksize", "1024")]);
+        let raw_packet = packet.encode();
+        let expected = b"\x00\x01foo\0octet\0blksize\01024\0";
+        assert_eq!(&expected[..], raw_packet.packet_buf());
+        assert_eq!(vec![("blksize", "1024")], packet.options().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn read_request_with_two_options_is_encoded() {
+        let options = [("blksize", "1468"), ("windowsize", "8")];
+        let packet = RequestPacket::read_request_with_options("boot/pxe.0", Mode::Octet, &options);
+        let expected = b"\x00\x01boot/pxe.0\0octet\0blksize\01468\0windowsize\08\0";
+        assert_eq!(&expected[..], packet.encode().packet_buf());
+    }
+
+    #[test]
+    fn read_request_options_are_decoded() {
+        let bytes = b"\x00\x01file\x00octet\x00blksize\x001468\x00tsize\x000\x00";
+        let raw_packet = RawPacket::new(bytes.to_vec(), bytes.len());
+        let packet: RequestPacket = raw_packet.decode().expect("expected a request");
+        assert_eq!(vec![("blksize", "1468"), ("tsize", "0")], packet.options().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn read_request_without_options_has_no_options() {
+        let bytes = b"\x00\x01file\x00octet\x00";
+        let raw_packet = RawPacket::new(bytes.to_vec(), bytes.len());
+        let packet: RequestPacket = raw_packet.decode().expect("expected a request");
+        assert_eq!(0, packet.options().count());
+    }
+
+    #[test]
+    fn incomplete_option_pair_is_not_yielded() {
+        let bytes = b"\x00\x01file\x00octet\x00blksize\x001468\x00tsize\x00";
+        let raw_packet = RawPacket::new(bytes.to_vec(), bytes.len());
+        let packet: RequestPacket = raw_packet.decode().expect("expected a request");
+        assert_eq!(vec![("blksize", "1468")], packet.options().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn unterminated_option_value_is_not_yielded() {
+        let bytes = b"\x00\x01file\x00octet\x00blksize\x001468";
+        let raw_packet = RawPacket::new(bytes.to_vec(), bytes.len());
+        let packet: RequestPacket = raw_packet.decode().expect("expected a request");
+        assert_eq!(0, packet.options().count());
+    }
+
     #[test]
     fn request_packet_with_netascii_mode_is_encoded() {
         let packet = RequestPacket::read_request("na", Mode::NetAscii);
@@ -678,7 +1376,7 @@ mod test {
     #[test]
     fn encoding_and_decoding_request_packet_is_identity() {
         fn prop(packet: RequestPacket<'static>)  -> bool {
-            Some(packet.clone()) == packet.encode().decode()
+            Ok(packet.clone()) == packet.encode().decode()
         }
         quickcheck(prop as fn(RequestPacket<'static>) -> bool)
     }
@@ -691,14 +1389,49 @@ mod test {
         assert_eq!(&expected[..], raw_packet.packet_buf());
     }
 
+    #[test]
+    fn encoded_packets_are_not_empty() {
+        assert!(!AckPacket::new(1).is_empty());
+        assert!(!DataPacketOctet::from_slice(1, &[]).is_empty());
+    }
+
     #[test]
     fn encoding_and_decoding_packet_ack_is_identity() {
         fn prop(packet: AckPacket) -> bool {
-            Some(packet) == packet.encode().decode()
+            Ok(packet) == packet.encode().decode()
         }
         quickcheck(prop as fn(AckPacket) -> bool)
     }
 
+    #[test]
+    fn packet_ack_is_encoded_into_array() {
+        assert_eq!([0, 4, 0, 5], AckPacket::new(5).encode_into_array());
+        assert_eq!([0, 4, 0x12, 0x34], AckPacket::new(0x1234).encode_into_array());
+    }
+
+    #[test]
+    fn packet_ack_is_encoded_into_slice() {
+        let mut buf = [0xff; 8];
+        assert_eq!(Ok(4), AckPacket::new(1).encode_into(&mut buf));
+        assert_eq!([0, 4, 0, 1, 0xff, 0xff, 0xff, 0xff], buf);
+    }
+
+    #[test]
+    fn packet_data_octet_is_encoded_into_slice() {
+        let mut buf = [0; 16];
+        let packet = DataPacketOctet::from_slice(10, &[1, 2, 3]);
+        assert_eq!(Ok(7), packet.encode_into(&mut buf));
+        assert_eq!(&[0, 3, 0, 10, 1, 2, 3], &buf[..7]);
+    }
+
+    #[test]
+    fn encoding_into_too_small_slice_fails() {
+        let mut buf = [0; 6];
+        let packet = DataPacketOctet::from_slice(10, &[1, 2, 3]);
+        assert_eq!(Err(EncodeError::BufferTooSmall { needed: 7, available: 6 }), packet.encode_into(&mut buf));
+        assert_eq!([0; 6], buf);
+    }
+
     #[test]
     fn packet_data_octet_is_encoded() {
         let packet = DataPacketOctet::from_vec(10, vec![1u8, 2, 3, 4, 5], 5);
@@ -707,10 +1440,33 @@ mod test {
         assert_eq!(&expected[..], raw_packet.packet_buf());
     }
 
+    #[test]
+    fn received_data_is_changed_in_place() {
+        let mut packet = DataPacketOctet::decode(&[0, 3, 0, 7, 1, 2, 0x42, 0xff]).unwrap();
+        for byte in packet.data_mut() {
+            *byte ^= 0x42;
+        }
+        assert_eq!(&[0x43, 0x40, 0, 0xbd], packet.data());
+    }
+
+    #[test]
+    fn changing_data_in_place_keeps_the_header() {
+        let original = [9u8, 8, 7];
+        let mut packet = DataPacketOctet::from_slice(300, &original);
+        assert_eq!(3, packet.data_mut().len());
+        packet.data_mut()[0] = 0;
+        assert_eq!(300, packet.block_id());
+        assert_eq!(&[0, 3, 1, 44, 0, 8, 7], packet.encode().packet_buf());
+        assert_eq!([9, 8, 7], original);
+
+        let mut packet = DataPacketOctet::from_vec(1, vec![5; 10], 4);
+        assert_eq!(4, packet.data_mut().len());
+    }
+
     #[test]
     fn encoding_and_decoding_packet_data_octet_is_identity() {
         fn prop(packet: DataPacketOctet<'static>) -> bool {
-            Some(packet.clone()) == packet.encode().decode()
+            Ok(packet.clone()) == packet.encode().decode()
         }
         quickcheck(prop as fn(DataPacketOctet<'static>) -> bool)
     }
@@ -731,14 +1487,322 @@ mod test {
         assert_eq!(expected, raw_packet.packet_buf())
     }
 
+    #[test]
+    fn error_with_a_message_that_is_not_utf8_keeps_its_code() {
+        let packet = ErrorPacket::decode(b"\x00\x05\x00\x01caf\xe9 \r\nmissing\x00").unwrap();
+        assert_eq!(Error::FileNotFound, packet.error());
+        assert_eq!(None, packet.message());
+        assert_eq!(b"caf\xe9 \r\nmissing", packet.message_bytes());
+        assert_eq!("error 1 (file not found): caf\u{fffd} \nmissing", packet.to_string());
+        assert_eq!(b"\x00\x05\x00\x01caf\xe9 \r\nmissing\x00", packet.encode().packet_buf());
+    }
+
+    #[test]
+    fn error_message_is_decoded_from_netascii() {
+        let packet = ErrorPacket::decode(b"\x00\x05\x00\x03disk\r\nfull\x00").unwrap();
+        assert_eq!(Some(Cow::from("disk\nfull")), packet.message());
+        assert_eq!(ErrorPacket::new(Error::DiskFull, "disk\nfull"), packet);
+    }
+
     #[test]
     fn encoding_and_decoding_packet_error_is_identity() {
         fn prop(packet: ErrorPacket<'static>) -> bool {
-            Some(packet.clone()) == packet.encode().decode()
+            Ok(packet.clone()) == packet.encode().decode()
         }
         quickcheck(prop as fn(ErrorPacket<'static>) -> bool)
     }
 
+    #[test]
+    fn truncated_headers_are_too_short() {
+        for len in 0..4 {
+            let bytes = [0, 0, 0, 0];
+            if len < 2 {
+                assert_eq!(Err(DecodeError::TooShort), RequestPacket::decode(&bytes[..len]));
+                assert_eq!(Err(DecodeError::TooShort), OackPacket::decode(&bytes[..len]));
+            }
+            assert_eq!(Err(DecodeError::TooShort), AckPacket::decode(&[0, 4, 0, 1][..len]));
+            assert_eq!(Err(DecodeError::TooShort), DataPacketOctet::decode(&[0, 3, 0, 1][..len]));
+            assert_eq!(Err(DecodeError::TooShort), ErrorPacket::decode(&[0, 5, 0, 1][..len]));
+        }
+    }
+
+    #[test]
+    fn three_byte_data_packet_is_too_short() {
+        let raw_packet = RawPacket::new(vec![0, 3, 0], 3);
+        assert_eq!(Some(Opcode::DATA), raw_packet.opcode());
+        assert_eq!(Err(DecodeError::TooShort), raw_packet.decode::<DataPacketOctet>());
+    }
+
+    #[test]
+    fn every_truncated_packet_is_decoded_without_panicking() {
+        let packets = vec![
+            RequestPacket::read_request_with_options("file", Mode::Octet, &[("blksize", "1024")]).encode(),
+            AckPacket::new(1).encode(),
+            DataPacketOctet::from_slice(1, &[1, 2, 3]).encode(),
+            ErrorPacket::new(Error::FileNotFound, "missing").encode(),
+            OackPacket::new(&[("tsize", "42")]).encode(),
+        ];
+        for packet in packets {
+            let bytes = packet.packet_buf();
+            for len in 0..bytes.len() {
+                let _ = RequestPacket::decode(&bytes[..len]);
+                let _ = AckPacket::decode(&bytes[..len]);
+                let _ = DataPacketOctet::decode(&bytes[..len]);
+                let _ = ErrorPacket::decode(&bytes[..len]);
+                let _ = OackPacket::decode(&bytes[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_request_is_missing_a_terminator() {
+        let bytes = b"\x00\x01file\x00octet\x00";
+        for len in 2..bytes.len() {
+            assert_eq!(Err(DecodeError::MissingNulTerminator), RequestPacket::decode(&bytes[..len]));
+        }
+    }
+
+    #[test]
+    fn packet_of_another_type_has_a_bad_opcode() {
+        let ack = [0, 4, 0, 1];
+        assert_eq!(Err(DecodeError::BadOpcode(4)), DataPacketOctet::decode(&ack));
+        assert_eq!(Err(DecodeError::BadOpcode(4)), RequestPacket::decode(&ack));
+        assert_eq!(Err(DecodeError::BadOpcode(99)), AckPacket::decode(&[0, 99, 0, 1]));
+    }
+
+    #[test]
+    fn request_with_unknown_mode_is_invalid() {
+        assert_eq!(Err(DecodeError::InvalidMode), RequestPacket::decode(b"\x00\x01file\x00binary\x00"));
+    }
+
+    #[test]
+    fn mail_mode_is_not_supported() {
+        assert!("mail".parse::<Mode>().is_err());
+        assert_eq!(Err(DecodeError::InvalidMode), RequestPacket::decode(b"\x00\x02user\x00mail\x00"));
+    }
+
+    #[test]
+    fn every_mode_sent_can_be_parsed_back() {
+        for &mode in &[Mode::NetAscii, Mode::Octet] {
+            assert_eq!(mode, mode.as_str().parse::<Mode>().unwrap());
+            let packet = RequestPacket::write_request("file", mode).encode();
+            assert_eq!(mode, RequestPacket::decode(packet.packet_buf()).unwrap().mode());
+        }
+    }
+
+    #[test]
+    fn encoded_length_is_the_length_of_the_packet_bytes() {
+        assert_eq!(4, AckPacket::new(1).encode().len());
+        assert!(!RequestPacket::read_request("x", Mode::Octet).encode().is_empty());
+        assert!(RawPacket::new(vec![0; 16], 0).is_empty());
+
+        let encoded = vec![
+            RequestPacket::read_request_with_options("file", Mode::Octet, &[("blksize", "1024")]).encode(),
+            RequestPacket::write_request("file", Mode::NetAscii).encode(),
+            AckPacket::new(7).encode(),
+            DataPacketOctet::from_slice(3, &[1; 100]).encode(),
+            ErrorPacket::new(Error::DiskFull, "full").encode(),
+            OackPacket::new(&[("tsize", "10")]).encode(),
+        ];
+        for packet in &encoded {
+            assert_eq!(packet.packet_buf().len(), packet.len());
+            assert!(!packet.is_empty());
+        }
+    }
+
+    #[test]
+    fn data_is_split_into_blocks() {
+        let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        assert_eq!(vec![(1, &data[..512]), (2, &data[512..])], split_into_blocks(&data, 512).collect::<Vec<_>>());
+        assert_eq!(vec![(1, &data[..1000]), (2, &data[1000..])],
+                   split_into_blocks(&data, 1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn empty_data_is_a_single_empty_block() {
+        assert_eq!(vec![(1, &[][..])], split_into_blocks(&[], 512).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn block_ids_wrap_around_to_0() {
+        let data = vec![0; 65535 * 512 + 1];
+        let mut blocks = split_into_blocks(&data, 512).skip(65534);
+        assert_eq!(Some((65535, &[0; 512][..])), blocks.next());
+        assert_eq!(Some((0, &[0][..])), blocks.next());
+        assert_eq!(None, blocks.next());
+    }
+
+    #[test]
+    fn block_count_includes_the_empty_final_block() {
+        assert_eq!(1, compute_block_count(0, 512));
+        assert_eq!(2, compute_block_count(512, 512));
+        assert_eq!(2, compute_block_count(513, 512));
+        assert_eq!(3, compute_block_count(1024, 512));
+        assert_eq!(2, compute_block_count(1500, 1428));
+    }
+
+    #[test]
+    fn error_codes_map_to_io_error_kinds() {
+        assert_eq!(io::ErrorKind::NotFound, io::ErrorKind::from(Error::FileNotFound));
+        assert_eq!(io::ErrorKind::PermissionDenied, io::ErrorKind::from(Error::AccessViolation));
+        assert_eq!(io::ErrorKind::StorageFull, io::ErrorKind::from(Error::DiskFull));
+        assert_eq!(io::ErrorKind::AlreadyExists, io::ErrorKind::from(Error::FileAlreadyExists));
+        assert_eq!(io::ErrorKind::InvalidInput, io::ErrorKind::from(Error::IllegalOperation));
+        for &error in &[Error::Undefined, Error::UnknownTransferId, Error::NoSuchUser] {
+            assert_eq!(io::ErrorKind::Other, io::ErrorKind::from(error));
+        }
+    }
+
+    #[test]
+    fn error_packet_converts_to_an_io_error() {
+        let packet = ErrorPacket::new(Error::FileNotFound, "no such file");
+        let err = io::Error::from(&packet);
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        assert_eq!("error 1 (file not found): no such file", err.to_string());
+    }
+
+    #[test]
+    fn packets_are_displayed_for_diagnostics() {
+        assert_eq!("DATA (3)", Opcode::DATA.to_string());
+        assert_eq!("DATA", format!("{:?}", Opcode::DATA));
+        assert_eq!("DATA block 3 (512 bytes)", DataPacketOctet::from_slice(3, &[0; 512]).to_string());
+        assert_eq!("error 1 (file not found): no such\nfile",
+                   ErrorPacket::new(Error::FileNotFound, "no such\nfile").to_string());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn modes_are_serialized_as_their_wire_names() {
+        assert_eq!("\"octet\"", serde_json::to_string(&Mode::Octet).unwrap());
+        assert_eq!("\"netascii\"", serde_json::to_string(&Mode::NetAscii).unwrap());
+        assert_eq!(Mode::Octet, serde_json::from_str::<Mode>("\"octet\"").unwrap());
+        assert_eq!(Mode::NetAscii, Mode::try_from("netascii").unwrap());
+        assert!(serde_json::from_str::<Mode>("\"mail\"").is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn error_codes_are_serialized_as_numbers() {
+        assert_eq!("1", serde_json::to_string(&Error::FileNotFound).unwrap());
+        assert_eq!(Error::NoSuchUser, serde_json::from_str::<Error>("7").unwrap());
+        assert!(serde_json::from_str::<Error>("8").is_err());
+    }
+
+    /// Decodes `data` as every type of packet, using whatever decodes and encoding it
+    /// again. Malformed input must be rejected with a `DecodeError`, never panic.
+    fn decode_as_any_packet(data: &[u8]) {
+        let raw = RawPacket::new(data.to_vec(), data.len());
+        let _ = raw.try_opcode();
+        if let Ok(packet) = RequestPacket::decode(data) {
+            let _ = (packet.filename(), packet.mode(), packet.options().count());
+            packet.encode();
+        }
+        if let Ok(packet) = AckPacket::decode(data) {
+            packet.encode();
+        }
+        if let Ok(packet) = DataPacketOctet::decode(data) {
+            let _ = packet.data().len();
+            packet.encode();
+        }
+        if let Ok(packet) = ErrorPacket::decode(data) {
+            let _ = packet.message();
+            packet.encode();
+        }
+        if let Ok(packet) = OackPacket::decode(data) {
+            let _ = (packet.options().count(), packet.option("blksize"));
+            packet.encode();
+        }
+    }
+
+    /// Number of random inputs tried by the decoder fuzz tests, 1000 unless set by the
+    /// `TFTP_FUZZ_TESTS` environment variable for longer runs.
+    fn fuzz_tests() -> usize {
+        env::var("TFTP_FUZZ_TESTS").ok().and_then(|tests| tests.parse().ok()).unwrap_or(1000)
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic_when_decoded() {
+        fn prop(data: Vec<u8>) -> bool {
+            decode_as_any_packet(&data);
+            true
+        }
+        QuickCheck::new().tests(fuzz_tests()).quickcheck(prop as fn(Vec<u8>) -> bool)
+    }
+
+    #[test]
+    fn arbitrary_fields_never_panic_when_decoded() {
+        // Random bytes rarely get past the opcode and UTF-8 checks, so build packets
+        // from a known opcode and NUL separated strings to reach the field parsing.
+        fn prop(opcode: u8, code: Option<u8>, fields: Vec<String>) -> bool {
+            let mut data = vec![0, opcode % 8];
+            // Block numbers and error codes come before the strings.
+            if let Some(code) = code {
+                data.extend_from_slice(&[0, code % 10]);
+            }
+            data.extend_from_slice(fields.join("\0").as_bytes());
+            decode_as_any_packet(&data);
+            decode_as_any_packet(&data[..data.len() - data.len() / 2]);
+            true
+        }
+        QuickCheck::new().tests(fuzz_tests()).quickcheck(prop as fn(u8, Option<u8>, Vec<String>) -> bool)
+    }
+
+    #[test]
+    fn truncated_packets_are_rejected() {
+        let packets = vec![
+            RequestPacket::read_request_with_options("file", Mode::Octet, &[("blksize", "1024")]).encode(),
+            DataPacketOctet::from_slice(1, &[1, 2, 3]).encode(),
+            ErrorPacket::new(Error::FileNotFound, "missing").encode(),
+            OackPacket::new(&[("tsize", "10")]).encode(),
+            AckPacket::new(1).encode(),
+        ];
+        for packet in &packets {
+            let bytes = packet.packet_buf();
+            for len in 0..bytes.len() {
+                decode_as_any_packet(&bytes[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn error_with_unknown_code_is_rejected() {
+        assert_eq!(Err(DecodeError::UnknownErrorCode(42)), ErrorPacket::decode(b"\x00\x05\x00\x2aoops\x00"));
+    }
+
+    #[test]
+    fn packet_oack_is_encoded() {
+        let packet = OackPacket::new(&[("blksize", "1468"), ("tsize", "42")]);
+        let raw_packet = packet.encode();
+        let expected = b"\x00\x06blksize\x001468\x00tsize\x0042\x00";
+        assert_eq!(&expected[..], raw_packet.packet_buf());
+        assert_eq!(Ok(packet), raw_packet.decode());
+    }
+
+    #[test]
+    fn raw_packet_bytes_exclude_unused_buffer() {
+        let mut buf = vec![0; 516];
+        buf[..4].copy_from_slice(&[0, 4, 0, 9]);
+        let raw_packet = RawPacket::new(buf, 4);
+        assert_eq!(4, raw_packet.as_bytes().len());
+        assert_eq!(vec![0, 4, 0, 9], raw_packet.into_bytes());
+    }
+
+    #[test]
+    fn raw_packet_payload_follows_the_header() {
+        let raw_packet = DataPacketOctet::from_slice(3, &[1, 2, 3]).encode_using(vec![0; 516]);
+        assert_eq!(7, raw_packet.len());
+        assert_eq!(&[0, 3, 0, 3, 1, 2, 3][..], raw_packet.as_bytes());
+        assert_eq!(&[1, 2, 3][..], raw_packet.payload());
+        assert!(AckPacket::new(1).encode().payload().is_empty());
+        assert!(RawPacket::new(vec![0, 4, 0], 3).payload().is_empty());
+    }
+
+    #[test]
+    fn ack_into_bytes_is_four_bytes() {
+        let raw_packet = AckPacket::new(1).encode_using(vec![0; 516]);
+        assert_eq!(vec![0, 4, 0, 1], raw_packet.into_bytes());
+    }
+
     #[test]
     fn packet_buffer_is_zeroes_before_reuse() {
         let packet = AckPacket::new(1);
@@ -754,14 +1818,14 @@ mod bench {
 
     use self::test::{Bencher, black_box};
 
-    use super::{Mode, EncodePacket, Error};
+    use super::{Mode, Packet, EncodePacket, Error};
     use super::{RequestPacket, AckPacket, DataPacketOctet, ErrorPacket};
 
     #[bench]
     fn decode_read_request(b: &mut Bencher) {
         let raw_packet = RequestPacket::read_request("file", Mode::Octet).encode();
         b.iter(|| {
-            let packet: Option<RequestPacket> = raw_packet.decode();
+            let packet: Option<RequestPacket> = raw_packet.decode().ok();
             black_box(packet)
         });
         b.bytes = raw_packet.len() as u64;
@@ -781,7 +1845,7 @@ mod bench {
     fn decode_ack(b: &mut Bencher) {
         let raw_packet = AckPacket::new(1).encode();
         b.iter(|| {
-            let ack: Option<AckPacket> = raw_packet.decode();
+            let ack: Option<AckPacket> = raw_packet.decode().ok();
             black_box(ack)
         });
         b.bytes = raw_packet.len() as u64;
@@ -797,12 +1861,21 @@ mod bench {
         b.bytes = raw_packet.len() as u64;
     }
 
+    #[bench]
+    fn encode_ack_into_array(b: &mut Bencher) {
+        let packet = AckPacket::new(1);
+        b.iter(|| {
+            black_box(packet.encode_into_array())
+        });
+        b.bytes = 4;
+    }
+
     #[bench]
     fn decode_data_octet(b: &mut Bencher) {
         let data = vec![1u8; 100];
         let raw_packet = DataPacketOctet::from_slice(1, &data[..]).encode();
         b.iter(|| {
-            let ack: Option<DataPacketOctet> = raw_packet.decode();
+            let ack: Option<DataPacketOctet> = raw_packet.decode().ok();
             black_box(ack)
         });
         b.bytes = raw_packet.len() as u64;
@@ -826,14 +1899,29 @@ mod bench {
         let packet = DataPacketOctet::from_slice(1, &data[..]);
         let raw_packet = packet.encode();
 
-        b.bench_n(N as u64, |b: &mut Bencher| {
-            let mut buf = vec!(0u8; 512);
+        let mut buf = Some(vec!(0u8; 512));
+        b.iter(|| {
+            for _ in 0..N {
+                let encoded = packet.encode_using(buf.take().unwrap());
+                buf = Some(encoded.get_buffer());
+            }
+        });
+        b.bytes = (raw_packet.len() * N) as u64;
+    }
+
+    #[bench]
+    fn encode_data_octet_into_slice(b: &mut Bencher) {
+        static N: usize = 1000;
+        let data = vec![1u8; 100];
+        let packet = DataPacketOctet::from_slice(1, &data[..]);
+
+        let mut buf = [0u8; 516];
+        b.iter(|| {
             for _ in 0..N {
-                let encoded = packet.encode_using(buf);
-                buf = encoded.get_buffer();
+                black_box(packet.encode_into(&mut buf).unwrap());
             }
-            b.bytes = (raw_packet.len() * N) as u64;
         });
+        b.bytes = (packet.len() * N) as u64;
     }
 
     #[bench]
@@ -841,7 +1929,7 @@ mod bench {
         let message = "This is some error message";
         let raw_packet = ErrorPacket::new(Error::FileNotFound, message).encode();
         b.iter(|| {
-            let ack: Option<DataPacketOctet> = raw_packet.decode();
+            let ack: Option<DataPacketOctet> = raw_packet.decode().ok();
             black_box(ack)
         });
         b.bytes = raw_packet.len() as u64;
@@ -1,140 +1,1410 @@
-use std::io::{self, Cursor, Read};
-use std::convert::Into;
-use std::net::SocketAddr;
-use std::thread;
+//! A Trivial File Transfer (TFTP) protocol server implementation.
+//!
+//! This module contains the ability to serve files to remote TFTP clients. Every
+//! transfer is conducted on its own thread, from a newly bound socket, so each one
+//! gets a fresh transfer identifier as required by RFC 1350.
 
-use tokio_core::net::UdpSocket;
-use tokio_core::reactor::Core;
-use tokio_core::channel::{Receiver, channel};
-use futures::{Poll, Async};
-use futures::stream::Stream;
-use futures::Future;
+use std::error;
+use std::fmt;
+use std::io::{self, Read};
+use std::fs::{self, File};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::usize;
 
-use decodedpacket::DecodedPacket;
-use packet::{RequestPacket, RawPacket, DataPacketOctet, EncodePacket, AckPacket};
+use chrono::Utc;
 
-struct ClientRequest {
-    addr: SocketAddr,
-    request: DecodedPacket<RequestPacket<'static>>,
+use audit::{AuditEntry, AuditLog, duration_ms};
+
+use decodedpacket::AnyPacket;
+use options::OptionNegotiator;
+use packet::{RequestPacket, RawPacket, DataPacketOctet, EncodePacket, AckPacket,
+    ErrorPacket, OackPacket, Opcode, Error, recv_buffer_size};
+
+static MAX_DATA_SIZE: usize = 512;
+
+/// Largest block size that can be negotiated with the `blksize` option (RFC 2348).
+static MAX_BLOCK_SIZE: u16 = 65464;
+
+/// Largest request packet accepted by the listener.
+static MAX_REQUEST_SIZE: usize = 512;
+
+/// Longest requested path accepted, in bytes.
+static MAX_PATH_LEN: usize = 512;
+
+/// How often the listener and `graceful_shutdown` check whether the server is
+/// shutting down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Server tunables.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Maximum number of transfers served at once.
+    ///
+    /// Requests received while this many transfers are active are answered with
+    /// a "Server busy" error. Defaults to no limit.
+    pub max_clients: usize,
+
+    /// How long a transfer waits for the client to acknowledge a block before it
+    /// sends the block again, unless the client negotiated another timeout.
+    pub transfer_timeout: Duration,
+
+    /// Number of times an unacknowledged block is sent again before the transfer is
+    /// aborted.
+    pub max_retries: u32,
+
+    /// Largest number of bytes per second sent to a single client.
+    ///
+    /// The transfer waits between blocks as long as needed to stay below the rate.
+    /// Defaults to no limit, a rate of 0 means no limit as well.
+    pub max_transfer_rate: Option<u64>,
 }
 
-impl ClientRequest {
-    fn new(addr: SocketAddr, request: DecodedPacket<RequestPacket<'static>>) -> ClientRequest {
-        ClientRequest {
-            addr: addr,
-            request: request,
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            max_clients: usize::MAX,
+            transfer_timeout: Duration::from_secs(5),
+            max_retries: 5,
+            max_transfer_rate: None,
         }
     }
 }
 
-struct RequestAcceptor {
-    socket: UdpSocket,
+/// Sends a single error packet to the client.
+fn send_error(socket: &UdpSocket, addr: SocketAddr, error: Error, msg: &str) -> io::Result<()> {
+    let encoded = ErrorPacket::new(error, msg).encode();
+    socket.send_to(encoded.packet_buf(), addr).map(|_| ())
+}
+
+/// Receives a packet of any type with a buffer of `size` bytes, `None` if it can't
+/// be decoded.
+fn receive_any(socket: &UdpSocket, size: usize) -> io::Result<(Option<AnyPacket>, SocketAddr)> {
+    let mut buf = vec![0; size];
+    let (n, from) = try!(socket.recv_from(&mut buf));
+    Ok((AnyPacket::decode(RawPacket::new(buf, n)).ok(), from))
+}
+
+/// Reads from `reader` until `buf` is full or the end of the input is reached.
+fn read_block<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Settings of a single transfer, as negotiated with the client.
+#[derive(Debug, Clone, PartialEq)]
+struct Negotiated {
+    block_size: usize,
+    timeout: Duration,
+    /// Acknowledgment of the accepted options, `None` if none was accepted.
+    oack: Option<OackPacket<'static>>,
 }
 
-impl RequestAcceptor {
-    fn new(socket: UdpSocket) -> RequestAcceptor {
-        RequestAcceptor {
-            socket: socket,
+impl Negotiated {
+    /// Negotiates the options of a read request (RFC 2347).
+    ///
+    /// `blksize` is clamped to `MAX_BLOCK_SIZE` and ignored below 512 bytes,
+    /// `timeout` is accepted between 1 and 255 seconds and `tsize` is answered with
+    /// the size of the file. Invalid and unknown options are left out of the OACK.
+    fn read_request(request: &RequestPacket, file_size: u64, timeout: Duration) -> Negotiated {
+        let mut options = OptionNegotiator::from_request(request);
+        let block_size = options.negotiate_blksize(MAX_BLOCK_SIZE).map_or(MAX_DATA_SIZE, |size| size as usize);
+        let timeout = options.negotiate_timeout(1..=255).map_or(timeout, |secs| Duration::from_secs(secs as u64));
+        options.tsize(file_size);
+        Negotiated {
+            block_size: block_size,
+            timeout: timeout,
+            oack: if options.has_accepted() { Some(options.build_oack()) } else { None },
         }
     }
 }
 
-impl Stream for RequestAcceptor {
-    type Item = ClientRequest;
-    type Error = io::Error;
+/// Waits for the client at `addr` to acknowledge `block_id`, ignoring any other packet.
+///
+/// `packet` is sent again every `timeout` until it is acknowledged, at most
+/// `max_retries` times, however many other packets arrive meanwhile. Packets from
+/// another port are answered with an "Unknown transfer ID" error (RFC 1350).
+/// Returns false if the client ended the transfer with an error packet instead.
+fn wait_for_ack(socket: &UdpSocket, addr: SocketAddr, block_id: u16, packet: &[u8], timeout: Duration,
+                max_retries: u32) -> io::Result<bool> {
+    let mut retries = 0;
+    let mut deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            if retries >= max_retries {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "client did not acknowledge the block"))
+            }
+            retries += 1;
+            debug!("Block {} not acknowledged, sending it again (attempt {})", block_id, retries);
+            try!(socket.send_to(packet, addr));
+            deadline = now + timeout;
+            continue
+        }
+        try!(socket.set_read_timeout(Some(deadline - now)));
+        let mut buf = vec![0; MAX_DATA_SIZE];
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref err) if is_timeout(err) || err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if from != addr {
+            debug!("Packet from {} during the transfer to {}", from, addr);
+            if let Err(err) = send_error(socket, from, Error::UnknownTransferId, "Unknown transfer ID") {
+                debug!("Could not answer {}: {}", from, err);
+            }
+            continue
+        }
+        let packet = RawPacket::new(buf, n);
+        match packet.opcode() {
+            Some(Opcode::ACK) => match packet.decode::<AckPacket>() {
+                Ok(ref ack) if ack.block_id() == block_id => return Ok(true),
+                _ => continue,
+            },
+            Some(Opcode::ERROR) => {
+                if let Ok(error) = packet.decode::<ErrorPacket>() {
+                    debug!("Client sent {}", error);
+                }
+                return Ok(false)
+            }
+            Some(opcode) => {
+                debug!("Ignoring {} packet while waiting for the ack of block {}", opcode, block_id);
+                continue
+            }
+            None => continue,
+        }
+    }
+}
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let mut buf = vec![0; 512];
-        let (n, addr) = try_nb!(self.socket.recv_from(&mut buf));
+/// Whether `err` is the read timeout of a socket expiring, which is reported as
+/// `WouldBlock` on some platforms.
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+/// Limits the rate packets are sent at, see `ServerConfig::max_transfer_rate`.
+///
+/// Every packet takes its size in tokens from the bucket, which refills at `rate`
+/// tokens per second. A transfer that falls behind may catch up with a burst of at
+/// most one second worth of packets.
+struct TokenBucket {
+    rate: u64,
+    last_check: Instant,
+    tokens: f64,
+}
 
-        let packet: DecodedPacket<RequestPacket> = DecodedPacket::decode(RawPacket::new(buf, n)).unwrap();
-        Ok(Some(ClientRequest::new(addr, packet)).into())
+impl TokenBucket {
+    fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate,
+            last_check: Instant::now(),
+            tokens: 0.0,
+        }
+    }
+
+    /// Takes `packet_size` tokens and sleeps until the bucket is no longer in debt.
+    fn throttle(&mut self, packet_size: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_check);
+        self.last_check = now;
+        let rate = self.rate as f64;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(rate) - packet_size as f64;
+        if self.tokens < 0.0 {
+            thread::sleep(Duration::from_secs_f64(-self.tokens / rate));
+        }
     }
 }
 
-struct RequestHandler {
-    socket: UdpSocket,
-    client_request: ClientRequest,
-    data: Cursor<Vec<u8>>,
-    block_id: u16,
-    send_data: bool,
-    last_id: Option<u16>,
+/// Returns `rate` as a transfer rate limit, `None` for no limit if it is 0.
+fn nonzero_rate(rate: u64) -> Option<u64> {
+    if rate == 0 { None } else { Some(rate) }
+}
+
+/// Progress of a transfer, counting acknowledged blocks only.
+#[derive(Default)]
+struct Sent {
+    blocks: u32,
+    bytes: u64,
+}
+
+/// Sends the contents of `file` to the client at `addr`, one block at a time.
+///
+/// If any option was accepted, it is acknowledged first and the transfer only
+/// starts once the client answered the OACK with an ACK of block 0.
+///
+/// Unacknowledged blocks are sent again up to `max_retries` times of `config`, and
+/// with a `max_transfer_rate` it waits between blocks to stay below the rate. The
+/// transfer stops before the next block once `aborted` is set.
+fn send_file(socket: UdpSocket, addr: SocketAddr, mut file: File, options: Negotiated, config: &ServerConfig,
+             aborted: &AtomicBool, sent: &mut Sent) -> io::Result<()> {
+    if let Some(ref oack) = options.oack {
+        let oack = oack.encode();
+        try!(socket.send_to(oack.packet_buf(), addr));
+        if !try!(wait_for_ack(&socket, addr, 0, oack.packet_buf(), options.timeout, config.max_retries)) {
+            info!("Transfer aborted by the client");
+            return Ok(())
+        }
+    }
+
+    // Both buffers are reused for every block of the transfer.
+    let mut data = vec![0; options.block_size];
+    let mut packet = vec![0; recv_buffer_size(options.block_size)];
+    let mut block_id = 1u16;
+    let mut throttle = config.max_transfer_rate.map(TokenBucket::new);
+    loop {
+        if aborted.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "server shut down"))
+        }
+        let n = try!(read_block(&mut file, &mut data));
+        let len = DataPacketOctet::from_slice(block_id, &data[..n]).encode_into(&mut packet)
+            .expect("packet buffer holds a full block");
+        try!(socket.send_to(&packet[..len], addr));
+        if !try!(wait_for_ack(&socket, addr, block_id, &packet[..len], options.timeout,
+                                config.max_retries)) {
+            info!("Transfer aborted by the client");
+            return Ok(())
+        }
+        sent.blocks += 1;
+        sent.bytes += n as u64;
+
+        if n < options.block_size {
+            return Ok(())
+        }
+        if let Some(ref mut throttle) = throttle {
+            throttle.throttle(len);
+        }
+        block_id = block_id.wrapping_add(1);
+    }
+}
+
+/// Reason a requested path is refused by `validate_tftp_path`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum PathValidationError {
+    /// The path is the empty string.
+    Empty,
+    /// The path contains a NUL byte.
+    NulByte,
+    /// The path contains a control character other than NUL, such as a line break.
+    ControlCharacter,
+    /// The path starts with `/`.
+    Absolute,
+    /// A component of the path is `..`.
+    ParentDirectory,
+    /// The path is longer than 512 bytes.
+    TooLong,
+}
+
+impl fmt::Display for PathValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PathValidationError::Empty => write!(f, "path is empty"),
+            PathValidationError::NulByte => write!(f, "path contains a NUL byte"),
+            PathValidationError::ControlCharacter => write!(f, "path contains a control character"),
+            PathValidationError::Absolute => write!(f, "path is absolute"),
+            PathValidationError::ParentDirectory => write!(f, "path refers to a parent directory"),
+            PathValidationError::TooLong => write!(f, "path is longer than {} bytes", MAX_PATH_LEN),
+        }
+    }
+}
+
+impl error::Error for PathValidationError {
+    fn description(&self) -> &str { "invalid requested path" }
+}
+
+/// Checks a path requested by a client before it is used to access files.
+///
+/// Every read and write request is checked, with or without a server root. The empty
+/// path is rejected, it names no file.
+pub fn validate_tftp_path(path: &str) -> Result<(), PathValidationError> {
+    if path.is_empty() {
+        Err(PathValidationError::Empty)
+    } else if path.len() > MAX_PATH_LEN {
+        Err(PathValidationError::TooLong)
+    } else if path.contains('\0') {
+        Err(PathValidationError::NulByte)
+    } else if path.bytes().any(|b| b < 32) {
+        Err(PathValidationError::ControlCharacter)
+    } else if path.starts_with('/') {
+        Err(PathValidationError::Absolute)
+    } else if path.split('/').any(|component| component == "..") {
+        Err(PathValidationError::ParentDirectory)
+    } else {
+        Ok(())
+    }
 }
 
-impl RequestHandler {
-    fn new(socket: UdpSocket, client_request: ClientRequest) -> RequestHandler {
-        RequestHandler {
-            socket: socket,
-            client_request: client_request,
-            data: Cursor::new(vec![1; 1025]),
-            block_id: 1,
-            send_data: true,
-            last_id: None,
+/// Reason a requested path can't be resolved by `canonicalize_tftp_path`.
+#[derive(Debug)]
+pub enum PathError {
+    /// The path was refused by `validate_tftp_path`.
+    Invalid(PathValidationError),
+    /// No file exists at the path.
+    NotFound,
+    /// The path leads out of the root, through a symbolic link for example.
+    Traversal,
+    /// The path could not be resolved for another reason.
+    Io(io::Error),
+}
+
+impl From<io::Error> for PathError {
+    fn from(err: io::Error) -> PathError {
+        match err.kind() {
+            io::ErrorKind::NotFound => PathError::NotFound,
+            _ => PathError::Io(err),
         }
     }
 }
 
-impl Future for RequestHandler {
-    type Item = ();
-    type Error = io::Error;
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PathError::Invalid(ref err) => err.fmt(f),
+            PathError::NotFound => write!(f, "file not found"),
+            PathError::Traversal => write!(f, "path escapes the root"),
+            PathError::Io(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for PathError {
+    fn description(&self) -> &str { "requested path can't be resolved" }
+}
+
+/// Resolves the path of an existing file requested by a client inside of `root`.
+///
+/// The path is validated with `validate_tftp_path` and symbolic links are resolved, a
+/// path that ends up outside of `root` is rejected with `PathError::Traversal`.
+pub fn canonicalize_tftp_path(root: &Path, request: &str) -> Result<PathBuf, PathError> {
+    try!(validate_tftp_path(request).map_err(PathError::Invalid));
+    let root = try!(root.canonicalize());
+    let resolved = try!(root.join(request).canonicalize());
+    inside_root(&root, resolved)
+}
+
+/// Resolves the path of a file to be created by a write request inside of `root`.
+///
+/// Like `canonicalize_tftp_path`, except that the file itself does not have to exist,
+/// only its parent directory.
+fn canonicalize_tftp_write_path(root: &Path, request: &str) -> Result<PathBuf, PathError> {
+    try!(validate_tftp_path(request).map_err(PathError::Invalid));
+    let root = try!(root.canonicalize());
+    let candidate = root.join(request);
+    let resolved = match (candidate.parent(), candidate.file_name()) {
+        (Some(parent), Some(name)) => try!(parent.canonicalize()).join(name),
+        _ => return Err(PathError::Traversal),
+    };
+    inside_root(&root, resolved)
+}
+
+/// Checks that `resolved` is contained in the canonical `root`.
+fn inside_root(root: &Path, resolved: PathBuf) -> Result<PathBuf, PathError> {
+    if resolved.starts_with(root) {
+        Ok(resolved)
+    } else {
+        Err(PathError::Traversal)
+    }
+}
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+/// Counts a transfer as active for as long as it is alive.
+///
+/// The count is decremented when the guard is dropped, also when the transfer
+/// thread panics.
+struct ActiveGuard {
+    active: Arc<AtomicUsize>,
+}
+
+impl ActiveGuard {
+    fn new(active: Arc<AtomicUsize>) -> ActiveGuard {
+        active.fetch_add(1, Ordering::SeqCst);
+        ActiveGuard {
+            active: active,
+        }
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A transfer thread, and how to reach its client if it has to be cut short.
+struct RunningTransfer {
+    handle: JoinHandle<()>,
+    socket: UdpSocket,
+    client: SocketAddr,
+}
+
+/// A TFTP server.
+pub struct Server {
+    root: Option<PathBuf>,
+    config: ServerConfig,
+    active: Arc<AtomicUsize>,
+    audit_log: Option<Arc<AuditLog>>,
+    /// Socket requests are received on, once bound with `bind`.
+    socket: Option<UdpSocket>,
+    /// Set once the server stops accepting requests.
+    shutting_down: AtomicBool,
+    /// Set when the transfers still running at the end of `graceful_shutdown` must stop.
+    aborted: Arc<AtomicBool>,
+    transfers: Mutex<Vec<RunningTransfer>>,
+}
+
+impl Server {
+    /// Creates a server that serves the files of the working directory.
+    ///
+    /// Requests for paths outside of it are answered with an access violation error,
+    /// like for a server created with `with_root`.
+    pub fn new() -> Server {
+        Server {
+            root: None,
+            config: ServerConfig::default(),
+            active: Arc::new(AtomicUsize::new(0)),
+            audit_log: None,
+            socket: None,
+            shutting_down: AtomicBool::new(false),
+            aborted: Arc::new(AtomicBool::new(false)),
+            transfers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Creates a server that only serves files contained in the `root` directory.
+    ///
+    /// Requests for paths outside of `root` are answered with an access violation
+    /// error.
+    pub fn with_root(root: &Path) -> Server {
+        Server {
+            root: Some(root.to_path_buf()),
+            ..Server::new()
+        }
+    }
+
+    /// Replaces the configuration of the server.
+    pub fn with_config(mut self, mut config: ServerConfig) -> Server {
+        // Nothing could be sent at a rate of 0.
+        config.max_transfer_rate = config.max_transfer_rate.and_then(nonzero_rate);
+        self.config = config;
+        self
+    }
+
+    /// Limits the number of transfers served at once to `max_clients`.
+    ///
+    /// Requests received while this many transfers are active are answered with
+    /// a "Server busy" error from the listening socket.
+    pub fn with_max_clients(mut self, max_clients: usize) -> Server {
+        self.config.max_clients = max_clients;
+        self
+    }
+
+    /// Limits every transfer to `bytes_per_second`, for links with little bandwidth.
+    ///
+    /// Each client gets the full rate, the limit does not apply to the server as a whole.
+    /// A rate of 0 removes the limit.
+    pub fn with_rate_limit(mut self, bytes_per_second: u64) -> Server {
+        self.config.max_transfer_rate = nonzero_rate(bytes_per_second);
+        self
+    }
+
+    /// Reports every transfer, also the rejected requests, to `audit_log`.
+    pub fn with_audit_log(mut self, audit_log: Arc<AuditLog>) -> Server {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Returns the number of transfers currently being served.
+    pub fn active_clients(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Stops accepting requests, the transfers in progress continue.
+    ///
+    /// `serve` returns `Ok` shortly after.
+    pub fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Stops accepting requests and waits up to `timeout` for the transfers in
+    /// progress to complete.
+    ///
+    /// The clients of the transfers still running after `timeout` are sent an error
+    /// and the transfers stop; the result is then an error of kind `TimedOut`.
+    pub fn graceful_shutdown(&self, timeout: Duration) -> io::Result<()> {
+        self.shutdown();
+        let deadline = Instant::now() + timeout;
         loop {
-            if self.send_data {
-                match self.last_id {
-                    Some(last_id) if self.block_id > last_id => break,
-                    _ => {}
+            let mut transfers = self.transfers.lock().unwrap();
+            transfers.retain(|transfer| !transfer.handle.is_finished());
+            if transfers.is_empty() {
+                return Ok(())
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                self.aborted.store(true, Ordering::SeqCst);
+                for transfer in transfers.iter() {
+                    if let Err(err) = send_error(&transfer.socket, transfer.client, Error::Undefined,
+                                                 "Server shutting down") {
+                        warn!("Could not notify {} of the shutdown: {}", transfer.client, err);
+                    }
                 }
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                          format!("{} transfers did not complete", transfers.len())))
+            }
+            drop(transfers);
+            thread::sleep(cmp::min(SHUTDOWN_POLL_INTERVAL, deadline - now));
+        }
+    }
 
-                let mut buf = vec![0; 512];
-                let n = self.data.read(&mut buf).unwrap();
+    /// Maps a requested file name to a local path inside of the server root, or the
+    /// working directory if there is none.
+    fn resolve(&self, request: &RequestPacket, write: bool) -> Result<PathBuf, (Error, &'static str)> {
+        let filename = match request.filename() {
+            Some(filename) => filename,
+            None => return Err((Error::AccessViolation, "Access violation")),
+        };
+        let root = self.root.as_ref().map_or(Path::new("."), |root| root.as_path());
+        let resolved = if write {
+            canonicalize_tftp_write_path(root, &filename)
+        } else {
+            canonicalize_tftp_path(root, &filename)
+        };
+        resolved.map_err(|err| {
+            warn!("Rejected path {:?}: {}", filename, err);
+            match err {
+                PathError::NotFound => (Error::FileNotFound, "File not found"),
+                _ => (Error::AccessViolation, "Access violation"),
+            }
+        })
+    }
 
-                if n < 512 {
-                    self.last_id = Some(self.block_id);
-                }
+    /// Binds the socket requests are received on to `addr`, without serving them yet.
+    ///
+    /// With port 0 the system picks a free port, `port` and `bind_addr` tell which one.
+    /// `serve_bound` then serves the requests.
+    pub fn bind(mut self, addr: &SocketAddr) -> io::Result<Server> {
+        self.socket = Some(try!(UdpSocket::bind(addr)));
+        Ok(self)
+    }
+
+    /// Returns the port the server is bound to.
+    ///
+    /// Panics if the server was not bound with `bind`.
+    #[inline]
+    pub fn port(&self) -> u16 {
+        self.bind_addr().port()
+    }
+
+    /// Returns the address the server is bound to.
+    ///
+    /// Panics if the server was not bound with `bind`.
+    #[inline]
+    pub fn bind_addr(&self) -> SocketAddr {
+        self.socket.as_ref().expect("server is not bound").local_addr().unwrap()
+    }
 
-                let data_packet = DataPacketOctet::from_vec(self.block_id, buf, n);
-                let encoded_packet = data_packet.encode();
+    /// Serves requests received on the socket bound with `bind` until an error occurs.
+    pub fn serve_bound(&self) -> io::Result<()> {
+        match self.socket {
+            Some(ref socket) => self.serve(try!(socket.try_clone())),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "server is not bound")),
+        }
+    }
+
+    /// Listens for requests on the given address, serving them until an error occurs.
+    pub fn run(&self, addr: &SocketAddr) -> io::Result<()> {
+        let socket = try!(UdpSocket::bind(addr));
+        self.serve(socket)
+    }
 
-                println!("Sending data packet id = {} length = {}", self.block_id, n);
-                println!("{}", encoded_packet.packet_buf().len());
-                try_nb!(self.socket.send_to(encoded_packet.packet_buf(), &self.client_request.addr));
-                self.send_data = false;
+    /// Serves requests received on an already bound socket until an error occurs, or
+    /// the server is shut down.
+    ///
+    /// The read timeout of `socket` is replaced, so that a shutdown is noticed while
+    /// no requests arrive.
+    pub fn serve(&self, socket: UdpSocket) -> io::Result<()> {
+        let mut transfer_addr = try!(socket.local_addr());
+        transfer_addr.set_port(0);
+        try!(socket.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL)));
+
+        info!("Listening on {}", try!(socket.local_addr()));
+
+        loop {
+            let received = receive_any(&socket, MAX_REQUEST_SIZE);
+            if self.shutting_down.load(Ordering::SeqCst) {
+                info!("Shutting down, no longer accepting requests");
+                return Ok(())
             }
+            let (packet, addr) = match received {
+                Ok(received) => received,
+                Err(ref err) if is_timeout(err) => continue,
+                Err(err) => return Err(err),
+            };
+            let started = Instant::now();
+            let request = match packet {
+                Some(AnyPacket::Rrq(request)) | Some(AnyPacket::Wrq(request)) => request,
+                Some(AnyPacket::Unknown(opcode, _)) => {
+                    debug!("Ignoring packet with unknown opcode {} from {}", opcode, addr);
+                    continue
+                }
+                Some(packet) => {
+                    debug!("Ignoring {} packet from {}, expected a request", packet.opcode().unwrap(), addr);
+                    continue
+                }
+                None => continue,
+            };
+            info!("Request from {}: mode = {:?}, filename = {:?}", addr, request.mode(), request.filename());
 
-            let mut buf = vec![0; 512];
-            let (n, _) = try_nb!(self.socket.recv_from(&mut buf));
-            let ack_packet: DecodedPacket<AckPacket> = DecodedPacket::decode(RawPacket::new(buf, n)).unwrap();
-            println!("Received ack packet id = {}", ack_packet.block_id());
-            self.block_id += 1;
-            self.send_data = true;
+            // Failing to handle one request must not stop the server for every client.
+            if self.active_clients() >= self.config.max_clients {
+                if let Err(err) = send_error(&socket, addr, Error::Undefined, "Server busy") {
+                    warn!("Could not tell {} that the server is busy: {}", addr, err);
+                }
+                continue
+            }
+
+            let transfer_socket = match UdpSocket::bind(transfer_addr) {
+                Ok(transfer_socket) => transfer_socket,
+                Err(err) => {
+                    warn!("Could not bind a socket for the transfer to {}: {}", addr, err);
+                    // Best effort, the client times out otherwise.
+                    let _ = send_error(&socket, addr, Error::Undefined, "Could not start the transfer");
+                    continue
+                }
+            };
+            let file = match request {
+                RequestPacket::ReadRequest(..) => {
+                    self.resolve(&request, false).and_then(|path| {
+                        File::open(&path).and_then(|file| {
+                            fs::metadata(&path).map(|metadata| (file, metadata.len()))
+                        }).map_err(|_| (Error::FileNotFound, "File not found"))
+                    })
+                }
+                RequestPacket::WriteRequest(..) => {
+                    self.resolve(&request, true).and_then(|_| {
+                        Err((Error::IllegalOperation, "Write requests are not supported"))
+                    })
+                }
+            };
+
+            let operation = if request.is_write() { "write" } else { "read" };
+            let audit = self.audit_log.clone().map(|log| (log, request.path().into_owned()));
+            match file {
+                Ok((file, file_size)) => {
+                    let client_socket = match transfer_socket.try_clone() {
+                        Ok(client_socket) => client_socket,
+                        Err(err) => {
+                            warn!("Could not start the transfer to {}: {}", addr, err);
+                            let _ = send_error(&transfer_socket, addr, Error::Undefined,
+                                               "Could not start the transfer");
+                            continue
+                        }
+                    };
+                    let guard = ActiveGuard::new(self.active.clone());
+                    let options = Negotiated::read_request(&request, file_size, self.config.transfer_timeout);
+                    let config = self.config.clone();
+                    let aborted = self.aborted.clone();
+                    let handle = thread::spawn(move || {
+                        let _guard = guard;
+                        let mut sent = Sent::default();
+                        let result = send_file(transfer_socket, addr, file, options, &config, &aborted,
+                                               &mut sent);
+                        if let Err(ref err) = result {
+                            warn!("Transfer to {} failed: {}", addr, err);
+                        }
+                        if let Some((log, path)) = audit {
+                            log.record(AuditEntry {
+                                timestamp: Utc::now(),
+                                client: addr,
+                                operation: operation,
+                                path: &path,
+                                blocks: sent.blocks,
+                                bytes: sent.bytes,
+                                duration_ms: duration_ms(started.elapsed()),
+                                error: result.err().map(|err| err.to_string()),
+                            });
+                        }
+                    });
+                    let mut transfers = self.transfers.lock().unwrap();
+                    transfers.retain(|transfer| !transfer.handle.is_finished());
+                    transfers.push(RunningTransfer {
+                        handle: handle,
+                        socket: client_socket,
+                        client: addr,
+                    });
+                }
+                Err((error, msg)) => {
+                    if let Err(err) = send_error(&transfer_socket, addr, error, msg) {
+                        warn!("Could not send \"{}\" to {}: {}", msg, addr, err);
+                    }
+                    if let Some((log, path)) = audit {
+                        log.record(AuditEntry {
+                            timestamp: Utc::now(),
+                            client: addr,
+                            operation: operation,
+                            path: &path,
+                            blocks: 0,
+                            bytes: 0,
+                            duration_ms: duration_ms(started.elapsed()),
+                            error: Some(msg.to_string()),
+                        });
+                    }
+                }
+            }
         }
-        Ok(().into())
     }
 }
 
 pub fn start() {
-    let mut l = Core::new().unwrap();
-    let handle = l.handle();
-
     let addr = "127.0.0.1:9999".to_string().parse::<SocketAddr>().unwrap();
-    let socket = UdpSocket::bind(&addr, &handle).unwrap();
+    Server::new().run(&addr).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
 
-    println!("Listening on {}", addr);
+    #[cfg(feature = "serde")]
+    use chrono::{DateTime, Utc};
+    #[cfg(feature = "serde")]
+    use serde_json::{self, Value};
 
-    let acceptor = RequestAcceptor::new(socket);
-    let server = acceptor.for_each(|client_request| {
-        println!("mode = {:?}, filename = {:?}", client_request.request.mode(), client_request.request.filename());
+    #[cfg(feature = "serde")]
+    use audit::JsonLineLogger;
+    use client;
+    #[cfg(feature = "serde")]
+    use client::Client;
 
-        handle.spawn({
-            let mut addr = addr.clone();
-            addr.set_port(0);
-            let socket = UdpSocket::bind(&addr, &handle).unwrap();
-            RequestHandler::new(socket, client_request).map_err(|_| ())
+    use packet::{Mode, Error, EncodePacket, RawPacket, RequestPacket, AckPacket,
+                 DataPacketOctet, ErrorPacket, OackPacket, Opcode, recv_buffer_size};
+    use test_server::{TestServer, temp_dir};
+
+    use super::{Server, ServerConfig, ActiveGuard, PathError, PathValidationError, validate_tftp_path,
+                canonicalize_tftp_path};
+
+    fn client_socket() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket
+    }
+
+    fn send_request(socket: &UdpSocket, server_addr: SocketAddr, request: RequestPacket) -> (RawPacket, SocketAddr) {
+        socket.send_to(request.encode().packet_buf(), &server_addr).unwrap();
+        receive(socket)
+    }
+
+    fn receive(socket: &UdpSocket) -> (RawPacket, SocketAddr) {
+        let mut buf = vec![0; recv_buffer_size(super::MAX_BLOCK_SIZE as usize)];
+        let (n, from) = socket.recv_from(&mut buf).unwrap();
+        (RawPacket::new(buf, n), from)
+    }
+
+    /// Acknowledges `packet` and every following block until the transfer completes.
+    fn finish_download(socket: &UdpSocket, mut packet: RawPacket, from: SocketAddr) -> Vec<u8> {
+        let mut received = Vec::new();
+        loop {
+            let data: DataPacketOctet = packet.decode().expect("expected a data packet");
+            received.extend_from_slice(data.data());
+            let ack = AckPacket::new(data.block_id()).encode();
+            socket.send_to(ack.packet_buf(), &from).unwrap();
+            if data.data().len() < 512 {
+                return received
+            }
+            packet = receive(socket).0;
+        }
+    }
+
+    fn download(server_addr: SocketAddr, filename: &str) -> Vec<u8> {
+        let socket = client_socket();
+        let request = RequestPacket::read_request(filename, Mode::Octet);
+        let (packet, from) = send_request(&socket, server_addr, request);
+        finish_download(&socket, packet, from)
+    }
+
+    fn request_error(server_addr: SocketAddr, request: RequestPacket) -> Error {
+        let socket = client_socket();
+        let (packet, _) = send_request(&socket, server_addr, request);
+        assert_eq!(Some(Opcode::ERROR), packet.opcode());
+        let error: ErrorPacket = packet.decode().expect("expected an error packet");
+        error.error()
+    }
+
+    #[test]
+    fn file_inside_root_is_served() {
+        let server = TestServer::new();
+        server.create_file("file.txt", &[7; 700]);
+
+        assert_eq!(vec![7; 700], download(server.addr(), "file.txt"));
+    }
+
+    #[test]
+    fn missing_file_inside_root_is_not_found() {
+        let server = TestServer::new();
+
+        let request = RequestPacket::read_request("missing.txt", Mode::Octet);
+        assert_eq!(Error::FileNotFound, request_error(server.addr(), request));
+    }
+
+    #[test]
+    fn parent_directory_traversal_is_rejected() {
+        let server = TestServer::new();
+
+        let request = RequestPacket::read_request("../etc/shadow", Mode::Octet);
+        assert_eq!(Error::AccessViolation, request_error(server.addr(), request));
+    }
+
+    #[test]
+    fn absolute_path_is_rejected() {
+        let server = TestServer::new();
+
+        let request = RequestPacket::read_request("/etc/hostname", Mode::Octet);
+        assert_eq!(Error::AccessViolation, request_error(server.addr(), request));
+    }
+
+    #[test]
+    fn relative_paths_are_valid() {
+        assert_eq!(Ok(()), validate_tftp_path("boot/pxelinux.0"));
+        assert_eq!(Ok(()), validate_tftp_path("file..txt"));
+        assert_eq!(Ok(()), validate_tftp_path(&"a".repeat(512)));
+    }
+
+    #[test]
+    fn empty_path_is_invalid() {
+        assert_eq!(Err(PathValidationError::Empty), validate_tftp_path(""));
+    }
+
+    #[test]
+    fn path_with_nul_byte_is_invalid() {
+        assert_eq!(Err(PathValidationError::NulByte), validate_tftp_path("boot\0pxelinux.0"));
+    }
+
+    #[test]
+    fn path_with_control_character_is_invalid() {
+        assert_eq!(Err(PathValidationError::ControlCharacter), validate_tftp_path("boot\npxelinux.0"));
+        assert_eq!(Err(PathValidationError::ControlCharacter), validate_tftp_path("\u{1f}"));
+    }
+
+    #[test]
+    fn absolute_path_is_invalid() {
+        assert_eq!(Err(PathValidationError::Absolute), validate_tftp_path("/etc/hostname"));
+    }
+
+    #[test]
+    fn path_with_parent_directory_is_invalid() {
+        assert_eq!(Err(PathValidationError::ParentDirectory), validate_tftp_path(".."));
+        assert_eq!(Err(PathValidationError::ParentDirectory), validate_tftp_path("boot/../../etc/shadow"));
+    }
+
+    #[test]
+    fn path_longer_than_512_bytes_is_invalid() {
+        assert_eq!(Err(PathValidationError::TooLong), validate_tftp_path(&"a".repeat(513)));
+    }
+
+    #[test]
+    fn path_with_control_character_is_rejected() {
+        let server = TestServer::new();
+        server.create_file("file\n.txt", &[1; 10]);
+
+        let request = RequestPacket::read_request("file\n.txt", Mode::Octet);
+        assert_eq!(Error::AccessViolation, request_error(server.addr(), request));
+    }
+
+    #[test]
+    fn file_inside_root_is_canonicalized() {
+        let root = temp_dir("canonicalize");
+        fs::create_dir(root.join("boot")).unwrap();
+        fs::File::create(root.join("boot/pxelinux.0")).unwrap();
+
+        let expected = root.canonicalize().unwrap().join("boot/pxelinux.0");
+        assert_eq!(expected, canonicalize_tftp_path(&root, "boot/pxelinux.0").unwrap());
+        assert_eq!(expected, canonicalize_tftp_path(&root, "boot//pxelinux.0").unwrap());
+        assert_eq!(expected, canonicalize_tftp_path(&root, "./boot/./pxelinux.0").unwrap());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn missing_file_is_not_found() {
+        let root = temp_dir("canonicalize");
+        match canonicalize_tftp_path(&root, "missing.txt") {
+            Err(PathError::NotFound) => {}
+            other => panic!("expected the file not to be found, got {:?}", other),
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn invalid_path_is_not_resolved() {
+        let root = temp_dir("canonicalize");
+        match canonicalize_tftp_path(&root, "../etc/shadow") {
+            Err(PathError::Invalid(PathValidationError::ParentDirectory)) => {}
+            other => panic!("expected an invalid path, got {:?}", other),
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_leading_outside_root_is_a_traversal() {
+        use std::os::unix::fs::symlink;
+
+        let root = temp_dir("canonicalize");
+        let outside = temp_dir("outside");
+        fs::File::create(outside.join("secret.txt")).unwrap();
+        symlink(&outside, root.join("link")).unwrap();
+
+        match canonicalize_tftp_path(&root, "link/secret.txt") {
+            Err(PathError::Traversal) => {}
+            other => panic!("expected a traversal, got {:?}", other),
+        }
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_inside_root_is_followed() {
+        use std::os::unix::fs::symlink;
+
+        let root = temp_dir("canonicalize");
+        fs::create_dir(root.join("images")).unwrap();
+        fs::File::create(root.join("images/kernel-1.0")).unwrap();
+        symlink(root.join("images/kernel-1.0"), root.join("kernel")).unwrap();
+
+        let expected = root.canonicalize().unwrap().join("images/kernel-1.0");
+        assert_eq!(expected, canonicalize_tftp_path(&root, "kernel").unwrap());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn backslashes_do_not_separate_components_on_unix() {
+        let root = temp_dir("canonicalize");
+        fs::create_dir(root.join("boot")).unwrap();
+        fs::File::create(root.join("boot/pxelinux.0")).unwrap();
+
+        match canonicalize_tftp_path(&root, "boot\\pxelinux.0") {
+            Err(PathError::NotFound) => {}
+            other => panic!("expected the file not to be found, got {:?}", other),
+        }
+        match canonicalize_tftp_path(&root, "..\\..\\etc\\passwd") {
+            Err(PathError::NotFound) => {}
+            other => panic!("expected the file not to be found, got {:?}", other),
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_pointing_outside_root_is_rejected() {
+        use std::fs::File;
+        use std::os::unix::fs::symlink;
+
+        let server = TestServer::new();
+        let outside = temp_dir("outside").join("file.txt");
+        File::create(&outside).unwrap();
+        symlink(&outside, server.root().join("link.txt")).unwrap();
+
+        let request = RequestPacket::read_request("link.txt", Mode::Octet);
+        assert_eq!(Error::AccessViolation, request_error(server.addr(), request));
+    }
+
+    #[test]
+    fn write_request_outside_root_is_rejected() {
+        let server = TestServer::new();
+
+        let request = RequestPacket::write_request("../escaped.txt", Mode::Octet);
+        assert_eq!(Error::AccessViolation, request_error(server.addr(), request));
+    }
+
+    #[test]
+    fn concurrent_downloads_are_served() {
+        let server = TestServer::new();
+        server.create_file("first", &[1; 5000]);
+        server.create_file("second", &[2; 3000]);
+
+        let addr = server.addr();
+        let first = thread::spawn(move || download(addr, "first"));
+        let second = thread::spawn(move || download(addr, "second"));
+
+        assert_eq!(vec![1; 5000], first.join().unwrap());
+        assert_eq!(vec![2; 3000], second.join().unwrap());
+    }
+
+    #[test]
+    fn transfers_use_a_new_transfer_id() {
+        let server = TestServer::new();
+        server.create_file("file", &[1; 10]);
+
+        let socket = client_socket();
+        let request = RequestPacket::read_request("file", Mode::Octet);
+        let (_, from) = send_request(&socket, server.addr(), request);
+        assert!(from.port() != server.addr().port());
+    }
+
+    #[test]
+    fn request_over_max_clients_is_answered_busy() {
+        let server = TestServer::with_config(ServerConfig {
+            max_clients: 2,
+            ..ServerConfig::default()
         });
+        server.create_file("file", &[3; 2000]);
 
-        Ok(())
-    });
+        // Both transfers stay active until their first block is acknowledged.
+        let first = client_socket();
+        let (first_packet, first_from) = send_request(&first, server.addr(),
+                                                      RequestPacket::read_request("file", Mode::Octet));
+        let second = client_socket();
+        let (second_packet, second_from) = send_request(&second, server.addr(),
+                                                        RequestPacket::read_request("file", Mode::Octet));
 
-    l.run(server).unwrap();
+        let third = client_socket();
+        let (packet, from) = send_request(&third, server.addr(), RequestPacket::read_request("file", Mode::Octet));
+        assert_eq!(server.addr(), from);
+        let error: ErrorPacket = packet.decode().expect("expected an error packet");
+        assert_eq!(Error::Undefined, error.error());
+        assert_eq!("Server busy", error.message().unwrap());
+
+        assert_eq!(vec![3; 2000], finish_download(&first, first_packet, first_from));
+        assert_eq!(vec![3; 2000], finish_download(&second, second_packet, second_from));
+    }
+
+    #[test]
+    fn transfer_can_start_after_another_completes_at_max_clients() {
+        let server = TestServer::with_config(ServerConfig {
+            max_clients: 2,
+            ..ServerConfig::default()
+        });
+        server.create_file("file", &[4; 1000]);
+
+        let first = client_socket();
+        let (first_packet, first_from) = send_request(&first, server.addr(),
+                                                      RequestPacket::read_request("file", Mode::Octet));
+        let second = client_socket();
+        let (second_packet, second_from) = send_request(&second, server.addr(),
+                                                        RequestPacket::read_request("file", Mode::Octet));
+        assert_eq!(2, server.active_clients());
+
+        assert_eq!(vec![4; 1000], finish_download(&first, first_packet, first_from));
+        server.wait_for_active_clients(1);
+
+        assert_eq!(vec![4; 1000], download(server.addr(), "file"));
+        assert_eq!(vec![4; 1000], finish_download(&second, second_packet, second_from));
+    }
+
+    #[test]
+    fn unacknowledged_block_is_sent_again() {
+        let server = TestServer::with_config(ServerConfig {
+            transfer_timeout: Duration::from_millis(50),
+            max_retries: 2,
+            ..ServerConfig::default()
+        });
+        server.create_file("file", &[5; 700]);
+
+        let socket = client_socket();
+        let (first, from) = send_request(&socket, server.addr(), RequestPacket::read_request("file", Mode::Octet));
+        let (again, again_from) = receive(&socket);
+        assert_eq!(from, again_from);
+        assert_eq!(first.packet_buf(), again.packet_buf());
+        assert_eq!(vec![5; 700], finish_download(&socket, again, from));
+    }
+
+    #[test]
+    fn transfer_is_aborted_after_the_last_retry() {
+        let server = TestServer::with_config(ServerConfig {
+            transfer_timeout: Duration::from_millis(20),
+            max_retries: 2,
+            ..ServerConfig::default()
+        });
+        server.create_file("file", &[5; 700]);
+
+        let socket = client_socket();
+        send_request(&socket, server.addr(), RequestPacket::read_request("file", Mode::Octet));
+        for _ in 0..2 {
+            let (packet, _) = receive(&socket);
+            assert_eq!(Some(Opcode::DATA), packet.opcode());
+        }
+        server.wait_for_active_clients(0);
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let mut buf = [0; 4];
+        assert!(socket.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn packets_from_another_port_do_not_delay_the_retransmission() {
+        let server = TestServer::with_config(ServerConfig {
+            transfer_timeout: Duration::from_millis(200),
+            max_retries: 2,
+            ..ServerConfig::default()
+        });
+        server.create_file("file", &[5; 700]);
+
+        let socket = client_socket();
+        let (first, from) = send_request(&socket, server.addr(), RequestPacket::read_request("file", Mode::Octet));
+        let intruder = client_socket();
+        // Packets arrive more often than the timeout expires, for longer than it.
+        for _ in 0..6 {
+            intruder.send_to(AckPacket::new(1).encode().packet_buf(), &from).unwrap();
+            let (packet, _) = receive(&intruder);
+            let error: ErrorPacket = packet.decode().expect("expected an error packet");
+            assert_eq!(Error::UnknownTransferId, error.error());
+            thread::sleep(Duration::from_millis(50));
+        }
+        // Sent when the timeout expired, not a timeout after the last intruding packet.
+        socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let (again, _) = receive(&socket);
+        assert_eq!(first.packet_buf(), again.packet_buf());
+        server.wait_for_active_clients(0);
+    }
+
+    #[test]
+    fn transfers_are_throttled_to_the_rate_limit() {
+        let server = TestServer::with_server(|server| server.with_rate_limit(512 * 10));
+        server.create_file("file", &[6; 51200]);
+
+        let started = Instant::now();
+        assert_eq!(vec![6; 51200], download(server.addr(), "file"));
+        // Ten seconds at the limit, less 10% tolerance.
+        assert!(started.elapsed() >= Duration::from_secs(9), "took {:?}", started.elapsed());
+    }
+
+    #[test]
+    fn with_rate_limit_sets_the_limit() {
+        let server = Server::new().with_rate_limit(1000);
+        assert_eq!(Some(1000), server.config.max_transfer_rate);
+    }
+
+    #[test]
+    fn rate_limit_of_zero_is_no_limit() {
+        assert_eq!(None, Server::new().with_rate_limit(1000).with_rate_limit(0).config.max_transfer_rate);
+
+        let server = TestServer::with_config(ServerConfig {
+            max_transfer_rate: Some(0),
+            ..ServerConfig::default()
+        });
+        server.create_file("file", &[6; 1300]);
+        assert_eq!(vec![6; 1300], download(server.addr(), "file"));
+    }
+
+    #[test]
+    fn server_bound_to_port_0_serves_downloads() {
+        let root = temp_dir("bound");
+        fs::write(root.join("file.bin"), &[6; 1300]).unwrap();
+        let server = Arc::new(Server::with_root(&root).bind(&"127.0.0.1:0".parse().unwrap()).unwrap());
+        assert!(server.port() != 0);
+        assert_eq!(server.port(), server.bind_addr().port());
+
+        let serving = server.clone();
+        thread::spawn(move || serving.serve_bound());
+        let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), server.port());
+        assert_eq!(vec![6; 1300], client::get_into_vec(addr, Path::new("file.bin"), Mode::Octet).unwrap());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Starts a download of 100 blocks that takes about a second, then shuts the
+    /// server down with `timeout`.
+    ///
+    /// Returns the result of the shutdown, and the file or the error the client got.
+    fn shut_down_during_slow_download(timeout: Duration) -> (io::Result<()>, Result<Vec<u8>, Error>) {
+        let root = temp_dir("shutdown");
+        fs::write(root.join("file.bin"), &[4; 99 * 512 + 100][..]).unwrap();
+        let server = Arc::new(Server::with_root(&root).bind(&"127.0.0.1:0".parse().unwrap()).unwrap());
+        let serving = server.clone();
+        let served = thread::spawn(move || serving.serve_bound());
+
+        let socket = client_socket();
+        let request = RequestPacket::read_request("file.bin", Mode::Octet);
+        let (mut packet, from) = send_request(&socket, server.bind_addr(), request);
+        let client = thread::spawn(move || {
+            let mut received = Vec::new();
+            loop {
+                if packet.opcode() == Some(Opcode::ERROR) {
+                    let error: ErrorPacket = packet.decode().unwrap();
+                    return Err(error.error())
+                }
+                let data: DataPacketOctet = packet.decode().expect("expected a data packet");
+                received.extend_from_slice(data.data());
+                thread::sleep(Duration::from_millis(10));
+                socket.send_to(AckPacket::new(data.block_id()).encode().packet_buf(), &from).unwrap();
+                if data.data().len() < 512 {
+                    return Ok(received)
+                }
+                packet = receive(&socket).0;
+            }
+        });
+
+        let result = server.graceful_shutdown(timeout);
+        let received = client.join().unwrap();
+        assert!(served.join().unwrap().is_ok());
+        fs::remove_dir_all(&root).unwrap();
+        (result, received)
+    }
+
+    #[test]
+    fn graceful_shutdown_waits_for_transfers_to_complete() {
+        let (result, received) = shut_down_during_slow_download(Duration::from_secs(5));
+        assert!(result.is_ok());
+        assert_eq!(vec![4; 99 * 512 + 100], received.unwrap());
+    }
+
+    #[test]
+    fn graceful_shutdown_stops_transfers_after_the_timeout() {
+        let started = Instant::now();
+        let (result, received) = shut_down_during_slow_download(Duration::from_millis(500));
+        assert_eq!(io::ErrorKind::TimedOut, result.unwrap_err().kind());
+        assert_eq!(Err(Error::Undefined), received);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn shut_down_server_accepts_no_requests() {
+        let server = Arc::new(Server::new().bind(&"127.0.0.1:0".parse().unwrap()).unwrap());
+        let serving = server.clone();
+        let served = thread::spawn(move || serving.serve_bound());
+        server.shutdown();
+        assert!(served.join().unwrap().is_ok());
+
+        let socket = client_socket();
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let request = RequestPacket::read_request("file.bin", Mode::Octet);
+        socket.send_to(request.encode().packet_buf(), &server.bind_addr()).unwrap();
+        let mut buf = [0; 4];
+        assert!(socket.recv_from(&mut buf).is_err());
+        assert!(server.graceful_shutdown(Duration::from_millis(0)).is_ok());
+    }
+
+    #[test]
+    fn unbound_server_can_not_be_served() {
+        assert_eq!(io::ErrorKind::NotConnected, Server::new().serve_bound().unwrap_err().kind());
+    }
+
+    #[test]
+    fn with_max_clients_sets_the_limit() {
+        let server = Server::new().with_max_clients(3);
+        assert_eq!(3, server.config.max_clients);
+        assert_eq!(0, server.active_clients());
+    }
+
+    #[test]
+    fn active_count_is_decremented_when_a_transfer_panics() {
+        let server = Server::new();
+        let guard = ActiveGuard::new(server.active.clone());
+        assert_eq!(1, server.active_clients());
+
+        let result = thread::spawn(move || {
+            let _guard = guard;
+            panic!("transfer failed");
+        }).join();
+        assert!(result.is_err());
+        assert_eq!(0, server.active_clients());
+    }
+
+    #[test]
+    fn read_request_options_are_acknowledged() {
+        let server = TestServer::new();
+        let contents: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+        server.create_file("file", &contents);
+
+        let socket = client_socket();
+        socket.send_to(b"\x00\x01file\x00octet\x00blksize\x001468\x00tsize\x000\x00", &server.addr()).unwrap();
+        let (packet, from) = receive(&socket);
+        assert_eq!(Some(Opcode::OACK), packet.opcode());
+        {
+            let oack: OackPacket = packet.decode().expect("expected an option acknowledgment");
+            assert_eq!(Some("1468"), oack.option("blksize"));
+            assert_eq!(Some("4000"), oack.option("tsize"));
+        }
+
+        socket.send_to(AckPacket::new(0).encode().packet_buf(), &from).unwrap();
+        let mut received = Vec::new();
+        let mut block_sizes = Vec::new();
+        loop {
+            let (packet, _) = receive(&socket);
+            let data: DataPacketOctet = packet.decode().expect("expected a data packet");
+            received.extend_from_slice(data.data());
+            block_sizes.push(data.data().len());
+            socket.send_to(AckPacket::new(data.block_id()).encode().packet_buf(), &from).unwrap();
+            if data.data().len() < 1468 {
+                break
+            }
+        }
+        assert_eq!(vec![1468, 1468, 1064], block_sizes);
+        assert_eq!(contents, received);
+    }
+
+    #[test]
+    fn request_without_options_is_not_acknowledged() {
+        let server = TestServer::new();
+        server.create_file("file", &[5; 10]);
+
+        let socket = client_socket();
+        let (packet, _) = send_request(&socket, server.addr(), RequestPacket::read_request("file", Mode::Octet));
+        assert_eq!(Some(Opcode::DATA), packet.opcode());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn transfers_are_recorded_in_the_audit_log() {
+        let dir = temp_dir("audit");
+        let server_log = dir.join("server.log");
+        let client_log = dir.join("client.log");
+        let logger = Arc::new(JsonLineLogger::create(&server_log).unwrap());
+        let server = TestServer::with_server(|server| server.with_audit_log(logger));
+        server.create_file("file.bin", &[1; 1300]);
+
+        let client = Client::new(server.addr()).unwrap()
+            .with_audit_log(Arc::new(JsonLineLogger::create(&client_log).unwrap()));
+        let mut output = Vec::new();
+        client.get(Path::new("file.bin"), Mode::Octet, &mut output).unwrap();
+        assert!(client::get_into_vec(server.addr(), Path::new("missing.bin"), Mode::Octet).is_err());
+        server.wait_for_active_clients(0);
+        // The rejected request is recorded after the client got the error.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while fs::read_to_string(&server_log).unwrap().lines().count() < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let parse = |path: &Path| -> Vec<Value> {
+            fs::read_to_string(path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+        };
+        let server_lines = parse(&server_log);
+        assert_eq!(2, server_lines.len());
+        let client_lines = parse(&client_log);
+        assert_eq!(1, client_lines.len());
+
+        for line in vec![&server_lines[0], &client_lines[0]] {
+            let timestamp = line["timestamp"].as_str().expect("timestamp is a string");
+            assert!(timestamp.parse::<DateTime<Utc>>().is_ok(), "{}", timestamp);
+            assert!(line["duration_ms"].is_u64());
+            assert_eq!("read", line["operation"]);
+            assert_eq!("file.bin", line["path"]);
+            assert_eq!(3, line["blocks"]);
+            assert_eq!(1300, line["bytes"]);
+            assert_eq!(Value::Null, line["error"]);
+        }
+        // The client is bound to all interfaces, only the ports match.
+        let addr = |line: &Value| line["client"].as_str().unwrap().parse::<SocketAddr>().unwrap();
+        assert_eq!(addr(&client_lines[0]).port(), addr(&server_lines[0]).port());
+
+        assert_eq!("missing.bin", server_lines[1]["path"]);
+        assert_eq!(0, server_lines[1]["blocks"]);
+        assert_eq!("File not found", server_lines[1]["error"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
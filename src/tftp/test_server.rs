@@ -0,0 +1,304 @@
+//! An in-process TFTP server serving a temporary directory, for tests.
+
+extern crate rand;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::rand::{Rng, SeedableRng, XorShiftRng};
+
+use packet::Opcode;
+use server::{Server, ServerConfig};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates a new, empty directory unique to this test process.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    let dir = env::temp_dir().join(format!("tftp-{}-{}-{}", name, process::id(), id));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A server listening on an ephemeral localhost port.
+///
+/// The server thread runs until the test process exits, the served directory is
+/// removed when the `TestServer` is dropped.
+pub struct TestServer {
+    addr: SocketAddr,
+    root: PathBuf,
+    server: Arc<Server>,
+    faults: Option<Arc<FaultInjector>>,
+}
+
+impl TestServer {
+    pub fn new() -> TestServer {
+        TestServer::with_config(ServerConfig::default())
+    }
+
+    pub fn with_config(config: ServerConfig) -> TestServer {
+        TestServer::with_server(|server| server.with_config(config))
+    }
+
+    /// Starts a server built by `configure` from one serving the temporary directory.
+    pub fn with_server<F: FnOnce(Server) -> Server>(configure: F) -> TestServer {
+        let root = temp_dir("server");
+        let server = configure(Server::with_root(&root)).bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let server = Arc::new(server);
+        let serving = server.clone();
+        thread::spawn(move || serving.serve_bound());
+        TestServer {
+            addr: server.bind_addr(),
+            root: root,
+            server: server,
+            faults: None,
+        }
+    }
+
+    /// Starts a server that is reached through a `FaultInjector` applying `faults`.
+    ///
+    /// Only one client at a time can talk to the server.
+    ///
+    /// The server sends unacknowledged blocks again after 50 milliseconds, so that
+    /// transfers recover from lost packets quickly.
+    pub fn with_faults(faults: FaultConfig) -> TestServer {
+        let mut server = TestServer::with_config(ServerConfig {
+            transfer_timeout: Duration::from_millis(50),
+            max_retries: 20,
+            ..ServerConfig::default()
+        });
+        let injector = FaultInjector::start(server.addr, faults);
+        server.addr = injector.addr();
+        server.faults = Some(injector);
+        server
+    }
+
+    /// Address the server listens for requests on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Faults injected so far, in the order they happened.
+    ///
+    /// Always empty unless the server was started with `with_faults`.
+    pub fn faults(&self) -> Vec<Fault> {
+        self.faults.as_ref().map_or(Vec::new(), |injector| injector.faults())
+    }
+
+    /// Directory the server serves files from.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Number of transfers the server is currently conducting.
+    pub fn active_clients(&self) -> usize {
+        self.server.active_clients()
+    }
+
+    /// Waits up to five seconds for the number of active transfers to drop to `count`.
+    pub fn wait_for_active_clients(&self, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.active_clients() > count {
+            assert!(Instant::now() < deadline, "transfers did not finish");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Creates a file with the given contents in the served directory.
+    pub fn create_file(&self, name: &str, contents: &[u8]) -> PathBuf {
+        let path = self.root.join(name);
+        File::create(&path).unwrap().write_all(contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Network faults injected between the clients and a `TestServer`.
+#[derive(Debug, Clone, Default)]
+pub struct FaultConfig {
+    /// Probability of a DATA packet being dropped.
+    pub data_drop_rate: f64,
+    /// Probability of an ACK packet being dropped.
+    pub ack_drop_rate: f64,
+    /// Number of DATA and ACK packets held back at the start of the transfer and then
+    /// sent in reverse order.
+    pub reorder_count: usize,
+    /// Probability of a packet being sent twice.
+    pub duplicate_rate: f64,
+}
+
+/// A fault injected by a `FaultInjector`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The DATA packet of the block was dropped.
+    DroppedData(u16),
+    /// The ACK of the block was dropped.
+    DroppedAck(u16),
+    /// The packet with the opcode was held back to be reordered.
+    Reordered(u16),
+    /// The packet with the opcode was sent twice.
+    Duplicated(u16),
+}
+
+/// A UDP proxy forwarding packets between a client and a server, dropping,
+/// reordering and duplicating them as configured.
+///
+/// The client sends its requests to the address of the injector. Every transfer of
+/// the server is answered from its own port of the injector, so the client sees a
+/// transfer identifier per transfer like it would talking to the server directly.
+pub struct FaultInjector {
+    config: FaultConfig,
+    /// Socket the client sends its requests to.
+    listener: Arc<UdpSocket>,
+    /// Socket the server is talked to from.
+    upstream: Arc<UdpSocket>,
+    server_addr: SocketAddr,
+    state: Mutex<FaultState>,
+}
+
+struct FaultState {
+    rng: XorShiftRng,
+    client: Option<SocketAddr>,
+    /// Socket facing the client for every transfer, by the address of the server side.
+    transfers: HashMap<SocketAddr, Arc<UdpSocket>>,
+    /// Packets held back to be reordered, with the socket and address to send them with.
+    held: Vec<(Vec<u8>, Arc<UdpSocket>, SocketAddr)>,
+    reordered: usize,
+    faults: Vec<Fault>,
+}
+
+impl FaultInjector {
+    /// Starts forwarding to the server listening on `server_addr`.
+    pub fn start(server_addr: SocketAddr, config: FaultConfig) -> Arc<FaultInjector> {
+        let injector = Arc::new(FaultInjector {
+            config: config,
+            listener: Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap()),
+            upstream: Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap()),
+            server_addr: server_addr,
+            state: Mutex::new(FaultState {
+                // A fixed seed, the same faults are drawn on every run.
+                rng: XorShiftRng::from_seed([0x7466, 0x7470, 0x6661, 0x756c]),
+                client: None,
+                transfers: HashMap::new(),
+                held: Vec::new(),
+                reordered: 0,
+                faults: Vec::new(),
+            }),
+        });
+        let requests = injector.clone();
+        thread::spawn(move || requests.forward_requests());
+        let answers = injector.clone();
+        thread::spawn(move || FaultInjector::forward_answers(answers));
+        injector
+    }
+
+    /// Address the client sends its requests to.
+    pub fn addr(&self) -> SocketAddr {
+        self.listener.local_addr().unwrap()
+    }
+
+    /// Faults injected so far, in the order they happened.
+    pub fn faults(&self) -> Vec<Fault> {
+        self.state.lock().unwrap().faults.clone()
+    }
+
+    /// Passes the requests of the client on to the listening port of the server.
+    fn forward_requests(&self) {
+        let mut buf = vec![0; 65536];
+        while let Ok((n, from)) = self.listener.recv_from(&mut buf) {
+            self.state.lock().unwrap().client = Some(from);
+            self.inject(&buf[..n], self.upstream.clone(), self.server_addr);
+        }
+    }
+
+    /// Passes the packets of every transfer of the server on to the client, and
+    /// starts forwarding the answers of the client for new transfers.
+    fn forward_answers(injector: Arc<FaultInjector>) {
+        let mut buf = vec![0; 65536];
+        while let Ok((n, transfer)) = injector.upstream.recv_from(&mut buf) {
+            let (socket, client) = {
+                let mut state = injector.state.lock().unwrap();
+                let client = match state.client {
+                    Some(client) => client,
+                    None => continue,
+                };
+                let socket = state.transfers.entry(transfer).or_insert_with(|| {
+                    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+                    let forwarding = injector.clone();
+                    let receiving = socket.clone();
+                    thread::spawn(move || forwarding.forward_transfer(&receiving, transfer));
+                    socket
+                }).clone();
+                (socket, client)
+            };
+            injector.inject(&buf[..n], socket, client);
+        }
+    }
+
+    /// Passes the packets the client sends to `socket` on to the server port `transfer`.
+    fn forward_transfer(&self, socket: &UdpSocket, transfer: SocketAddr) {
+        let mut buf = vec![0; 65536];
+        while let Ok((n, _)) = socket.recv_from(&mut buf) {
+            self.inject(&buf[..n], self.upstream.clone(), transfer);
+        }
+    }
+
+    /// Sends `packet` to `to` from `socket`, unless a fault is drawn for it.
+    fn inject(&self, packet: &[u8], socket: Arc<UdpSocket>, to: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        let (opcode, block_id) = header(packet);
+        if opcode == Opcode::DATA as u16 && state.rng.gen::<f64>() < self.config.data_drop_rate {
+            state.faults.push(Fault::DroppedData(block_id));
+            return
+        }
+        if opcode == Opcode::ACK as u16 && state.rng.gen::<f64>() < self.config.ack_drop_rate {
+            state.faults.push(Fault::DroppedAck(block_id));
+            return
+        }
+        let copies = if state.rng.gen::<f64>() < self.config.duplicate_rate {
+            state.faults.push(Fault::Duplicated(opcode));
+            2
+        } else {
+            1
+        };
+        let reorderable = opcode == Opcode::DATA as u16 || opcode == Opcode::ACK as u16;
+        if reorderable && state.reordered < self.config.reorder_count {
+            state.faults.push(Fault::Reordered(opcode));
+            state.reordered += 1;
+            for _ in 0..copies {
+                state.held.push((packet.to_vec(), socket.clone(), to));
+            }
+            if state.reordered == self.config.reorder_count {
+                while let Some((packet, socket, to)) = state.held.pop() {
+                    let _ = socket.send_to(&packet, to);
+                }
+            }
+            return
+        }
+        for _ in 0..copies {
+            let _ = socket.send_to(packet, to);
+        }
+    }
+}
+
+/// Returns the opcode and the following field of `packet`, the block number for
+/// DATA and ACK packets.
+fn header(packet: &[u8]) -> (u16, u16) {
+    let field = |i: usize| if packet.len() >= i + 2 { (packet[i] as u16) << 8 | packet[i + 1] as u16 } else { 0 };
+    (field(0), field(2))
+}
@@ -0,0 +1,646 @@
+//! A TFTP client built on tokio, enabled with the `tokio` feature.
+//!
+//! Downloads are futures driven by the tokio reactor and share the packet types and
+//! errors of the `client` module. Unlike the mio client, options are not negotiated.
+//! Like it, the last packet is retransmitted on the schedule of `ClientConfig` while
+//! the server is silent, and the download fails with `Error::Timeout` once
+//! `max_retries` retransmissions went unanswered.
+//!
+//! `spawn_get` runs a download as a task of its own and returns a `TransferHandle`
+//! that can cancel it.
+
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use tokio;
+use tokio::net::UdpSocket;
+use tokio::prelude::{future, Async, Future, Poll};
+use tokio::prelude::task::{self, Task};
+use tokio::timer::Delay;
+
+use client::{Backoff, ClientConfig, Error, TransferStats, request_filename};
+use packet::{Mode, RequestPacket, DataPacketOctet, AckPacket, ErrorPacket, EncodePacket, RawPacket,
+             Opcode, Error as ErrorCode, recv_buffer_size};
+
+static MAX_DATA_SIZE: usize = 512;
+
+/// Returns `Async::NotReady` from the enclosing `poll` until the value is ready.
+macro_rules! try_ready {
+    ($e:expr) => (match try!($e) {
+        Async::Ready(value) => value,
+        Async::NotReady => return Ok(Async::NotReady),
+    })
+}
+
+enum State {
+    /// The request could not be started, reported on the first poll.
+    Failed(Error),
+    SendReadRequest(Vec<u8>),
+    ReceivingData(u16),
+    /// Acknowledges a block, then waits for the given block or finishes on `None`.
+    SendAck(u16, Option<u16>),
+    Done,
+}
+
+/// State of a download shared with its `TransferHandle`.
+#[derive(Default)]
+struct Control {
+    cancelled: AtomicBool,
+    /// Task polling the download, woken up when it is cancelled.
+    task: Mutex<Option<Task>>,
+    /// Task waiting for the result of a spawned download, woken up when it is sent.
+    waiter: Mutex<Option<Task>>,
+    stats: Mutex<TransferStats>,
+}
+
+impl Control {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        notify(&self.task);
+    }
+
+    fn stats(&self) -> TransferStats {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+fn notify(task: &Mutex<Option<Task>>) {
+    if let Some(ref task) = *task.lock().unwrap() {
+        task.notify();
+    }
+}
+
+/// Sends the result of a spawned download to its `TransferHandle`.
+///
+/// The waiting task is woken up when the reporter is dropped, after the result was
+/// sent or when the download was dropped unfinished.
+struct Reporter<W> {
+    sender: mpsc::Sender<Result<W, Error>>,
+    control: Arc<Control>,
+}
+
+impl<W> Drop for Reporter<W> {
+    fn drop(&mut self) {
+        notify(&self.control.waiter);
+    }
+}
+
+/// Future of a download started with `get`, resolving to the writer once the file is
+/// written to it.
+pub struct Get<W> {
+    socket: Option<UdpSocket>,
+    remote_addr: SocketAddr,
+    /// Whether `remote_addr` is the port the server answered from (its TID).
+    tid_known: bool,
+    writer: Option<W>,
+    buf: Vec<u8>,
+    state: State,
+    control: Arc<Control>,
+    /// Bytes of the last read request or acknowledgment, sent again on retransmission.
+    last_sent: Vec<u8>,
+    retransmit: Backoff,
+    max_retries: u32,
+    /// Retransmissions of the last packet sent so far.
+    retransmit_count: u32,
+    /// Expires when the last packet sent is to be retransmitted, `None` until the
+    /// request is sent.
+    timeout: Option<Delay>,
+}
+
+/// Downloads the file at `path` from the server at `remote_addr` into `writer`.
+pub fn get<W: io::Write>(remote_addr: SocketAddr, path: &Path, mode: Mode, writer: W) -> Get<W> {
+    get_with_config(remote_addr, path, mode, writer, &ClientConfig::default())
+}
+
+/// Downloads the file at `path` from the server at `remote_addr` into `writer` like
+/// `get`, retransmitting on the schedule of `config`.
+///
+/// Of the config only `retransmit` and `max_retries` apply.
+pub fn get_with_config<W: io::Write>(remote_addr: SocketAddr, path: &Path, mode: Mode, writer: W,
+                                     config: &ClientConfig) -> Get<W> {
+    let any = str::FromStr::from_str("0.0.0.0:0").unwrap();
+    let started = request_filename(path)
+        .map(|filename| RequestPacket::read_request(filename, mode).encode().into_bytes())
+        .and_then(|request| UdpSocket::bind(&any).map(|socket| (socket, request)).map_err(From::from));
+    let (socket, state) = match started {
+        Ok((socket, request)) => (Some(socket), State::SendReadRequest(request)),
+        Err(err) => (None, State::Failed(err)),
+    };
+    Get {
+        socket: socket,
+        remote_addr: remote_addr,
+        tid_known: false,
+        writer: Some(writer),
+        // One byte to spare, so that an oversized block is not truncated to a valid one.
+        buf: vec![0; recv_buffer_size(MAX_DATA_SIZE) + 1],
+        state: state,
+        control: Arc::new(Control::default()),
+        last_sent: Vec::new(),
+        retransmit: config.retransmit.clone().normalized(),
+        max_retries: config.max_retries,
+        retransmit_count: 0,
+        timeout: None,
+    }
+}
+
+/// Starts downloading the file at `path` into `writer` as a task of its own, like
+/// `get`.
+///
+/// Must be called from a task of a tokio runtime, the download is spawned on it.
+pub fn spawn_get<W>(remote_addr: SocketAddr, path: &Path, mode: Mode, writer: W) -> TransferHandle<W>
+    where W: io::Write + Send + 'static
+{
+    let download = get(remote_addr, path, mode, writer);
+    let control = download.control.clone();
+    let (sender, receiver) = mpsc::channel();
+    let reporter = Reporter {
+        sender: sender,
+        control: control.clone(),
+    };
+    tokio::spawn(download.then(move |result| {
+        // The handle may be gone already, the result is not needed then.
+        let _ = reporter.sender.send(result);
+        Ok(())
+    }));
+    TransferHandle {
+        control: control,
+        result: Some(receiver),
+    }
+}
+
+/// A download started with `spawn_get`.
+///
+/// The download is cancelled when the handle is dropped, unless `wait` or `cancel`
+/// was called.
+pub struct TransferHandle<W> {
+    control: Arc<Control>,
+    /// `None` once `wait` or `cancel` took it.
+    result: Option<mpsc::Receiver<Result<W, Error>>>,
+}
+
+/// Waits for the result of a spawned download.
+fn finished<W>(control: Arc<Control>, receiver: mpsc::Receiver<Result<W, Error>>)
+               -> impl Future<Item = Result<W, Error>, Error = Error> {
+    future::poll_fn(move || {
+        *control.waiter.lock().unwrap() = Some(task::current());
+        match receiver.try_recv() {
+            Ok(result) => Ok(Async::Ready(result)),
+            Err(mpsc::TryRecvError::Empty) => Ok(Async::NotReady),
+            Err(mpsc::TryRecvError::Disconnected) =>
+                Err(Error::Io(io::Error::new(io::ErrorKind::Other, "download task was dropped"))),
+        }
+    })
+}
+
+impl<W> TransferHandle<W> {
+    /// Returns the statistics of the blocks written so far.
+    pub fn stats(&self) -> TransferStats {
+        self.control.stats()
+    }
+
+    /// Waits for the download to finish, resolving to the writer and the statistics
+    /// of the transfer.
+    pub fn wait(mut self) -> impl Future<Item = (W, TransferStats), Error = Error> {
+        let control = self.control.clone();
+        finished(self.control.clone(), self.result.take().unwrap())
+            .and_then(move |result| result.map(|writer| (writer, control.stats())))
+    }
+
+    /// Cancels the download, sending an error packet to the server, and waits for the
+    /// task to stop.
+    ///
+    /// Resolves to the statistics of the blocks written before the download stopped,
+    /// or `None` if it had already finished or failed.
+    pub fn cancel(mut self) -> impl Future<Item = Option<TransferStats>, Error = Error> {
+        self.control.cancel();
+        let control = self.control.clone();
+        finished(self.control.clone(), self.result.take().unwrap()).then(move |result| Ok(match result {
+            Ok(Err(Error::Cancelled)) => Some(control.stats()),
+            _ => None,
+        }))
+    }
+}
+
+impl<W> Drop for TransferHandle<W> {
+    fn drop(&mut self) {
+        if self.result.is_some() {
+            self.control.cancel();
+        }
+    }
+}
+
+impl<W: io::Write> Get<W> {
+    /// Handles a packet received while waiting for block `current_id`.
+    fn handle_packet(&mut self, n: usize, current_id: u16) -> Result<State, Error> {
+        let packet = RawPacket::new(mem::replace(&mut self.buf, Vec::new()), n);
+        let state = match packet.opcode() {
+            Some(Opcode::DATA) => {
+                let data_packet: DataPacketOctet = try!(packet.decode());
+                if data_packet.data().len() > MAX_DATA_SIZE {
+                    return Err(Error::ProtocolViolation(format!(
+                        "block {} has {} bytes, more than the block size of {}",
+                        data_packet.block_id(), data_packet.data().len(), MAX_DATA_SIZE)))
+                }
+                if data_packet.block_id() == current_id {
+                    try!(self.writer.as_mut().unwrap().write_all(data_packet.data()));
+                    {
+                        let mut stats = self.control.stats.lock().unwrap();
+                        stats.bytes += data_packet.data().len() as u64;
+                        stats.blocks += 1;
+                    }
+                    let next_id = if data_packet.data().len() < MAX_DATA_SIZE {
+                        None
+                    } else {
+                        Some(current_id.wrapping_add(1))
+                    };
+                    State::SendAck(current_id, next_id)
+                } else if data_packet.block_id() == current_id.wrapping_sub(1) {
+                    // The server did not get our ACK and resends the previous block.
+                    State::SendAck(data_packet.block_id(), Some(current_id))
+                } else {
+                    State::ReceivingData(current_id)
+                }
+            }
+            Some(Opcode::ERROR) => {
+                let error: ErrorPacket = try!(packet.decode());
+                return Err(Error::from_server(error.into_owned()))
+            }
+            _ => State::ReceivingData(current_id),
+        };
+        self.buf = packet.get_buffer();
+        Ok(state)
+    }
+
+    /// Records that `packet` was sent, restarting the retransmission schedule.
+    fn packet_sent(&mut self, packet: &[u8]) {
+        self.last_sent.clear();
+        self.last_sent.extend_from_slice(packet);
+        self.retransmit_count = 0;
+        let expires = Instant::now() + self.retransmit.timeout(0);
+        match self.timeout {
+            Some(ref mut timeout) => timeout.reset(expires),
+            None => self.timeout = Some(Delay::new(expires)),
+        }
+    }
+
+    /// Retransmits the last packet if the server was silent for too long, fails with
+    /// `Error::Timeout` after `max_retries` retransmissions.
+    ///
+    /// Returns once the task is woken up by the next timeout.
+    fn poll_timeout(&mut self) -> Result<(), Error> {
+        loop {
+            {
+                let timeout = self.timeout.as_mut().expect("a packet was sent before waiting for an answer");
+                match timeout.poll() {
+                    Ok(Async::Ready(())) => {}
+                    Ok(Async::NotReady) => return Ok(()),
+                    Err(err) => return Err(Error::Io(io::Error::new(io::ErrorKind::Other, err))),
+                }
+            }
+            if self.retransmit_count >= self.max_retries {
+                return Err(Error::Timeout)
+            }
+            self.retransmit_count += 1;
+            self.control.stats.lock().unwrap().retransmits += 1;
+            debug!("Timed out, retransmitting (attempt {})", self.retransmit_count);
+            // A packet that would block is lost, the next timeout sends it again.
+            try!(self.socket.as_mut().unwrap().poll_send_to(&self.last_sent, &self.remote_addr));
+            let expires = Instant::now() + self.retransmit.timeout(self.retransmit_count);
+            self.timeout.as_mut().unwrap().reset(expires);
+        }
+    }
+
+    /// Checks that a packet received from `from` belongs to the transfer.
+    ///
+    /// The first answer fixes the port of the server. Packets from other ports are
+    /// answered with an error and dropped, they must not take over the transfer.
+    fn check_tid(&mut self, from: SocketAddr) -> bool {
+        if !self.tid_known {
+            self.remote_addr = from;
+            self.tid_known = true;
+            return true
+        }
+        if from == self.remote_addr {
+            return true
+        }
+        warn!("Packet from unknown transfer id {}", from);
+        let error = ErrorPacket::new(ErrorCode::UnknownTransferId, "Unknown transfer ID").encode();
+        // Best effort, the packet is dropped whether the error could be sent or not.
+        let _ = self.socket.as_mut().unwrap().poll_send_to(error.packet_buf(), &from);
+        false
+    }
+
+    /// Ends a cancelled download, telling the server if the request was sent.
+    fn abort(&mut self) -> Error {
+        let started = match self.state {
+            State::ReceivingData(_) | State::SendAck(..) => true,
+            _ => false,
+        };
+        if started {
+            let error = ErrorPacket::new(ErrorCode::Undefined, "Transfer cancelled").encode();
+            // Best effort, the download fails whether the error could be sent or not.
+            let _ = self.socket.as_mut().unwrap().poll_send_to(error.packet_buf(), &self.remote_addr);
+        }
+        self.state = State::Done;
+        Error::Cancelled
+    }
+}
+
+impl<W: io::Write> Future for Get<W> {
+    type Item = W;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<W, Error> {
+        *self.control.task.lock().unwrap() = Some(task::current());
+        if self.control.cancelled.load(Ordering::SeqCst) {
+            return Err(self.abort())
+        }
+        loop {
+            let next = match self.state {
+                State::Failed(_) => match mem::replace(&mut self.state, State::Done) {
+                    State::Failed(err) => return Err(err),
+                    _ => unreachable!(),
+                },
+                State::SendReadRequest(_) => {
+                    let request = match mem::replace(&mut self.state, State::Done) {
+                        State::SendReadRequest(request) => request,
+                        _ => unreachable!(),
+                    };
+                    match try!(self.socket.as_mut().unwrap().poll_send_to(&request, &self.remote_addr)) {
+                        Async::Ready(_) => {}
+                        Async::NotReady => {
+                            self.state = State::SendReadRequest(request);
+                            return Ok(Async::NotReady)
+                        }
+                    }
+                    self.packet_sent(&request);
+                    State::ReceivingData(1)
+                }
+                State::ReceivingData(current_id) => {
+                    let received = try!(self.socket.as_mut().unwrap().poll_recv_from(&mut self.buf));
+                    let (n, from) = match received {
+                        Async::Ready(received) => received,
+                        Async::NotReady => {
+                            try!(self.poll_timeout());
+                            return Ok(Async::NotReady)
+                        }
+                    };
+                    if !self.check_tid(from) {
+                        continue
+                    }
+                    try!(self.handle_packet(n, current_id))
+                }
+                State::SendAck(block_id, next_id) => {
+                    let ack = AckPacket::new(block_id).encode_into_array();
+                    try_ready!(self.socket.as_mut().unwrap().poll_send_to(&ack, &self.remote_addr));
+                    self.packet_sent(&ack);
+                    match next_id {
+                        Some(next_id) => State::ReceivingData(next_id),
+                        None => State::Done,
+                    }
+                }
+                State::Done => panic!("download polled after it finished"),
+            };
+            self.state = next;
+            if let State::Done = self.state {
+                return Ok(Async::Ready(self.writer.take().unwrap()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{SocketAddr, UdpSocket};
+    use std::path::Path;
+    use std::sync::mpsc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    use tokio::prelude::future;
+    use tokio::runtime;
+    use tokio::runtime::current_thread::Runtime;
+
+    use client::{Backoff, ClientConfig, Error, TransferStats};
+    use packet::{Mode, AckPacket, DataPacketOctet, EncodePacket, Error as ErrorCode, ErrorPacket, RawPacket};
+    use test_server::TestServer;
+
+    use super::{TransferHandle, get, get_with_config, spawn_get};
+
+    #[test]
+    fn file_is_downloaded() {
+        let server = TestServer::new();
+        let contents: Vec<u8> = (0..1300).map(|i| (i % 251) as u8).collect();
+        server.create_file("file.bin", &contents);
+
+        let mut runtime = Runtime::new().unwrap();
+        let output = runtime.block_on(get(server.addr(), Path::new("file.bin"), Mode::Octet, Vec::new())).unwrap();
+        assert_eq!(contents, output);
+    }
+
+    #[test]
+    fn missing_file_is_a_server_error() {
+        let server = TestServer::new();
+
+        let mut runtime = Runtime::new().unwrap();
+        match runtime.block_on(get(server.addr(), Path::new("missing.bin"), Mode::Octet, Vec::new())) {
+            Err(Error::Server(err)) => assert_eq!(ErrorCode::FileNotFound, err.error()),
+            other => panic!("expected a server error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn invalid_filename_fails_on_first_poll() {
+        let server = TestServer::new();
+
+        let mut runtime = Runtime::new().unwrap();
+        match runtime.block_on(get(server.addr(), Path::new("a\0b"), Mode::Octet, Vec::new())) {
+            Err(Error::InvalidFilename(_)) => {}
+            other => panic!("expected an invalid filename, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn oversized_block_is_rejected() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (_, client) = server.recv_from(&mut buf).unwrap();
+            server.send_to(DataPacketOctet::from_slice(1, &[1; 2000]).encode().packet_buf(), client).unwrap();
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        match runtime.block_on(get(addr, Path::new("file.bin"), Mode::Octet, Vec::new())) {
+            Err(Error::ProtocolViolation(_)) => {}
+            other => panic!("expected a protocol violation, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    fn fast_retransmit(max_retries: u32) -> ClientConfig {
+        ClientConfig {
+            retransmit: Backoff {
+                base: Duration::from_millis(50),
+                multiplier: 2,
+                max: Duration::from_millis(200),
+            },
+            max_retries: max_retries,
+            ..ClientConfig::default()
+        }
+    }
+
+    /// Sends `block` from `socket` and returns the id of the ACK it is answered with.
+    fn send_block(socket: &UdpSocket, client: SocketAddr, block_id: u16, block: &[u8]) -> u16 {
+        socket.send_to(DataPacketOctet::from_slice(block_id, block).encode().packet_buf(), client).unwrap();
+        let mut buf = vec![0; 512];
+        let (n, _) = socket.recv_from(&mut buf).unwrap();
+        let ack: AckPacket = RawPacket::new(buf, n).decode().expect("expected an ack");
+        ack.block_id()
+    }
+
+    #[test]
+    fn packet_from_another_port_is_refused() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = thread::spawn(move || {
+            let mut buf = vec![0; 512];
+            let (_, client) = listener.recv_from(&mut buf).unwrap();
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            assert_eq!(1, send_block(&socket, client, 1, &[1; 512]));
+
+            // Another host tries to take over the transfer with the final block.
+            let intruder = UdpSocket::bind("127.0.0.1:0").unwrap();
+            intruder.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            intruder.send_to(DataPacketOctet::from_slice(2, &[9; 10]).encode().packet_buf(), client).unwrap();
+            let (n, _) = intruder.recv_from(&mut buf).unwrap();
+            let raw = RawPacket::new(buf, n);
+            let error: ErrorPacket = raw.decode().expect("expected an error");
+            assert_eq!(ErrorCode::UnknownTransferId, error.error());
+
+            assert_eq!(2, send_block(&socket, client, 2, &[2; 10]));
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let output = runtime.block_on(get(addr, Path::new("file.bin"), Mode::Octet, Vec::new())).unwrap();
+        let mut expected = vec![1; 512];
+        expected.extend_from_slice(&[2; 10]);
+        assert_eq!(expected, output);
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn lost_read_request_is_retransmitted() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = vec![0; 512];
+            // The first read request is lost.
+            listener.recv_from(&mut buf).unwrap();
+            let (_, client) = listener.recv_from(&mut buf).unwrap();
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.send_to(DataPacketOctet::from_slice(1, &[1; 10]).encode().packet_buf(), client).unwrap();
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let download = get_with_config(addr, Path::new("file.bin"), Mode::Octet, Vec::new(), &fast_retransmit(5));
+        assert_eq!(vec![1; 10], runtime.block_on(download).unwrap());
+    }
+
+    #[test]
+    fn silent_server_times_out() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut runtime = Runtime::new().unwrap();
+        let download = get_with_config(addr, Path::new("file.bin"), Mode::Octet, Vec::new(), &fast_retransmit(2));
+        match runtime.block_on(download) {
+            Err(Error::Timeout) => {}
+            other => panic!("expected the download to time out, got {:?}", other.map(|_| ())),
+        }
+
+        // The read request was sent once and retransmitted twice.
+        listener.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let mut buf = vec![0; 512];
+        let mut received = 0;
+        while listener.recv_from(&mut buf).is_ok() {
+            received += 1;
+        }
+        assert_eq!(3, received);
+    }
+
+    /// Serves the first 3 blocks of a 10 block file from a socket of its own, then
+    /// reports them acknowledged on the channel.
+    ///
+    /// The thread returns the error code of the next packet, if it is an error.
+    fn serve_three_blocks() -> (UdpSocket, mpsc::Receiver<()>, JoinHandle<Option<ErrorCode>>) {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let serving = server.try_clone().unwrap();
+        let (acked, wait_acked) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut buf = vec![0; 516];
+            let (_, client) = serving.recv_from(&mut buf).unwrap();
+            for block_id in 1..4 {
+                let data = DataPacketOctet::from_slice(block_id, &[block_id as u8; 512]).encode();
+                serving.send_to(data.packet_buf(), client).unwrap();
+                serving.recv_from(&mut buf).unwrap();
+            }
+            acked.send(()).unwrap();
+            let (n, _) = serving.recv_from(&mut buf).unwrap();
+            let packet = RawPacket::new(buf, n);
+            let error = packet.decode::<ErrorPacket>().ok().map(|error| error.error());
+            error
+        });
+        (server, wait_acked, thread)
+    }
+
+    fn spawn_download(runtime: &mut runtime::Runtime, addr: SocketAddr) -> TransferHandle<Vec<u8>> {
+        runtime.block_on(future::lazy(move || {
+            Ok::<_, ()>(spawn_get(addr, Path::new("file.bin"), Mode::Octet, Vec::new()))
+        })).unwrap()
+    }
+
+    #[test]
+    fn cancelled_download_reports_the_blocks_written() {
+        let (server, wait_acked, serving) = serve_three_blocks();
+        let mut runtime = runtime::Runtime::new().unwrap();
+        let handle = spawn_download(&mut runtime, server.local_addr().unwrap());
+
+        wait_acked.recv().unwrap();
+        let written = TransferStats { bytes: 3 * 512, blocks: 3, retransmits: 0 };
+        assert_eq!(written, handle.stats());
+        assert_eq!(Some(written), runtime.block_on(handle.cancel()).unwrap());
+        assert_eq!(Some(ErrorCode::Undefined), serving.join().unwrap());
+    }
+
+    #[test]
+    fn dropped_handle_cancels_the_download() {
+        let (server, wait_acked, serving) = serve_three_blocks();
+        let mut runtime = runtime::Runtime::new().unwrap();
+        let handle = spawn_download(&mut runtime, server.local_addr().unwrap());
+
+        wait_acked.recv().unwrap();
+        drop(handle);
+        assert_eq!(Some(ErrorCode::Undefined), serving.join().unwrap());
+    }
+
+    #[test]
+    fn spawned_download_resolves_to_the_file_and_its_stats() {
+        let server = TestServer::new();
+        let contents: Vec<u8> = (0..1300).map(|i| (i % 251) as u8).collect();
+        server.create_file("file.bin", &contents);
+
+        let mut runtime = runtime::Runtime::new().unwrap();
+        let handle = spawn_download(&mut runtime, server.addr());
+        let (output, stats) = runtime.block_on(handle.wait()).unwrap();
+        assert_eq!(contents, output);
+        assert_eq!(TransferStats { bytes: 1300, blocks: 3, retransmits: 0 }, stats);
+    }
+}
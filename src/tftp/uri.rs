@@ -0,0 +1,200 @@
+//! `tftp://` URIs (RFC 3617).
+//!
+//! A URI names a file on a server, `tftp://host[:port]/path`. Parsing does not touch
+//! the network, the host name is only looked up by `TftpUri::resolve`.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::{self, FromStr};
+
+/// Port of a URI without one.
+pub const DEFAULT_PORT: u16 = 69;
+
+/// A parsed `tftp://` URI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TftpUri {
+    /// Host name or IP address, without the brackets of an IPv6 literal.
+    pub host: String,
+    /// Port of the server, 69 unless the URI names another one.
+    pub port: u16,
+    /// Path of the file on the server, percent-decoded and without the leading `/`.
+    pub path: PathBuf,
+}
+
+impl TftpUri {
+    /// Looks up the host and returns the address of the server.
+    ///
+    /// If the host has several addresses the first one is returned.
+    pub fn resolve(&self) -> io::Result<SocketAddr> {
+        let mut addrs = try!((&self.host[..], self.port).to_socket_addrs());
+        addrs.next().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no address"))
+    }
+
+    /// Returns the path of the file on the server, without the leading `/`.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl fmt::Display for TftpUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            try!(write!(f, "tftp://[{}]", self.host));
+        } else {
+            try!(write!(f, "tftp://{}", self.host));
+        }
+        if self.port != DEFAULT_PORT {
+            try!(write!(f, ":{}", self.port));
+        }
+        write!(f, "/{}", self.path.display())
+    }
+}
+
+/// Why a string is not a `tftp://` URI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The scheme is missing or not `tftp`.
+    Scheme,
+    /// There is no host before the path.
+    MissingHost,
+    /// The port is not a number between 0 and 65535.
+    InvalidPort,
+    /// There is no path, or it has an invalid percent escape.
+    InvalidPath,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Scheme => write!(f, "URI scheme is not tftp"),
+            ParseError::MissingHost => write!(f, "URI has no host"),
+            ParseError::InvalidPort => write!(f, "URI has an invalid port"),
+            ParseError::InvalidPath => write!(f, "URI has no valid file path"),
+        }
+    }
+}
+
+impl error::Error for ParseError {
+    fn description(&self) -> &str { "invalid tftp URI" }
+}
+
+impl FromStr for TftpUri {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<TftpUri, ParseError> {
+        let scheme_len = "tftp://".len();
+        // Sliced with `get`, the scheme may end inside a multibyte character.
+        if !s.get(..scheme_len).map_or(false, |scheme| scheme.eq_ignore_ascii_case("tftp://")) {
+            return Err(ParseError::Scheme)
+        }
+        let rest = &s[scheme_len..];
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+
+        let (host, port) = if authority.starts_with('[') {
+            let end = try!(authority.find(']').ok_or(ParseError::MissingHost));
+            (&authority[1..end], &authority[end + 1..])
+        } else {
+            match authority.rfind(':') {
+                Some(i) => (&authority[..i], &authority[i..]),
+                None => (authority, ""),
+            }
+        };
+        if host.is_empty() {
+            return Err(ParseError::MissingHost)
+        }
+        let port = match port {
+            "" => DEFAULT_PORT,
+            port if port.starts_with(':') => try!(port[1..].parse().map_err(|_| ParseError::InvalidPort)),
+            _ => return Err(ParseError::InvalidPort),
+        };
+
+        let path = try!(percent_decode(path).ok_or(ParseError::InvalidPath));
+        if path.is_empty() {
+            return Err(ParseError::InvalidPath)
+        }
+        Ok(TftpUri {
+            host: host.to_string(),
+            port: port,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+/// Decodes the `%XX` escapes of `s`, `None` if one is malformed or the result is
+/// not UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            // `from_str_radix` would accept a sign as the first digit.
+            let hex = match tail.get(..2) {
+                Some(hex) if hex.iter().all(u8::is_ascii_hexdigit) => str::from_utf8(hex).unwrap(),
+                _ => return None,
+            };
+            bytes.push(match u8::from_str_radix(hex, 16) {
+                Ok(byte) => byte,
+                Err(_) => return None,
+            });
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::path::Path;
+
+    use super::{ParseError, TftpUri};
+
+    #[test]
+    fn host_without_port_uses_port_69() {
+        let uri: TftpUri = "tftp://boot.example.com/pxelinux.0".parse().unwrap();
+        assert_eq!("boot.example.com", uri.host);
+        assert_eq!(69, uri.port);
+        assert_eq!(Path::new("pxelinux.0"), uri.path());
+    }
+
+    #[test]
+    fn ipv6_literal_with_port_is_parsed() {
+        let uri: TftpUri = "tftp://[::1]:6969/test".parse().unwrap();
+        assert_eq!("::1", uri.host);
+        assert_eq!(6969, uri.port);
+        assert_eq!(Path::new("test"), uri.path());
+        assert_eq!("[::1]:6969".parse::<SocketAddr>().unwrap(), uri.resolve().unwrap());
+        assert_eq!("tftp://[::1]:6969/test", uri.to_string());
+    }
+
+    #[test]
+    fn path_is_percent_decoded() {
+        let uri: TftpUri = "TFTP://127.0.0.1/boot/my%20image.bin".parse().unwrap();
+        assert_eq!(Path::new("boot/my image.bin"), uri.path());
+        assert_eq!("127.0.0.1:69".parse::<SocketAddr>().unwrap(), uri.resolve().unwrap());
+    }
+
+    #[test]
+    fn invalid_uris_are_rejected() {
+        assert_eq!(Err(ParseError::Scheme), "http://example.com/file".parse::<TftpUri>());
+        assert_eq!(Err(ParseError::Scheme), "example.com/file".parse::<TftpUri>());
+        assert_eq!(Err(ParseError::Scheme), "tftp:/éx".parse::<TftpUri>());
+        assert_eq!(Err(ParseError::MissingHost), "tftp:///file".parse::<TftpUri>());
+        assert_eq!(Err(ParseError::MissingHost), "tftp://:69/file".parse::<TftpUri>());
+        assert_eq!(Err(ParseError::InvalidPort), "tftp://host:port/file".parse::<TftpUri>());
+        assert_eq!(Err(ParseError::InvalidPort), "tftp://host:70000/file".parse::<TftpUri>());
+        assert_eq!(Err(ParseError::InvalidPath), "tftp://host/".parse::<TftpUri>());
+        assert_eq!(Err(ParseError::InvalidPath), "tftp://host".parse::<TftpUri>());
+        assert_eq!(Err(ParseError::InvalidPath), "tftp://host/bad%2".parse::<TftpUri>());
+        assert_eq!(Err(ParseError::InvalidPath), "tftp://host/bad%+1".parse::<TftpUri>());
+    }
+}
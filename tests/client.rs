@@ -0,0 +1,1026 @@
+extern crate mio;
+extern crate tftp;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::env;
+use std::fs;
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tftp::client::{self, get_from, get_from_with_config, get_into_vec, get_uri_into_vec, get_to_file,
+                   get_to_file_with_config, put_with_options, tftp_get_retry, Backoff, Client, ClientConfig,
+                   TransferInfo, TransferOptions, TransferStats};
+#[cfg(feature = "serde")]
+use tftp::client::ResumableTransfer;
+use tftp::packet::{Mode, Error, RawPacket, RequestPacket, DataPacketOctet, AckPacket,
+                   ErrorPacket, OackPacket, EncodePacket, recv_buffer_size, split_into_blocks};
+use tftp::uri::TftpUri;
+
+/// Spawns a server on an ephemeral port that accepts a single read request.
+///
+/// `handler` conducts the transfer from a newly bound socket and receives the
+/// address of the client together with the requested file name.
+fn mock_server<F>(handler: F) -> SocketAddr
+    where F: FnOnce(&UdpSocket, SocketAddr, String) + Send + 'static
+{
+    let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buf = vec![0; 512];
+        let (n, client) = listener.recv_from(&mut buf).unwrap();
+        let raw = RawPacket::new(buf, n);
+        let request: RequestPacket = raw.decode().expect("expected a read request");
+        let filename = request.filename().unwrap().into_owned();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        handler(&socket, client, filename);
+    });
+    addr
+}
+
+/// Sends `data` block by block, retransmitting a block until it is acknowledged.
+///
+/// The first `dropped_acks` acknowledgments are ignored, as if they were lost. The
+/// transfer is abandoned if a block is not acknowledged after 20 attempts.
+fn serve(socket: &UdpSocket, client: SocketAddr, data: &[u8], dropped_acks: usize) {
+    serve_blocks(socket, client, data, 512, dropped_acks)
+}
+
+/// Like `serve`, with blocks of `block_size` bytes.
+fn serve_blocks(socket: &UdpSocket, client: SocketAddr, data: &[u8], block_size: usize, mut dropped_acks: usize) {
+    socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+    let mut blocks: Vec<(u16, &[u8])> = split_into_blocks(data, block_size as u16).collect();
+    if !data.is_empty() && data.len() % block_size == 0 {
+        let block_id = (blocks.len() as u16).wrapping_add(1);
+        blocks.push((block_id, &[]));
+    }
+
+    for (block_id, block) in blocks {
+        let packet = DataPacketOctet::from_slice(block_id, block).encode();
+        let mut acked = false;
+        for _ in 0..20 {
+            socket.send_to(packet.packet_buf(), client).unwrap();
+
+            let mut buf = vec![0; recv_buffer_size(512)];
+            let n = match socket.recv_from(&mut buf) {
+                Ok((n, _)) => n,
+                Err(_) => continue,
+            };
+            let ack: Option<AckPacket> = RawPacket::new(buf, n).decode().ok();
+            match ack {
+                Some(ref ack) if ack.block_id() == block_id && dropped_acks > 0 => dropped_acks -= 1,
+                Some(ref ack) if ack.block_id() == block_id => {
+                    acked = true;
+                    break
+                }
+                _ => continue,
+            }
+        }
+        if !acked {
+            return
+        }
+    }
+}
+
+/// Acknowledges a write request and receives blocks until the final, short one.
+///
+/// Returns the uploaded bytes, or `None` if the client stops sending.
+fn receive_upload(socket: &UdpSocket, client: SocketAddr) -> Option<Vec<u8>> {
+    receive_upload_with(socket, client, &AckPacket::new(0).encode_into_array(), 512)
+}
+
+/// Like `receive_upload`, answering the write request with `reply` and expecting
+/// blocks of `block_size` bytes.
+fn receive_upload_with(socket: &UdpSocket, client: SocketAddr, reply: &[u8], block_size: usize) -> Option<Vec<u8>> {
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socket.send_to(reply, client).unwrap();
+
+    let mut received = Vec::new();
+    let mut block_id = 1;
+    loop {
+        let mut buf = vec![0; recv_buffer_size(block_size)];
+        let (n, _) = match socket.recv_from(&mut buf) {
+            Ok(result) => result,
+            Err(_) => return None,
+        };
+        let raw = RawPacket::new(buf, n);
+        let block: DataPacketOctet = raw.decode().expect("expected a data block");
+        assert_eq!(block_id, block.block_id());
+        assert!(block.data().len() <= block_size);
+        received.extend_from_slice(block.data());
+        socket.send_to(&AckPacket::new(block_id).encode_into_array(), client).unwrap();
+        if block.data().len() < block_size {
+            return Some(received)
+        }
+        block_id += 1;
+    }
+}
+
+/// Returns an empty directory for the test `name`.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("tftp-client-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// Downloads `filename` from `addr`, failing the test if it does not finish in time.
+fn download(addr: SocketAddr, filename: &str) -> client::Result<Vec<u8>> {
+    let (tx, rx) = channel();
+    let filename = filename.to_string();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let result = get_from(addr, Path::new(&filename), Mode::Octet, &mut output);
+        tx.send(result.map(|_| output)).unwrap();
+    });
+    rx.recv_timeout(Duration::from_secs(5)).expect("transfer did not finish")
+}
+
+#[test]
+fn multi_block_file_is_received() {
+    let data = pattern(512 * 3 + 100);
+    let expected = data.clone();
+    let addr = mock_server(move |socket, client, filename| {
+        assert_eq!("pattern.bin", filename);
+        serve(socket, client, &data, 0);
+    });
+
+    assert_eq!(expected, download(addr, "pattern.bin").unwrap());
+}
+
+#[test]
+fn file_with_exact_block_multiple_is_received() {
+    let data = pattern(512 * 2);
+    let expected = data.clone();
+    let addr = mock_server(move |socket, client, _| serve(socket, client, &data, 0));
+
+    assert_eq!(expected, download(addr, "pattern.bin").unwrap());
+}
+
+#[test]
+fn transfer_survives_dropped_first_ack() {
+    let data = pattern(512 * 2 + 10);
+    let expected = data.clone();
+    let addr = mock_server(move |socket, client, _| serve(socket, client, &data, 1));
+
+    assert_eq!(expected, download(addr, "pattern.bin").unwrap());
+}
+
+#[test]
+fn file_is_received_with_a_single_event_buffer() {
+    let data = pattern(512 * 2 + 1);
+    let expected = data.clone();
+    let addr = mock_server(move |socket, client, _| serve(socket, client, &data, 0));
+
+    let config = ClientConfig {
+        events_capacity: 1,
+        ..ClientConfig::default()
+    };
+    let mut output = Vec::new();
+    get_from_with_config(addr, Path::new("pattern.bin"), Mode::Octet, &mut output, &config).unwrap();
+    assert_eq!(expected, output);
+}
+
+#[test]
+fn file_is_returned_as_a_vec() {
+    let data = pattern(700);
+    let expected = data.clone();
+    let addr = mock_server(move |socket, client, _| serve(socket, client, &data, 0));
+
+    assert_eq!(expected, get_into_vec(addr, Path::new("pattern.bin"), Mode::Octet).unwrap());
+}
+
+#[test]
+fn file_named_by_a_uri_is_returned_as_a_vec() {
+    let data = pattern(700);
+    let expected = data.clone();
+    let addr = mock_server(move |socket, client, filename| {
+        assert_eq!("boot/pattern 1.bin", filename);
+        serve(socket, client, &data, 0)
+    });
+
+    let uri: TftpUri = format!("tftp://{}/boot/pattern%201.bin", addr).parse().unwrap();
+    assert_eq!(expected, get_uri_into_vec(&uri, Mode::Octet).unwrap());
+}
+
+#[test]
+fn transfer_uses_the_given_socket() {
+    let data = pattern(700);
+    let expected = data.clone();
+    let socket = mio::udp::UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let local_addr = socket.local_addr().unwrap();
+    let addr = mock_server(move |socket, client, _| {
+        assert_eq!(local_addr, client);
+        serve(socket, client, &data, 0);
+    });
+
+    let mut output = Vec::new();
+    Client::from_socket(socket, addr).get(Path::new("pattern.bin"), Mode::Octet, &mut output).unwrap();
+    assert_eq!(expected, output);
+}
+
+#[test]
+fn transfer_shares_a_poll_with_other_sources() {
+    let data = pattern(1500);
+    let expected = data.clone();
+    let addr = mock_server(move |socket, client, _| serve(socket, client, &data, 0));
+
+    // Another source of the application, edge-triggered: its event is reported once.
+    let poll = Arc::new(mio::Poll::new().unwrap());
+    let other = mio::udp::UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    poll.register(&other, mio::Token(0), mio::Ready::readable(), mio::PollOpt::edge()).unwrap();
+    UdpSocket::bind("127.0.0.1:0").unwrap().send_to(&[1], other.local_addr().unwrap()).unwrap();
+
+    let (tx, rx) = channel();
+    let client = Client::new(addr).unwrap().with_poll(poll.clone(), mio::Token(7), move |event| {
+        tx.send(event.token()).unwrap();
+    });
+    let mut output = Vec::new();
+    client.get(Path::new("pattern.bin"), Mode::Octet, &mut output).unwrap();
+    assert_eq!(expected, output);
+
+    // The event of the other source was handed to the application.
+    assert_eq!(vec![mio::Token(0)], rx.try_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn file_is_received_through_a_writer_trait_object() {
+    let data = pattern(700);
+    let expected = data.clone();
+    let addr = mock_server(move |socket, client, _| serve(socket, client, &data, 0));
+
+    let mut output = Vec::new();
+    {
+        let writer: &mut Write = &mut output;
+        Client::new(addr).unwrap().get(Path::new("pattern.bin"), Mode::Octet, writer).unwrap();
+    }
+    assert_eq!(expected, output);
+}
+
+#[test]
+fn small_writes_are_uploaded_in_blocks() {
+    let data = pattern(1600);
+    let (tx, rx) = channel();
+    let addr = mock_server(move |socket, client, filename| {
+        assert_eq!("upload.bin", filename);
+        tx.send(receive_upload(socket, client)).unwrap();
+    });
+
+    let client = Client::new(addr).unwrap();
+    let mut stream = client.into_stream(Path::new("upload.bin"), Mode::Octet).unwrap();
+    let chunks: Vec<&[u8]> = data.chunks(95).collect();
+    assert_eq!(17, chunks.len());
+    for chunk in chunks {
+        stream.write_all(chunk).unwrap();
+    }
+    let stats = stream.close().unwrap();
+
+    assert_eq!(Some(data), rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(TransferStats { bytes: 1600, blocks: 4, retransmits: 0 }, stats);
+}
+
+/// Returns at most `chunk` bytes per read, like a slow network stream.
+struct ChunkedReader {
+    data: Vec<u8>,
+    pos: usize,
+    chunk: usize,
+}
+
+impl Read for ChunkedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = cmp::min(cmp::min(self.chunk, buf.len()), self.data.len() - self.pos);
+        buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[test]
+fn short_reads_are_uploaded_in_full_blocks() {
+    let data = pattern(1300);
+    let (tx, rx) = channel();
+    let addr = mock_server(move |socket, client, _| tx.send(receive_upload(socket, client)).unwrap());
+
+    // The server stops at the first short block, a partial one sent early would end the upload.
+    let mut reader = ChunkedReader { data: data.clone(), pos: 0, chunk: 7 };
+    let stats = put_with_options(addr, Path::new("upload.bin"), &mut reader, &TransferOptions::default()).unwrap();
+
+    assert_eq!(Some(data), rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(TransferStats { bytes: 1300, blocks: 3, retransmits: 0 }, stats);
+}
+
+#[test]
+fn upload_uses_the_block_size_acknowledged_by_the_server() {
+    let data = pattern(2500);
+    let (tx, rx) = channel();
+    let addr = mock_server(move |socket, client, _| {
+        let oack = OackPacket::new(&[("blksize", "1024")]).encode();
+        tx.send(receive_upload_with(socket, client, oack.packet_buf(), 1024)).unwrap();
+    });
+
+    let options = TransferOptions { blksize: Some(1024), request_tsize: true, ..Default::default() };
+    let stats = put_with_options(addr, Path::new("upload.bin"), &mut &data[..], &options).unwrap();
+
+    assert_eq!(Some(data), rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(TransferStats { bytes: 2500, blocks: 3, retransmits: 0 }, stats);
+}
+
+#[test]
+fn upload_falls_back_to_default_blocks_without_option_support() {
+    let data = pattern(1100);
+    let (tx, rx) = channel();
+    let addr = mock_server(move |socket, client, _| tx.send(receive_upload(socket, client)).unwrap());
+
+    let options = TransferOptions { blksize: Some(1024), ..Default::default() };
+    let stats = put_with_options(addr, Path::new("upload.bin"), &mut &data[..], &options).unwrap();
+
+    assert_eq!(Some(data), rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(3, stats.blocks);
+}
+
+#[test]
+fn flush_ends_an_upload_with_an_empty_block() {
+    let data = pattern(1024);
+    let (tx, rx) = channel();
+    let addr = mock_server(move |socket, client, _| tx.send(receive_upload(socket, client)).unwrap());
+
+    let mut stream = Client::new(addr).unwrap().into_stream(Path::new("upload.bin"), Mode::Octet).unwrap();
+    stream.write_all(&data).unwrap();
+    stream.flush().unwrap();
+    assert!(stream.write(&[1]).is_err());
+
+    assert_eq!(Some(data), rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(3, stream.close().unwrap().blocks);
+}
+
+#[test]
+fn failed_download_to_file_leaves_no_file_behind() {
+    let dir = temp_dir("failed-download");
+    let dest = dir.join("pattern.bin");
+    let part = dir.join("pattern.bin.part");
+    // A leftover of an earlier attempt is overwritten.
+    fs::write(&part, b"stale").unwrap();
+
+    let addr = mock_server(|socket, client, _| {
+        // The transfer of four blocks breaks down at block 2.
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let block = DataPacketOctet::from_slice(1, &pattern(512)).encode();
+        socket.send_to(block.packet_buf(), client).unwrap();
+        let mut buf = vec![0; recv_buffer_size(512)];
+        socket.recv_from(&mut buf).unwrap();
+        let error = ErrorPacket::new(Error::Undefined, "connection lost").encode();
+        socket.send_to(error.packet_buf(), client).unwrap();
+    });
+    match get_to_file(addr, Path::new("pattern.bin"), Mode::Octet, &dest) {
+        Err(client::Error::Server(_)) => {}
+        other => panic!("expected a server error, got {:?}", other),
+    }
+    assert!(!dest.exists());
+    assert!(!part.exists());
+
+    let data = pattern(512 * 3 + 100);
+    let expected = data.clone();
+    let addr = mock_server(move |socket, client, _| serve(socket, client, &data, 0));
+    get_to_file(addr, Path::new("pattern.bin"), Mode::Octet, &dest).unwrap();
+    assert_eq!(expected, fs::read(&dest).unwrap());
+    assert!(!part.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn synced_download_to_file_is_complete() {
+    let dir = temp_dir("synced_download_to_file_is_complete");
+    let dest = dir.join("pattern.bin");
+    let data = pattern(512 * 2 + 7);
+    let expected = data.clone();
+    let addr = mock_server(move |socket, client, _| serve(socket, client, &data, 0));
+
+    let config = ClientConfig { sync_file: true, ..ClientConfig::default() };
+    get_to_file_with_config(addr, Path::new("pattern.bin"), Mode::Octet, &dest, &config).unwrap();
+    assert_eq!(expected, fs::read(&dest).unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Sends `block` and returns the id of the ACK it is answered with.
+fn send_block(socket: &UdpSocket, client: SocketAddr, block_id: u16, block: &[u8]) -> u16 {
+    socket.send_to(DataPacketOctet::from_slice(block_id, block).encode().packet_buf(), client).unwrap();
+    let mut buf = vec![0; recv_buffer_size(512)];
+    let (n, _) = socket.recv_from(&mut buf).unwrap();
+    let ack: AckPacket = RawPacket::new(buf, n).decode().expect("expected an ack");
+    ack.block_id()
+}
+
+#[test]
+fn duplicate_transfer_from_a_retransmitted_request_is_refused() {
+    let addr = mock_server(|socket, client, _| {
+        assert_eq!(1, send_block(socket, client, 1, &[0xaa; 512]));
+
+        // A second transfer, started by a retransmitted read request, that keeps
+        // sending its first block.
+        let duplicate = UdpSocket::bind("127.0.0.1:0").unwrap();
+        duplicate.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for _ in 0..10 {
+            duplicate.send_to(DataPacketOctet::from_slice(1, &[0xbb; 512]).encode().packet_buf(), client).unwrap();
+            let mut buf = vec![0; 512];
+            let (n, _) = duplicate.recv_from(&mut buf).unwrap();
+            let raw = RawPacket::new(buf, n);
+            let error: ErrorPacket = raw.decode().expect("expected an error");
+            assert_eq!(Error::UnknownTransferId, error.error());
+        }
+
+        assert_eq!(2, send_block(socket, client, 2, &[0xcc; 10]));
+    });
+
+    let mut expected = vec![0xaa; 512];
+    expected.extend_from_slice(&[0xcc; 10]);
+    assert_eq!(expected, download(addr, "file.bin").unwrap());
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn failed_download_is_resumed_after_the_last_block_written() {
+    let dir = temp_dir("resume");
+    let dest = dir.join("pattern.bin");
+    let data = pattern(512 * 8 + 100);
+
+    let served = data.clone();
+    let addr = mock_server(move |socket, client, _| {
+        // The transfer breaks down after block 5.
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for (i, block) in served.chunks(512).take(5).enumerate() {
+            send_block(socket, client, i as u16 + 1, block);
+        }
+        let error = ErrorPacket::new(Error::Undefined, "connection lost").encode();
+        socket.send_to(error.packet_buf(), client).unwrap();
+    });
+    match ResumableTransfer::new("pattern.bin", &dest).resume(Client::new(addr).unwrap(), Mode::Octet) {
+        Err(client::Error::Server(_)) => {}
+        other => panic!("expected a server error, got {:?}", other),
+    }
+    let checkpoint = ResumableTransfer::load(&dest).unwrap();
+    assert!(checkpoint.resume_path().exists());
+    assert_eq!((6, 512 * 5), (checkpoint.block_id, checkpoint.byte_offset));
+
+    let (tx, rx) = channel();
+    let served = data.clone();
+    let addr = mock_server(move |socket, client, _| {
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        // Block 1 differs from the file, it must not be written.
+        let mut acks = vec![send_block(socket, client, 1, &[0xff; 512])];
+        for (i, block) in served.chunks(512).enumerate().skip(5) {
+            acks.push(send_block(socket, client, i as u16 + 1, block));
+        }
+        tx.send(acks).unwrap();
+    });
+    checkpoint.resume(Client::new(addr).unwrap(), Mode::Octet).unwrap();
+
+    assert_eq!(vec![5, 6, 7, 8, 9], rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(data, fs::read(&dest).unwrap());
+    assert!(!dir.join("pattern.bin.tftp-resume").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn resume_ignored_by_the_server_downloads_the_whole_file() {
+    let dir = temp_dir("resume-ignored");
+    let dest = dir.join("pattern.bin");
+    fs::write(&dest, &[0xff; 1024]).unwrap();
+    let checkpoint = ResumableTransfer {
+        block_id: 3,
+        byte_offset: 1024,
+        ..ResumableTransfer::new("pattern.bin", &dest)
+    };
+    checkpoint.save().unwrap();
+
+    let data = pattern(512 * 3 + 100);
+    let expected = data.clone();
+    let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut buf = vec![0; 512];
+        let (_, client) = listener.recv_from(&mut buf).unwrap();
+        // Only the ACK of block 1 moves the transfer on, block 1 is sent again.
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let ack = send_block(&socket, client, 1, &data[..512]);
+        socket.send_to(DataPacketOctet::from_slice(1, &data[..512]).encode().packet_buf(), client).unwrap();
+        let (n, _) = socket.recv_from(&mut buf).unwrap();
+        let raw = RawPacket::new(buf, n);
+        let error: Option<ErrorPacket> = raw.decode().ok();
+        tx.send((ack, error.is_some())).unwrap();
+
+        let mut buf = vec![0; 512];
+        let (_, client) = listener.recv_from(&mut buf).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        serve(&socket, client, &data, 0);
+    });
+    checkpoint.resume(Client::new(addr).unwrap(), Mode::Octet).unwrap();
+
+    assert_eq!((2, true), rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(expected, fs::read(&dest).unwrap());
+    assert!(!dir.join("pattern.bin.tftp-resume").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn probe_without_option_support_returns_none() {
+    let (tx, rx) = channel();
+    let addr = mock_server(move |socket, client, _| {
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let block = DataPacketOctet::from_slice(1, &[1; 512]).encode();
+        socket.send_to(block.packet_buf(), client).unwrap();
+
+        let mut buf = vec![0; recv_buffer_size(512)];
+        let (n, _) = socket.recv_from(&mut buf).unwrap();
+        let raw = RawPacket::new(buf, n);
+        let error: Option<ErrorPacket> = raw.decode().ok();
+        tx.send(error.map(|error| error.error())).unwrap();
+    });
+
+    assert_eq!(None, Client::probe(addr, "pattern.bin").unwrap());
+    assert_eq!(Some(Error::Undefined), rx.recv_timeout(Duration::from_secs(5)).unwrap());
+}
+
+#[test]
+fn negotiated_block_size_is_reported() {
+    let data = pattern(1024 * 2 + 5);
+    let expected = data.clone();
+    let addr = mock_server(move |socket, client, _| {
+        let oack = OackPacket::new(&[("blksize", "1024"), ("tsize", "2053")]).encode();
+        socket.send_to(oack.packet_buf(), client).unwrap();
+        let mut buf = vec![0; recv_buffer_size(512)];
+        let (n, _) = socket.recv_from(&mut buf).unwrap();
+        let ack: AckPacket = RawPacket::new(buf, n).decode().expect("expected an ack");
+        assert_eq!(0, ack.block_id());
+        serve_blocks(socket, client, &data, 1024, 0);
+    });
+
+    let config = ClientConfig {
+        block_size: Some(1024),
+        tsize: true,
+        ..ClientConfig::default()
+    };
+    let mut output = Vec::new();
+    let info = get_from_with_config(addr, Path::new("pattern.bin"), Mode::Octet, &mut output, &config).unwrap();
+    assert_eq!(expected, output);
+    assert_eq!(1024, info.block_size);
+    assert_eq!(Some(2053), info.tsize);
+    assert_eq!(None, info.timeout);
+}
+
+#[test]
+fn transfer_ends_at_the_reported_size_without_the_final_empty_block() {
+    let data = pattern(512 * 2);
+    let expected = data.clone();
+    let addr = mock_server(move |socket, client, _| {
+        let oack = OackPacket::new(&[("tsize", "1024")]).encode();
+        socket.send_to(oack.packet_buf(), client).unwrap();
+        let mut buf = vec![0; recv_buffer_size(512)];
+        let (n, _) = socket.recv_from(&mut buf).unwrap();
+        let ack: AckPacket = RawPacket::new(buf, n).decode().expect("expected an ack");
+        assert_eq!(0, ack.block_id());
+        // A non-conforming server, the empty block 3 is never sent.
+        assert_eq!(1, send_block(socket, client, 1, &data[..512]));
+        assert_eq!(2, send_block(socket, client, 2, &data[512..]));
+    });
+
+    let config = ClientConfig {
+        tsize: true,
+        retransmit: Backoff { base: Duration::from_millis(50), ..Backoff::default() },
+        ..ClientConfig::default()
+    };
+    let mut output = Vec::new();
+    let info = get_from_with_config(addr, Path::new("pattern.bin"), Mode::Octet, &mut output, &config).unwrap();
+    assert_eq!(expected, output);
+    assert_eq!(Some(1024), info.tsize);
+}
+
+#[test]
+fn lost_option_acknowledgment_ack_is_resent() {
+    let data = pattern(1024 + 5);
+    let expected = data.clone();
+    let addr = mock_server(move |socket, client, _| {
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let oack = OackPacket::new(&[("blksize", "1024")]).encode();
+        let mut dropped = false;
+        for _ in 0..20 {
+            socket.send_to(oack.packet_buf(), client).unwrap();
+            let mut buf = vec![0; recv_buffer_size(512)];
+            let n = match socket.recv_from(&mut buf) {
+                Ok((n, _)) => n,
+                Err(_) => continue,
+            };
+            let ack: Option<AckPacket> = RawPacket::new(buf, n).decode().ok();
+            match ack {
+                // The first ACK of block 0 is lost, the OACK has to be resent.
+                Some(ref ack) if ack.block_id() == 0 && !dropped => dropped = true,
+                Some(ref ack) if ack.block_id() == 0 => {
+                    serve_blocks(socket, client, &data, 1024, 0);
+                    return
+                }
+                _ => continue,
+            }
+        }
+    });
+
+    let config = ClientConfig {
+        block_size: Some(1024),
+        ..ClientConfig::default()
+    };
+    let mut output = Vec::new();
+    let info = get_from_with_config(addr, Path::new("pattern.bin"), Mode::Octet, &mut output, &config).unwrap();
+    assert_eq!(expected, output);
+    assert_eq!(1024, info.block_size);
+}
+
+#[test]
+fn lost_option_acknowledgment_and_lost_ack_of_block_zero_are_recovered() {
+    let data = pattern(1024 + 5);
+    let expected = data.clone();
+    let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = vec![0; 512];
+        // The OACK answering the first request is lost, the client sends the request again.
+        let (_, client) = listener.recv_from(&mut buf).unwrap();
+        let (n, again_from) = listener.recv_from(&mut buf).unwrap();
+        assert_eq!(client, again_from);
+        let _: RequestPacket = RawPacket::new(buf, n).decode().expect("expected the request again");
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let oack = OackPacket::new(&[("blksize", "1024")]).encode();
+        socket.send_to(oack.packet_buf(), client).unwrap();
+        // The first ACK of block 0 is lost too, the server sends the OACK once more.
+        for attempt in 0..2 {
+            let mut buf = vec![0; recv_buffer_size(512)];
+            let (n, _) = socket.recv_from(&mut buf).unwrap();
+            let ack: AckPacket = RawPacket::new(buf, n).decode().expect("expected an ack");
+            assert_eq!(0, ack.block_id());
+            if attempt == 0 {
+                socket.send_to(oack.packet_buf(), client).unwrap();
+            }
+        }
+        serve_blocks(&socket, client, &data, 1024, 0);
+    });
+
+    let config = ClientConfig {
+        block_size: Some(1024),
+        retransmit: Backoff { base: Duration::from_millis(50), ..Backoff::default() },
+        ..ClientConfig::default()
+    };
+    let mut output = Vec::new();
+    let info = get_from_with_config(addr, Path::new("pattern.bin"), Mode::Octet, &mut output, &config).unwrap();
+    assert_eq!(expected, output);
+    assert_eq!(1024, info.block_size);
+}
+
+#[test]
+fn options_ignored_by_the_server_fall_back_to_the_defaults() {
+    let data = pattern(1500);
+    let expected = data.clone();
+    // The server answers the request with block 1 instead of an OACK.
+    let addr = mock_server(move |socket, client, _| serve(socket, client, &data, 0));
+
+    let config = ClientConfig {
+        block_size: Some(1024),
+        tsize: true,
+        window_size: Some(4),
+        ..ClientConfig::default()
+    };
+    let mut output = Vec::new();
+    let info = get_from_with_config(addr, Path::new("pattern.bin"), Mode::Octet, &mut output, &config).unwrap();
+    assert_eq!(expected, output);
+    assert_eq!(TransferInfo { server_tid: info.server_tid, ..TransferInfo::default() }, info);
+}
+
+#[test]
+fn defaults_are_reported_without_negotiation() {
+    let data = pattern(100);
+    let addr = mock_server(move |socket, client, _| serve(socket, client, &data, 0));
+
+    let mut output = Vec::new();
+    let info = get_from_with_config(addr, Path::new("pattern.bin"), Mode::Octet, &mut output,
+                                    &ClientConfig::default()).unwrap();
+    assert_eq!(TransferInfo { server_tid: info.server_tid, ..TransferInfo::default() }, info);
+}
+
+#[test]
+fn port_the_server_answered_from_is_reported() {
+    let data = pattern(100);
+    let (tx, rx) = channel();
+    let addr = mock_server(move |socket, client, _| {
+        tx.send(socket.local_addr().unwrap()).unwrap();
+        serve(socket, client, &data, 0)
+    });
+
+    let mut output = Vec::new();
+    let info = get_from_with_config(addr, Path::new("pattern.bin"), Mode::Octet, &mut output,
+                                    &ClientConfig::default()).unwrap();
+    let server_tid = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(server_tid, info.server_tid);
+    assert!(info.server_tid.port() != addr.port());
+}
+
+#[test]
+fn transfer_is_cancelled_after_first_block() {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = channel();
+    let server_cancel = cancel.clone();
+    let addr = mock_server(move |socket, client, _| {
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let block = DataPacketOctet::from_slice(1, &[1; 512]).encode();
+        socket.send_to(block.packet_buf(), client).unwrap();
+
+        let mut buf = vec![0; recv_buffer_size(512)];
+        let (n, _) = socket.recv_from(&mut buf).unwrap();
+        let ack: AckPacket = RawPacket::new(buf, n).decode().expect("expected an ack");
+        assert_eq!(1, ack.block_id());
+        server_cancel.store(true, Ordering::SeqCst);
+
+        let mut buf = vec![0; recv_buffer_size(512)];
+        let (n, _) = socket.recv_from(&mut buf).unwrap();
+        let raw = RawPacket::new(buf, n);
+        let error: Option<ErrorPacket> = raw.decode().ok();
+        tx.send(error.map(|error| error.into_owned())).unwrap();
+    });
+
+    let config = ClientConfig {
+        cancel: Some(cancel),
+        ..ClientConfig::default()
+    };
+    let mut output = Vec::new();
+    let result = get_from_with_config(addr, Path::new("pattern.bin"), Mode::Octet, &mut output, &config);
+    match result {
+        Err(client::Error::Cancelled) => {}
+        other => panic!("expected the transfer to be cancelled, got {:?}", other),
+    }
+    assert_eq!(vec![1; 512], output);
+
+    let error = rx.recv_timeout(Duration::from_secs(5)).unwrap().expect("expected an error packet");
+    assert_eq!(Error::Undefined, error.error());
+}
+
+/// Retransmits quickly so tests with lost packets finish fast.
+fn fast_retransmit(max_retries: u32) -> ClientConfig {
+    ClientConfig {
+        retransmit: Backoff {
+            base: Duration::from_millis(50),
+            multiplier: 2,
+            max: Duration::from_millis(200),
+        },
+        max_retries: max_retries,
+        ..ClientConfig::default()
+    }
+}
+
+#[test]
+fn lost_read_request_is_retransmitted() {
+    let data = pattern(600);
+    let expected = data.clone();
+    let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buf = vec![0; 512];
+        // The first read request is lost.
+        listener.recv_from(&mut buf).unwrap();
+        let (n, client) = listener.recv_from(&mut buf).unwrap();
+        let _: RequestPacket = RawPacket::new(buf, n).decode().expect("expected a read request");
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        serve(&socket, client, &data, 0);
+    });
+
+    let mut output = Vec::new();
+    get_from_with_config(addr, Path::new("pattern.bin"), Mode::Octet, &mut output, &fast_retransmit(5)).unwrap();
+    assert_eq!(expected, output);
+}
+
+#[test]
+fn timed_out_download_is_retried() {
+    let data = pattern(700);
+    let expected = data.clone();
+    let (tx, rx) = channel();
+    let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        // Every attempt comes from a new port, the first two get no answer.
+        let mut clients = Vec::new();
+        let mut buf = vec![0; 512];
+        loop {
+            let (_, client) = listener.recv_from(&mut buf).unwrap();
+            if !clients.contains(&client) {
+                clients.push(client);
+            }
+            if clients.len() == 3 {
+                tx.send(clients.len()).unwrap();
+                let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+                serve(&socket, client, &data, 0);
+                return
+            }
+        }
+    });
+
+    let mut output = Vec::new();
+    let stats = tftp_get_retry(addr, Path::new("pattern.bin"), &mut output, &fast_retransmit(1), 3).unwrap();
+    assert_eq!(3, rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert_eq!(expected, output);
+    assert_eq!(700, stats.bytes);
+}
+
+#[test]
+fn missing_file_is_not_retried() {
+    let (tx, rx) = channel();
+    let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buf = vec![0; 512];
+        while let Ok((_, client)) = listener.recv_from(&mut buf) {
+            tx.send(client).unwrap();
+            let error = ErrorPacket::new(Error::FileNotFound, "no such file").encode();
+            listener.send_to(error.packet_buf(), client).unwrap();
+        }
+    });
+
+    let mut output = Vec::new();
+    match tftp_get_retry(addr, Path::new("missing.bin"), &mut output, &fast_retransmit(1), 3) {
+        Err(client::Error::Server(ref err)) => assert_eq!(Error::FileNotFound, err.error()),
+        other => panic!("expected the file not to be found, got {:?}", other),
+    }
+    // A retry would have sent its read request well within this time.
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(1, rx.try_iter().count());
+}
+
+#[test]
+fn silent_server_times_out() {
+    let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut output = Vec::new();
+    let result = get_from_with_config(addr, Path::new("pattern.bin"), Mode::Octet, &mut output, &fast_retransmit(2));
+    match result {
+        Err(client::Error::Timeout) => {}
+        other => panic!("expected the transfer to time out, got {:?}", other),
+    }
+
+    // The read request was sent once and retransmitted twice.
+    listener.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    let mut buf = vec![0; 512];
+    let mut received = 0;
+    while listener.recv_from(&mut buf).is_ok() {
+        received += 1;
+    }
+    assert_eq!(3, received);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn missing_server_fails_before_the_timeout() {
+    // Nothing listens on the port once the socket is closed.
+    let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    let started = Instant::now();
+    let mut output = Vec::new();
+    let result = get_from(addr, Path::new("pattern.bin"), Mode::Octet, &mut output);
+    match result {
+        Err(client::Error::Io(ref err)) if err.kind() == io::ErrorKind::ConnectionRefused => {}
+        other => panic!("expected the connection to be refused, got {:?}", other),
+    }
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn slow_server_fails_the_transfer_at_the_deadline() {
+    let (tx, rx) = channel();
+    let addr = mock_server(move |socket, client, _| {
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        // Every block comes well within the retransmission timeout, the file never ends.
+        let mut block_id = 1;
+        loop {
+            thread::sleep(Duration::from_millis(50));
+            socket.send_to(DataPacketOctet::from_slice(block_id, &[0; 512]).encode().packet_buf(), client).unwrap();
+            let mut buf = vec![0; 512];
+            let (n, _) = socket.recv_from(&mut buf).unwrap();
+            let raw = RawPacket::new(buf, n);
+            if let Ok(error) = raw.decode::<ErrorPacket>() {
+                tx.send(error.error()).unwrap();
+                return
+            }
+            block_id += 1;
+        }
+    });
+
+    let config = ClientConfig { deadline: Some(Duration::from_millis(300)), ..ClientConfig::default() };
+    let started = Instant::now();
+    match get_from_with_config(addr, Path::new("pattern.bin"), Mode::Octet, &mut Vec::new(), &config) {
+        Err(client::Error::DeadlineExceeded) => {}
+        other => panic!("expected the deadline to be exceeded, got {:?}", other),
+    }
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(Error::Undefined, rx.recv_timeout(Duration::from_secs(5)).unwrap());
+}
+
+#[test]
+fn oversized_block_is_rejected() {
+    let (tx, rx) = channel();
+    let addr = mock_server(move |socket, client, _| {
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket.send_to(DataPacketOctet::from_slice(1, &[1; 2000]).encode().packet_buf(), client).unwrap();
+        let mut buf = vec![0; 512];
+        let (n, _) = socket.recv_from(&mut buf).unwrap();
+        let raw = RawPacket::new(buf, n);
+        let error: ErrorPacket = raw.decode().expect("expected an error");
+        tx.send(error.error()).unwrap();
+    });
+
+    let mut output = Vec::new();
+    match get_from(addr, Path::new("pattern.bin"), Mode::Octet, &mut output) {
+        Err(client::Error::ProtocolViolation(_)) => {}
+        other => panic!("expected a protocol violation, got {:?}", other),
+    }
+    assert_eq!(Error::IllegalOperation, rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert!(output.is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn closed_downstream_pipe_aborts_the_transfer() {
+    let (tx, rx) = channel();
+    let addr = mock_server(move |socket, client, _| {
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        // Enough blocks to fill the write buffer of the client, the file never ends.
+        for block_id in 1..100 {
+            socket.send_to(DataPacketOctet::from_slice(block_id, &[0; 512]).encode().packet_buf(), client).unwrap();
+            let mut buf = vec![0; 512];
+            let (n, _) = socket.recv_from(&mut buf).unwrap();
+            let raw = RawPacket::new(buf, n);
+            if let Ok(error) = raw.decode::<ErrorPacket>() {
+                tx.send(error.error()).unwrap();
+                return
+            }
+        }
+    });
+
+    // A process that exits without reading, like `head` after its first lines.
+    let mut downstream = process::Command::new("true").stdin(process::Stdio::piped()).spawn().unwrap();
+    let pipe = downstream.stdin.take().unwrap();
+    downstream.wait().unwrap();
+
+    match Client::new(addr).unwrap().get(Path::new("pattern.bin"), Mode::Octet, pipe) {
+        Err(client::Error::Io(ref err)) if err.kind() == io::ErrorKind::BrokenPipe => {}
+        other => panic!("expected a broken pipe, got {:?}", other),
+    }
+    assert_eq!(Error::Undefined, rx.recv_timeout(Duration::from_secs(5)).unwrap());
+}
+
+#[test]
+fn busy_server_is_reported() {
+    let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        // A server at its limit of clients answers from the listening port.
+        let mut buf = vec![0; 512];
+        let (_, client) = listener.recv_from(&mut buf).unwrap();
+        let error = ErrorPacket::new(Error::Undefined, "Server busy").encode();
+        listener.send_to(error.packet_buf(), client).unwrap();
+    });
+
+    match download(addr, "pattern.bin") {
+        Err(client::Error::ServerBusy) => {}
+        other => panic!("expected the server to be busy, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn server_error_is_returned() {
+    let addr = mock_server(|socket, client, _| {
+        let error = ErrorPacket::new(Error::FileNotFound, "no such file").encode();
+        socket.send_to(error.packet_buf(), client).unwrap();
+    });
+
+    match download(addr, "missing.bin") {
+        Err(client::Error::Server(err)) => {
+            assert_eq!(Error::FileNotFound, err.error());
+            assert_eq!("no such file", err.message().unwrap());
+        }
+        other => panic!("expected a server error, got {:?}", other.map(|_| ())),
+    }
+}